@@ -1,3 +1,4 @@
+pub mod proxy_protocol;
 pub mod transport;
 pub mod utils;
 
@@ -0,0 +1,208 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::{error::Elapsed, timeout},
+};
+
+// HAProxy PROXY protocol, see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The header is sent right after connected by the load balancer
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Invalid PROXY protocol header: {0}")]
+    InvalidHeader(&'static str),
+
+    #[error("An IO error occurred: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Timeout: {0}")]
+    Timeout(#[from] Elapsed),
+}
+
+type Result<T> = std::result::Result<T, ProxyError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    // The real client
+    pub src: SocketAddr,
+    // The address the client connected to
+    pub dst: SocketAddr,
+}
+
+// Read the v1 or v2 header and nothing more, None for UNKNOWN, LOCAL or the non-inet family
+pub async fn read_header<R: AsyncRead + Unpin>(io: &mut R) -> Result<Option<ProxyHeader>> {
+    // Both v1 (at least 15 bytes) and v2 (16 bytes) are longer than the signature
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    io.read_exact(&mut buf).await?;
+    if buf == V2_SIGNATURE {
+        read_v2(io).await
+    } else if buf.starts_with(V1_PREFIX) {
+        read_v1(io, buf).await
+    } else {
+        Err(ProxyError::InvalidHeader("bad signature"))
+    }
+}
+
+// The addresses from the header, or the socket addresses if not proxied
+pub async fn resolve_addrs<R: AsyncRead + Unpin>(
+    io: &mut R,
+    peer: SocketAddr,
+    local: SocketAddr,
+) -> Result<ProxyHeader> {
+    let header = timeout(HEADER_TIMEOUT, read_header(io)).await??;
+    Ok(header.unwrap_or(ProxyHeader {
+        src: peer,
+        dst: local,
+    }))
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    io: &mut R,
+    mut buf: Vec<u8>,
+) -> Result<Option<ProxyHeader>> {
+    // Byte by byte to not consume the following stream
+    while !buf.ends_with(b"\r\n") {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(ProxyError::InvalidHeader("v1 line too long"));
+        }
+        buf.push(io.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..buf.len() - 2])
+        .map_err(|_| ProxyError::InvalidHeader("v1 not ascii"))?;
+    parse_v1(line).ok_or(ProxyError::InvalidHeader("v1 bad addresses"))
+}
+
+// e.g. TCP4 192.168.0.1 192.168.0.11 56324 443
+fn parse_v1(line: &str) -> Option<Option<ProxyHeader>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[0] {
+        "UNKNOWN" => return Some(None),
+        "TCP4" | "TCP6" if fields.len() == 5 => {}
+        _ => return None,
+    }
+    let src: IpAddr = fields[1].parse().ok()?;
+    let dst: IpAddr = fields[2].parse().ok()?;
+    if src.is_ipv4() != (fields[0] == "TCP4") || dst.is_ipv4() != src.is_ipv4() {
+        return None;
+    }
+    Some(Some(ProxyHeader {
+        src: SocketAddr::new(src, fields[3].parse().ok()?),
+        dst: SocketAddr::new(dst, fields[4].parse().ok()?),
+    }))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(io: &mut R) -> Result<Option<ProxyHeader>> {
+    let ver_cmd = io.read_u8().await?;
+    let family = io.read_u8().await?;
+    let len = io.read_u16().await? as usize;
+    // Including the TLVs, which are ignored
+    let mut buf = vec![0u8; len];
+    io.read_exact(&mut buf).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(ProxyError::InvalidHeader("v2 bad version"));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL, e.g. the health check of the load balancer
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(ProxyError::InvalidHeader("v2 bad command")),
+    }
+    match family >> 4 {
+        // AF_INET
+        1 if len >= 12 => {
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Ok(Some(ProxyHeader {
+                src: SocketAddr::new(ip(&buf[0..4]), port(&buf[8..10])),
+                dst: SocketAddr::new(ip(&buf[4..8]), port(&buf[10..12])),
+            }))
+        }
+        // AF_INET6
+        2 if len >= 36 => {
+            let ip = |b: &[u8]| IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()));
+            Ok(Some(ProxyHeader {
+                src: SocketAddr::new(ip(&buf[0..16]), port(&buf[32..34])),
+                dst: SocketAddr::new(ip(&buf[16..32]), port(&buf[34..36])),
+            }))
+        }
+        1 | 2 => Err(ProxyError::InvalidHeader("v2 addresses too short")),
+        // AF_UNSPEC or AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+fn port(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> (Result<Option<ProxyHeader>>, Vec<u8>) {
+        let ret = read_header(&mut data).await;
+        (ret, data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (ret, rest) = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1935\r\n\x03").await;
+        let header = ret.unwrap().unwrap();
+        assert_eq!(header.src, "192.168.0.1:56324".parse().unwrap());
+        assert_eq!(header.dst, "192.168.0.11:1935".parse().unwrap());
+        // The handshake C0 is left to the RTMP
+        assert_eq!(rest, b"\x03");
+
+        let (ret, _) = read(b"PROXY TCP6 2001:db8::1 ::1 4000 443\r\n").await;
+        assert_eq!(
+            ret.unwrap().unwrap().src,
+            "[2001:db8::1]:4000".parse().unwrap()
+        );
+
+        let (ret, rest) = read(b"PROXY UNKNOWN ffff::1 ::1 4000 443\r\nGET").await;
+        assert!(ret.unwrap().is_none());
+        assert_eq!(rest, b"GET");
+
+        let (ret, _) = read(b"PROXY TCP4 2001:db8::1 ::1 4000 443\r\n").await;
+        assert!(matches!(ret, Err(ProxyError::InvalidHeader(_))));
+        let (ret, _) = read(&[V1_PREFIX, &[b'0'; 200]].concat()).await;
+        assert!(matches!(
+            ret,
+            Err(ProxyError::InvalidHeader("v1 line too long"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        // PROXY, TCP over IPv4, 12 bytes addresses and a 3 bytes TLV
+        data.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        data.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0xdb, 0xc4, 0x07, 0x8f]);
+        data.extend_from_slice(&[0x04, 0, 0]);
+        data.push(0x03);
+        let (ret, rest) = read(&data).await;
+        let header = ret.unwrap().unwrap();
+        assert_eq!(header.src, "10.0.0.1:56260".parse().unwrap());
+        assert_eq!(header.dst, "10.0.0.2:1935".parse().unwrap());
+        assert_eq!(rest, b"\x03");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        // LOCAL
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let (ret, _) = read(&data).await;
+        assert!(ret.unwrap().is_none());
+
+        let (ret, _) = read(b"\x03\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").await;
+        assert!(matches!(ret, Err(ProxyError::InvalidHeader(_))));
+    }
+}
@@ -1,8 +1,10 @@
-use std::{io, time::Duration};
+use std::{
+    io::{self, IoSlice},
+    time::Duration,
+};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufStream},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
     time::{error::Elapsed, timeout},
 };
 use tracing::{debug, error, info, instrument, trace, warn};
@@ -23,8 +25,13 @@ pub enum TransportError {
 
 type Result<T> = std::result::Result<T, TransportError>;
 
+// The underlying stream, e.g. TcpStream or TlsStream
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for T {}
+
 pub struct Transport {
-    io: BufStream<TcpStream>,
+    io: BufStream<Box<dyn AsyncStream>>,
     recv_timeout: Duration,
     send_timeout: Duration,
     recv_bytes: u64,
@@ -38,13 +45,13 @@ pub struct Transport {
 }
 
 impl Transport {
-    pub fn new(io: TcpStream) -> Self {
+    pub fn new(io: impl AsyncStream + 'static) -> Self {
         let mut buf = Vec::with_capacity(131072);
         unsafe {
             buf.set_len(131072);
         }
         Self {
-            io: BufStream::with_capacity(0, 131072, io),
+            io: BufStream::with_capacity(0, 131072, Box::new(io)),
             recv_timeout: NOTIMEOUT,
             send_timeout: NOTIMEOUT,
             recv_bytes: 0,
@@ -175,6 +182,34 @@ impl Transport {
         }
     }
 
+    // Write the slices by as few syscalls as possible, the empty ones are skipped
+    pub async fn write_vectored_all<B: AsRef<[u8]>>(&mut self, bufs: &[B]) -> Result<()> {
+        let mut slices: Vec<IoSlice> = bufs
+            .iter()
+            .map(|b| IoSlice::new(b.as_ref()))
+            .filter(|s| !s.is_empty())
+            .collect();
+        let total: usize = slices.iter().map(|s| s.len()).sum();
+        let io = &mut self.io;
+        let write = async move {
+            let mut slices = &mut slices[..];
+            while !slices.is_empty() {
+                match io.write_vectored(slices).await? {
+                    0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    n => IoSlice::advance_slices(&mut slices, n),
+                }
+            }
+            Ok(())
+        };
+        if self.send_timeout == NOTIMEOUT {
+            write.await?;
+        } else {
+            timeout(self.send_timeout, write).await??;
+        }
+        self.send_bytes += total as u64;
+        Ok(())
+    }
+
     pub async fn flush(&mut self) -> Result<()> {
         Ok(self.io.flush().await?)
     }
@@ -1,7 +1,10 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use bytes::Bytes;
 use error::FlvMuxerError;
-use rtmp::message::{encode, RtmpMessage};
+use rtmp::{
+    codec::{ex_video_packet_type, video_codec_id, VideoCodec, VideoTagHeader},
+    message::{encode, RtmpMessage},
+};
 
 pub mod error;
 
@@ -25,6 +28,8 @@ const FLV_TAG_HEADER_SIZE: usize = 11;
 
 pub struct FlvTransmuxer {
     header_written: bool,
+    // Rewrite HEVC of Enhanced RTMP to codec id 12 for the players without Enhanced RTMP support
+    legacy_hevc: bool,
     send_bytes: u64,
     audio_count: u64,
     video_count: u64,
@@ -34,12 +39,21 @@ impl FlvTransmuxer {
     pub fn new() -> Self {
         Self {
             header_written: false,
+            legacy_hevc: false,
             send_bytes: 0,
             audio_count: 0,
             video_count: 0,
         }
     }
 
+    pub fn set_legacy_hevc(&mut self, enabled: bool) {
+        self.legacy_hevc = enabled;
+    }
+
+    pub fn legacy_hevc(&self) -> bool {
+        self.legacy_hevc
+    }
+
     pub fn write_tags(
         &mut self,
         msgs: &[RtmpMessage],
@@ -49,90 +63,44 @@ impl FlvTransmuxer {
 
         // Write FLV header first
         if !self.header_written {
-            // FLV header
-            cache.extend_from_slice(&FLV_HEADER);
-            // Previous tag header
-            cache.write_u32::<BigEndian>(0)?;
-            self.header_written = true;
+            cache.extend_from_slice(&self.header());
         }
 
         // Write frames
-        for msg in msgs {
-            match msg {
-                RtmpMessage::AudioData {
-                    payload, timestamp, ..
-                } => {
-                    self.write_audio(&mut cache, payload, *timestamp)?;
-                    self.audio_count += 1;
-                }
-                RtmpMessage::VideoData {
-                    payload, timestamp, ..
-                } => {
-                    self.write_video(&mut cache, payload, *timestamp)?;
-                    self.video_count += 1;
-                }
-                RtmpMessage::Amf0Data { .. } => {
-                    if msg.is_metadata() {
-                        let data = encode(msg.clone(), 0, 0)?;
-                        self.write_metadata(&mut cache, &data.raw_data)?
-                    }
-                }
-                _ => {}
-            }
-        }
+        let (audio, video) = write_frames(&mut cache, msgs, self.legacy_hevc)?;
+        self.audio_count += audio;
+        self.video_count += video;
 
         self.send_bytes += cache.len() as u64;
 
         Ok(cache)
     }
 
-    fn write_video(
-        &mut self,
-        cache: &mut Vec<u8>,
-        data: &Bytes,
-        timestamp: u32,
-    ) -> Result<(), FlvMuxerError> {
-        cache.write_u8(FRAME_TYPE_VIDEO)?;
-        cache.write_u24::<BigEndian>(data.len() as u32)?;
-        cache.write_u24::<BigEndian>(timestamp & 0xFFFFFF)?;
-        cache.write_u8(((timestamp >> 24) & 0xFF) as u8)?;
-        cache.write_u24::<BigEndian>(0)?;
-
-        cache.extend(data);
-
-        cache.write_u32::<BigEndian>((FLV_TAG_HEADER_SIZE + data.len()) as u32)?;
-        Ok(())
-    }
-
-    fn write_audio(
-        &mut self,
-        cache: &mut Vec<u8>,
-        data: &Bytes,
-        timestamp: u32,
-    ) -> Result<(), FlvMuxerError> {
-        cache.write_u8(FRAME_TYPE_AUDIO)?;
-        cache.write_u24::<BigEndian>(data.len() as u32)?;
-        cache.write_u24::<BigEndian>(timestamp & 0xFFFFFF)?;
-        cache.write_u8(((timestamp >> 24) & 0xFF) as u8)?;
-        cache.write_u24::<BigEndian>(0)?;
-
-        cache.extend(data);
-
-        cache.write_u32::<BigEndian>((FLV_TAG_HEADER_SIZE + data.len()) as u32)?;
-        Ok(())
+    // Write the tags from encode_tags, which are shared by the players without copy
+    pub fn write_encoded<'a>(&mut self, tags: impl IntoIterator<Item = &'a FlvTags>) -> Vec<Bytes> {
+        let mut chunks = Vec::new();
+        if !self.header_written {
+            chunks.push(Bytes::from(self.header()));
+        }
+        for tag in tags {
+            if tag.data.is_empty() {
+                continue;
+            }
+            self.audio_count += tag.audio;
+            self.video_count += tag.video;
+            chunks.push(tag.data.clone());
+        }
+        self.send_bytes += chunks.iter().map(|c| c.len() as u64).sum::<u64>();
+        chunks
     }
 
-    fn write_metadata(&mut self, cache: &mut Vec<u8>, data: &Bytes) -> Result<(), FlvMuxerError> {
-        cache.write_u8(FRAME_TYPE_SCRIPT)?;
-        cache.write_u24::<BigEndian>(data.len() as u32)?;
-        cache.write_u24::<BigEndian>(0)?;
-        cache.write_u8(0)?;
-        cache.write_u24::<BigEndian>(0)?;
-
-        cache.extend(data);
-
-        cache.write_u32::<BigEndian>((FLV_TAG_HEADER_SIZE + data.len()) as u32)?;
-        Ok(())
+    fn header(&mut self) -> Vec<u8> {
+        self.header_written = true;
+        // FLV header and the previous tag size
+        let mut header = Vec::with_capacity(FLV_HEADER.len() + 4);
+        header.extend_from_slice(&FLV_HEADER);
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header
     }
 
     pub fn get_send_bytes(&mut self) -> u64 {
@@ -147,3 +115,139 @@ impl FlvTransmuxer {
         self.video_count
     }
 }
+
+// FLV tags of the frames without the FLV header, can be cached and shared by the players
+#[derive(Debug, Clone, Default)]
+pub struct FlvTags {
+    pub data: Bytes,
+    pub audio: u64,
+    pub video: u64,
+}
+
+pub fn encode_tags(msgs: &[RtmpMessage], legacy_hevc: bool) -> Result<FlvTags, FlvMuxerError> {
+    let total: usize = msgs.iter().map(|m| m.len().unwrap_or(0)).sum();
+    let mut cache = Vec::with_capacity(total + msgs.len() * 15);
+    let (audio, video) = write_frames(&mut cache, msgs, legacy_hevc)?;
+    Ok(FlvTags {
+        data: Bytes::from(cache),
+        audio,
+        video,
+    })
+}
+
+// Return the number of audio and video tags written
+fn write_frames(
+    cache: &mut Vec<u8>,
+    msgs: &[RtmpMessage],
+    legacy_hevc: bool,
+) -> Result<(u64, u64), FlvMuxerError> {
+    let (mut audio, mut video) = (0, 0);
+    for msg in msgs {
+        match msg {
+            RtmpMessage::AudioData {
+                payload, timestamp, ..
+            } => {
+                write_tag(cache, FRAME_TYPE_AUDIO, payload, *timestamp)?;
+                audio += 1;
+            }
+            RtmpMessage::VideoData {
+                payload, timestamp, ..
+            } => {
+                if legacy_hevc {
+                    match to_legacy_hevc(payload) {
+                        Some(payload) => write_tag(cache, FRAME_TYPE_VIDEO, &payload, *timestamp)?,
+                        None => continue,
+                    }
+                } else {
+                    write_tag(cache, FRAME_TYPE_VIDEO, payload, *timestamp)?;
+                }
+                video += 1;
+            }
+            RtmpMessage::Amf0Data { .. } => {
+                if msg.is_metadata() {
+                    let data = encode(msg.clone(), 0, 0)?;
+                    write_tag(cache, FRAME_TYPE_SCRIPT, &data.raw_data, 0)?
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((audio, video))
+}
+
+fn write_tag(
+    cache: &mut Vec<u8>,
+    tag_type: u8,
+    data: &Bytes,
+    timestamp: u32,
+) -> Result<(), FlvMuxerError> {
+    cache.write_u8(tag_type)?;
+    cache.write_u24::<BigEndian>(data.len() as u32)?;
+    cache.write_u24::<BigEndian>(timestamp & 0xFFFFFF)?;
+    cache.write_u8(((timestamp >> 24) & 0xFF) as u8)?;
+    cache.write_u24::<BigEndian>(0)?;
+
+    cache.extend(data);
+
+    cache.write_u32::<BigEndian>((FLV_TAG_HEADER_SIZE + data.len()) as u32)?;
+    Ok(())
+}
+
+// Convert the ExVideoTagHeader of HEVC to the legacy one with codec id 12,
+// the others are returned as is, and None for the packets can not be converted
+fn to_legacy_hevc(data: &Bytes) -> Option<Bytes> {
+    match VideoTagHeader::parse(data) {
+        Some(h) if h.enhanced && h.codec == VideoCodec::Hevc => {
+            let (packet_type, cts): (u8, &[u8]) = match data[0] & 0x0f {
+                ex_video_packet_type::SEQUENCE_START => (0, &[0, 0, 0]),
+                // The composition time is already in the body
+                ex_video_packet_type::CODED_FRAMES => (1, &[]),
+                ex_video_packet_type::CODED_FRAMES_X => (1, &[0, 0, 0]),
+                ex_video_packet_type::SEQUENCE_END => (2, &[0, 0, 0]),
+                _ => return None,
+            };
+            let mut out = Vec::with_capacity(data.len());
+            out.push(h.frame_type << 4 | video_codec_id::HEVC);
+            out.push(packet_type);
+            out.extend_from_slice(cts);
+            out.extend_from_slice(&data[5..]);
+            Some(Bytes::from(out))
+        }
+        _ => Some(data.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_legacy_hevc() {
+        // IsExHeader | KeyFrame | SequenceStart, hvc1
+        let sh = Bytes::from_static(&[0x90, b'h', b'v', b'c', b'1', 0xaa]);
+        assert_eq!(
+            to_legacy_hevc(&sh).unwrap().as_ref(),
+            &[0x1c, 0x00, 0x00, 0x00, 0x00, 0xaa]
+        );
+        // IsExHeader | InterFrame | CodedFrames, with composition time 0x000102
+        let frame = Bytes::from_static(&[0xa1, b'h', b'v', b'c', b'1', 0x00, 0x01, 0x02, 0xbb]);
+        assert_eq!(
+            to_legacy_hevc(&frame).unwrap().as_ref(),
+            &[0x2c, 0x01, 0x00, 0x01, 0x02, 0xbb]
+        );
+        // IsExHeader | KeyFrame | CodedFramesX
+        let frame = Bytes::from_static(&[0x93, b'h', b'v', b'c', b'1', 0xcc]);
+        assert_eq!(
+            to_legacy_hevc(&frame).unwrap().as_ref(),
+            &[0x1c, 0x01, 0x00, 0x00, 0x00, 0xcc]
+        );
+        // AV1 and AVC are not changed
+        let av1 = Bytes::from_static(&[0x93, b'a', b'v', b'0', b'1', 0xcc]);
+        assert_eq!(to_legacy_hevc(&av1).unwrap(), av1);
+        let avc = Bytes::from_static(&[0x17, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(to_legacy_hevc(&avc).unwrap(), avc);
+        // Metadata of Enhanced RTMP is dropped
+        let meta = Bytes::from_static(&[0x94, b'h', b'v', b'c', b'1', 0x00]);
+        assert!(to_legacy_hevc(&meta).is_none());
+    }
+}
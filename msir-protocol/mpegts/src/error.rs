@@ -0,0 +1,18 @@
+use std::io;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TsMuxerError {
+    #[error("Invalid avc sequence header")]
+    InvalidAvcSequenceHeader,
+
+    #[error("Invalid aac sequence header")]
+    InvalidAacSequenceHeader,
+
+    #[error("Invalid avc nalu")]
+    InvalidAvcNalu,
+
+    #[error("An IO error occurred: {0}")]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,648 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use bytes::Bytes;
+use error::TsMuxerError;
+use rtmp::message::RtmpMessage;
+
+pub mod error;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+const TS_PAT_PID: u16 = 0x0000;
+const TS_PMT_PID: u16 = 0x1000;
+const TS_VIDEO_PID: u16 = 0x0100;
+const TS_AUDIO_PID: u16 = 0x0101;
+
+const TS_STREAM_TYPE_H264: u8 = 0x1b;
+const TS_STREAM_TYPE_AAC: u8 = 0x0f;
+
+const PES_STREAM_ID_VIDEO: u8 = 0xe0;
+const PES_STREAM_ID_AUDIO: u8 = 0xc0;
+
+// FLV timestamp is in ms, TS clock is 90kHz
+const TS_CLOCK_PER_MS: i64 = 90;
+
+const FLV_CODEC_ID_AVC: u8 = 7;
+const FLV_SOUND_FORMAT_AAC: u8 = 10;
+
+const AVC_PACKET_SEQUENCE_HEADER: u8 = 0;
+const AVC_PACKET_NALU: u8 = 1;
+const AAC_PACKET_SEQUENCE_HEADER: u8 = 0;
+const AAC_PACKET_RAW: u8 = 1;
+
+const H264_NAL_AUD: u8 = 9;
+const ANNEXB_START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+const H264_AUD: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+
+type Result<T> = std::result::Result<T, TsMuxerError>;
+
+#[derive(Debug, Default)]
+struct AvcConfig {
+    nalu_length_size: usize,
+    sps: Vec<Bytes>,
+    pps: Vec<Bytes>,
+}
+
+#[derive(Debug, Default)]
+struct AacConfig {
+    object_type: u8,
+    sample_rate_index: u8,
+    channels: u8,
+}
+
+#[derive(Debug, Default)]
+struct ContinuityCounter {
+    pat: u8,
+    pmt: u8,
+    video: u8,
+    audio: u8,
+}
+
+impl ContinuityCounter {
+    fn next(&mut self, pid: u16) -> u8 {
+        let cc = match pid {
+            TS_PAT_PID => &mut self.pat,
+            TS_PMT_PID => &mut self.pmt,
+            TS_VIDEO_PID => &mut self.video,
+            _ => &mut self.audio,
+        };
+        let ret = *cc;
+        *cc = (*cc + 1) & 0x0f;
+        ret
+    }
+}
+
+pub struct TsMuxer {
+    avc: Option<AvcConfig>,
+    aac: Option<AacConfig>,
+    cc: ContinuityCounter,
+    pat_pmt_pending: bool,
+    send_bytes: u64,
+    audio_count: u64,
+    video_count: u64,
+}
+
+impl Default for TsMuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self {
+            avc: None,
+            aac: None,
+            cc: ContinuityCounter::default(),
+            pat_pmt_pending: true,
+            send_bytes: 0,
+            audio_count: 0,
+            video_count: 0,
+        }
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.avc.is_some()
+    }
+
+    // PAT/PMT will be written before the next frame, e.g. at the start of hls segment
+    pub fn insert_pat_pmt(&mut self) {
+        self.pat_pmt_pending = true;
+    }
+
+    pub fn write_tags(&mut self, msgs: &[RtmpMessage]) -> Result<Vec<u8>> {
+        let mut cache = Vec::new();
+
+        for msg in msgs {
+            match msg {
+                RtmpMessage::VideoData {
+                    payload, timestamp, ..
+                } => {
+                    self.write_video(&mut cache, payload, *timestamp)?;
+                }
+                RtmpMessage::AudioData {
+                    payload, timestamp, ..
+                } => {
+                    self.write_audio(&mut cache, payload, *timestamp)?;
+                }
+                _ => {}
+            }
+        }
+
+        self.send_bytes += cache.len() as u64;
+
+        Ok(cache)
+    }
+
+    fn write_video(&mut self, cache: &mut Vec<u8>, data: &Bytes, timestamp: u32) -> Result<()> {
+        // FrameType(4) CodecID(4) AVCPacketType(8) CompositionTime(24)
+        if data.len() < 5 || data[0] & 0x0f != FLV_CODEC_ID_AVC {
+            return Ok(());
+        }
+        let keyframe = data[0] >> 4 == 1;
+        let cts = ((((data[2] as u32) << 16 | (data[3] as u32) << 8 | data[4] as u32) << 8) as i32
+            >> 8) as i64;
+        match data[1] {
+            AVC_PACKET_SEQUENCE_HEADER => {
+                self.avc = Some(parse_avc_config(&data[5..])?);
+                return Ok(());
+            }
+            AVC_PACKET_NALU => {}
+            _ => return Ok(()),
+        }
+        let avc = match &self.avc {
+            Some(avc) => avc,
+            None => return Ok(()),
+        };
+
+        // Convert to annexb, AUD first, then SPS/PPS for keyframe
+        let mut es = Vec::with_capacity(data.len() + 64);
+        es.extend_from_slice(&H264_AUD);
+        if keyframe {
+            for nalu in avc.sps.iter().chain(avc.pps.iter()) {
+                es.extend_from_slice(&ANNEXB_START_CODE);
+                es.extend_from_slice(nalu);
+            }
+        }
+        let mut pos = 5;
+        while pos < data.len() {
+            if pos + avc.nalu_length_size > data.len() {
+                return Err(TsMuxerError::InvalidAvcNalu);
+            }
+            let mut size = 0_usize;
+            for b in &data[pos..pos + avc.nalu_length_size] {
+                size = size << 8 | *b as usize;
+            }
+            pos += avc.nalu_length_size;
+            if size == 0 {
+                continue;
+            }
+            if pos + size > data.len() {
+                return Err(TsMuxerError::InvalidAvcNalu);
+            }
+            if data[pos] & 0x1f != H264_NAL_AUD {
+                es.extend_from_slice(&ANNEXB_START_CODE);
+                es.extend_from_slice(&data[pos..pos + size]);
+            }
+            pos += size;
+        }
+
+        if keyframe {
+            self.pat_pmt_pending = true;
+        }
+        self.write_pat_pmt_if_pending(cache)?;
+
+        let dts = timestamp as i64 * TS_CLOCK_PER_MS;
+        let pts = dts + cts * TS_CLOCK_PER_MS;
+        self.write_pes(
+            cache,
+            TS_VIDEO_PID,
+            PES_STREAM_ID_VIDEO,
+            &es,
+            pts as u64,
+            dts as u64,
+            keyframe,
+        )?;
+        self.video_count += 1;
+        Ok(())
+    }
+
+    fn write_audio(&mut self, cache: &mut Vec<u8>, data: &Bytes, timestamp: u32) -> Result<()> {
+        // SoundFormat(4) SoundRate(2) SoundSize(1) SoundType(1) AACPacketType(8)
+        if data.len() < 2 || data[0] >> 4 != FLV_SOUND_FORMAT_AAC {
+            return Ok(());
+        }
+        match data[1] {
+            AAC_PACKET_SEQUENCE_HEADER => {
+                self.aac = Some(parse_aac_config(&data[2..])?);
+                return Ok(());
+            }
+            AAC_PACKET_RAW => {}
+            _ => return Ok(()),
+        }
+        let aac = match &self.aac {
+            Some(aac) => aac,
+            None => return Ok(()),
+        };
+
+        // Wrap raw aac in ADTS
+        let raw = &data[2..];
+        let frame_length = raw.len() + 7;
+        let mut es = Vec::with_capacity(frame_length);
+        es.push(0xff);
+        es.push(0xf1);
+        es.push(
+            ((aac.object_type.saturating_sub(1) & 0x03) << 6)
+                | ((aac.sample_rate_index & 0x0f) << 2)
+                | ((aac.channels >> 2) & 0x01),
+        );
+        es.push(((aac.channels & 0x03) << 6) | ((frame_length >> 11) & 0x03) as u8);
+        es.push(((frame_length >> 3) & 0xff) as u8);
+        es.push((((frame_length & 0x07) << 5) | 0x1f) as u8);
+        es.push(0xfc);
+        es.extend_from_slice(raw);
+
+        // Audio only stream, no keyframe to carry PAT/PMT
+        if self.avc.is_none() {
+            self.write_pat_pmt_if_pending(cache)?;
+        }
+
+        let pts = timestamp as u64 * TS_CLOCK_PER_MS as u64;
+        self.write_pes(
+            cache,
+            TS_AUDIO_PID,
+            PES_STREAM_ID_AUDIO,
+            &es,
+            pts,
+            pts,
+            self.avc.is_none(),
+        )?;
+        self.audio_count += 1;
+        Ok(())
+    }
+
+    fn write_pat_pmt_if_pending(&mut self, cache: &mut Vec<u8>) -> Result<()> {
+        if !self.pat_pmt_pending {
+            return Ok(());
+        }
+        self.pat_pmt_pending = false;
+
+        // program_number(16) reserved(3) program_map_PID(13)
+        let mut pat = Vec::with_capacity(4);
+        pat.write_u16::<BigEndian>(0x0001)?;
+        pat.write_u16::<BigEndian>(0xe000 | TS_PMT_PID)?;
+        self.write_psi(cache, TS_PAT_PID, 0x00, &pat)?;
+
+        let pcr_pid = match self.avc {
+            Some(_) => TS_VIDEO_PID,
+            None => TS_AUDIO_PID,
+        };
+        // reserved(3) PCR_PID(13) reserved(4) program_info_length(12)
+        let mut pmt = Vec::with_capacity(14);
+        pmt.write_u16::<BigEndian>(0xe000 | pcr_pid)?;
+        pmt.write_u16::<BigEndian>(0xf000)?;
+        // stream_type(8) reserved(3) elementary_PID(13) reserved(4) ES_info_length(12)
+        if self.avc.is_some() {
+            pmt.write_u8(TS_STREAM_TYPE_H264)?;
+            pmt.write_u16::<BigEndian>(0xe000 | TS_VIDEO_PID)?;
+            pmt.write_u16::<BigEndian>(0xf000)?;
+        }
+        if self.aac.is_some() {
+            pmt.write_u8(TS_STREAM_TYPE_AAC)?;
+            pmt.write_u16::<BigEndian>(0xe000 | TS_AUDIO_PID)?;
+            pmt.write_u16::<BigEndian>(0xf000)?;
+        }
+        self.write_psi(cache, TS_PMT_PID, 0x02, &pmt)?;
+        Ok(())
+    }
+
+    fn write_psi(
+        &mut self,
+        cache: &mut Vec<u8>,
+        pid: u16,
+        table_id: u8,
+        data: &[u8],
+    ) -> Result<()> {
+        let start = cache.len();
+        cache.write_u8(TS_SYNC_BYTE)?;
+        cache.write_u16::<BigEndian>(0x4000 | pid)?;
+        cache.write_u8(0x10 | self.cc.next(pid))?;
+        // pointer_field
+        cache.write_u8(0x00)?;
+
+        let section = cache.len();
+        // section_length = id(2) version(1) section_number(1) last_section_number(1) + data + crc(4)
+        cache.write_u8(table_id)?;
+        cache.write_u16::<BigEndian>(0xb000 | (5 + data.len() + 4) as u16)?;
+        // transport_stream_id or program_number
+        cache.write_u16::<BigEndian>(0x0001)?;
+        // reserved(2) version_number(5) current_next_indicator(1)
+        cache.write_u8(0xc1)?;
+        cache.write_u8(0x00)?;
+        cache.write_u8(0x00)?;
+        cache.extend_from_slice(data);
+        let crc = crc32(&cache[section..]);
+        cache.write_u32::<BigEndian>(crc)?;
+
+        cache.resize(start + TS_PACKET_SIZE, 0xff);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_pes(
+        &mut self,
+        cache: &mut Vec<u8>,
+        pid: u16,
+        stream_id: u8,
+        es: &[u8],
+        pts: u64,
+        dts: u64,
+        random_access: bool,
+    ) -> Result<()> {
+        let has_dts = pts != dts;
+        let header_data_length = if has_dts { 10 } else { 5 };
+        let mut pes = Vec::with_capacity(9 + header_data_length + es.len());
+        pes.extend_from_slice(&[0x00, 0x00, 0x01, stream_id]);
+        // Zero is allowed for video, or the length is large than 16 bits
+        let pes_length = 3 + header_data_length + es.len();
+        if stream_id == PES_STREAM_ID_VIDEO || pes_length > 0xffff {
+            pes.write_u16::<BigEndian>(0)?;
+        } else {
+            pes.write_u16::<BigEndian>(pes_length as u16)?;
+        }
+        pes.write_u8(0x80)?;
+        if has_dts {
+            pes.write_u8(0xc0)?;
+            pes.write_u8(header_data_length as u8)?;
+            put_timestamp(&mut pes, 0x03, pts);
+            put_timestamp(&mut pes, 0x01, dts);
+        } else {
+            pes.write_u8(0x80)?;
+            pes.write_u8(header_data_length as u8)?;
+            put_timestamp(&mut pes, 0x02, pts);
+        }
+        pes.extend_from_slice(es);
+
+        let mut pos = 0;
+        while pos < pes.len() {
+            let first = pos == 0;
+            // The adaptation field, without the length byte
+            let mut af = Vec::new();
+            let mut has_af = false;
+            if first && random_access {
+                has_af = true;
+                // random_access_indicator and PCR_flag
+                af.push(0x50);
+                let pcr = dts & 0x1_ffff_ffff;
+                af.push((pcr >> 25) as u8);
+                af.push((pcr >> 17) as u8);
+                af.push((pcr >> 9) as u8);
+                af.push((pcr >> 1) as u8);
+                af.push(((pcr & 0x01) << 7) as u8 | 0x7e);
+                af.push(0x00);
+            }
+
+            let mut space = TS_PACKET_SIZE - 4;
+            if has_af {
+                space -= 1 + af.len();
+            }
+            let remain = pes.len() - pos;
+            if remain < space {
+                let stuffing = space - remain;
+                if has_af {
+                    af.resize(af.len() + stuffing, 0xff);
+                } else {
+                    has_af = true;
+                    if stuffing > 1 {
+                        af.push(0x00);
+                        af.resize(stuffing - 1, 0xff);
+                    }
+                }
+                space = remain;
+            }
+
+            cache.write_u8(TS_SYNC_BYTE)?;
+            let pusi = if first { 0x4000 } else { 0x0000 };
+            cache.write_u16::<BigEndian>(pusi | pid)?;
+            let afc = if has_af { 0x30 } else { 0x10 };
+            cache.write_u8(afc | self.cc.next(pid))?;
+            if has_af {
+                cache.write_u8(af.len() as u8)?;
+                cache.extend_from_slice(&af);
+            }
+            cache.extend_from_slice(&pes[pos..pos + space]);
+            pos += space;
+        }
+        Ok(())
+    }
+
+    pub fn get_send_bytes(&mut self) -> u64 {
+        self.send_bytes
+    }
+
+    pub fn get_audio_count(&mut self) -> u64 {
+        self.audio_count
+    }
+
+    pub fn get_video_count(&mut self) -> u64 {
+        self.video_count
+    }
+}
+
+// AVCDecoderConfigurationRecord
+fn parse_avc_config(data: &[u8]) -> Result<AvcConfig> {
+    if data.len() < 6 {
+        return Err(TsMuxerError::InvalidAvcSequenceHeader);
+    }
+    let mut avc = AvcConfig {
+        nalu_length_size: (data[4] & 0x03) as usize + 1,
+        ..Default::default()
+    };
+    let mut pos = 5;
+    let nb_sps = (data[pos] & 0x1f) as usize;
+    pos += 1;
+    for _ in 0..nb_sps {
+        let (nalu, next) = read_parameter_set(data, pos)?;
+        avc.sps.push(nalu);
+        pos = next;
+    }
+    if pos >= data.len() {
+        return Err(TsMuxerError::InvalidAvcSequenceHeader);
+    }
+    let nb_pps = data[pos] as usize;
+    pos += 1;
+    for _ in 0..nb_pps {
+        let (nalu, next) = read_parameter_set(data, pos)?;
+        avc.pps.push(nalu);
+        pos = next;
+    }
+    Ok(avc)
+}
+
+fn read_parameter_set(data: &[u8], pos: usize) -> Result<(Bytes, usize)> {
+    if pos + 2 > data.len() {
+        return Err(TsMuxerError::InvalidAvcSequenceHeader);
+    }
+    let size = (data[pos] as usize) << 8 | data[pos + 1] as usize;
+    if pos + 2 + size > data.len() {
+        return Err(TsMuxerError::InvalidAvcSequenceHeader);
+    }
+    Ok((
+        Bytes::copy_from_slice(&data[pos + 2..pos + 2 + size]),
+        pos + 2 + size,
+    ))
+}
+
+// AudioSpecificConfig
+fn parse_aac_config(data: &[u8]) -> Result<AacConfig> {
+    if data.len() < 2 {
+        return Err(TsMuxerError::InvalidAacSequenceHeader);
+    }
+    Ok(AacConfig {
+        object_type: data[0] >> 3,
+        sample_rate_index: ((data[0] & 0x07) << 1) | (data[1] >> 7),
+        channels: (data[1] >> 3) & 0x0f,
+    })
+}
+
+fn put_timestamp(buf: &mut Vec<u8>, prefix: u8, ts: u64) {
+    let ts = ts & 0x1_ffff_ffff;
+    buf.push((prefix << 4) | (((ts >> 30) & 0x07) << 1) as u8 | 0x01);
+    buf.push((ts >> 22) as u8);
+    buf.push((((ts >> 15) & 0x7f) << 1) as u8 | 0x01);
+    buf.push((ts >> 7) as u8);
+    buf.push(((ts & 0x7f) << 1) as u8 | 0x01);
+}
+
+// CRC32/MPEG-2
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffff_u32;
+    for b in data {
+        crc ^= (*b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 5] = [0x67, 0x64, 0x00, 0x1f, 0xac];
+    const PPS: [u8; 4] = [0x68, 0xee, 0x3c, 0x80];
+
+    fn video(timestamp: u32, payload: Vec<u8>) -> RtmpMessage {
+        RtmpMessage::VideoData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::from(payload),
+        }
+    }
+
+    fn audio(timestamp: u32, payload: Vec<u8>) -> RtmpMessage {
+        RtmpMessage::AudioData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::from(payload),
+        }
+    }
+
+    fn sequence_headers() -> Vec<RtmpMessage> {
+        let mut avc = vec![0x17, 0x00, 0x00, 0x00, 0x00];
+        avc.extend_from_slice(&[0x01, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x05]);
+        avc.extend_from_slice(&SPS);
+        avc.extend_from_slice(&[0x01, 0x00, 0x04]);
+        avc.extend_from_slice(&PPS);
+        // AAC-LC, 44100Hz, stereo
+        let aac = vec![0xaf, 0x00, 0x12, 0x10];
+        vec![video(0, avc), audio(0, aac)]
+    }
+
+    // Keyframe with cts 40ms
+    fn keyframe(timestamp: u32) -> RtmpMessage {
+        video(
+            timestamp,
+            vec![
+                0x17, 0x01, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x84,
+            ],
+        )
+    }
+
+    fn payload(pkt: &[u8]) -> &[u8] {
+        if pkt[3] & 0x20 != 0 {
+            &pkt[5 + pkt[4] as usize..]
+        } else {
+            &pkt[4..]
+        }
+    }
+
+    #[test]
+    fn test_pat_pmt() {
+        let mut muxer = TsMuxer::new();
+        assert!(muxer.write_tags(&sequence_headers()).unwrap().is_empty());
+        let data = muxer.write_tags(&[keyframe(1400)]).unwrap();
+        assert_eq!(data.len(), TS_PACKET_SIZE * 3);
+        for pkt in data.chunks(TS_PACKET_SIZE) {
+            assert_eq!(pkt[0], TS_SYNC_BYTE);
+        }
+
+        // Same as ffmpeg with h264 and aac
+        let pat = [
+            0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00,
+            0x01, 0xf0, 0x00, 0x2a, 0xb1, 0x04, 0xb2,
+        ];
+        assert_eq!(&data[..pat.len()], &pat);
+        assert!(data[pat.len()..TS_PACKET_SIZE].iter().all(|b| *b == 0xff));
+
+        let pmt = [
+            0x47, 0x50, 0x00, 0x10, 0x00, 0x02, 0xb0, 0x17, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1,
+            0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00, 0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00, 0x2f,
+            0x44, 0xb9, 0x9b,
+        ];
+        let pkt = &data[TS_PACKET_SIZE..TS_PACKET_SIZE * 2];
+        assert_eq!(&pkt[..pmt.len()], &pmt);
+        assert!(pkt[pmt.len()..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_video_pes() {
+        let mut muxer = TsMuxer::new();
+        muxer.write_tags(&sequence_headers()).unwrap();
+        let data = muxer.write_tags(&[keyframe(1400)]).unwrap();
+        let pkt = &data[TS_PACKET_SIZE * 2..];
+
+        // PUSI, video pid, random access with PCR 126000
+        assert_eq!(&pkt[..4], &[0x47, 0x41, 0x00, 0x30]);
+        assert_eq!(&pkt[5..12], &[0x50, 0x00, 0x00, 0xf6, 0x18, 0x7e, 0x00]);
+
+        let mut pes = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0xc0, 0x0a];
+        // PTS 129600, DTS 126000
+        pes.extend_from_slice(&[0x31, 0x00, 0x07, 0xf4, 0x81, 0x11, 0x00, 0x07, 0xd8, 0x61]);
+        // AUD, SPS, PPS and IDR in annexb
+        pes.extend_from_slice(&H264_AUD);
+        pes.extend_from_slice(&ANNEXB_START_CODE);
+        pes.extend_from_slice(&SPS);
+        pes.extend_from_slice(&ANNEXB_START_CODE);
+        pes.extend_from_slice(&PPS);
+        pes.extend_from_slice(&ANNEXB_START_CODE);
+        pes.extend_from_slice(&[0x65, 0x88, 0x84]);
+        assert_eq!(payload(pkt), &pes[..]);
+
+        // No PAT/PMT for non keyframe
+        let data = muxer
+            .write_tags(&[video(
+                1440,
+                vec![0x27, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x41, 0x9a],
+            )])
+            .unwrap();
+        assert_eq!(data.len(), TS_PACKET_SIZE);
+        assert_eq!(&data[..4], &[0x47, 0x41, 0x00, 0x31]);
+    }
+
+    #[test]
+    fn test_audio_pes() {
+        let mut muxer = TsMuxer::new();
+        muxer.write_tags(&sequence_headers()).unwrap();
+        muxer.write_tags(&[keyframe(1400)]).unwrap();
+        let data = muxer
+            .write_tags(&[audio(1400, vec![0xaf, 0x01, 0x21, 0x1b, 0x94, 0x00])])
+            .unwrap();
+        assert_eq!(data.len(), TS_PACKET_SIZE);
+        assert_eq!(&data[..4], &[0x47, 0x41, 0x01, 0x30]);
+
+        let pes = [
+            0x00, 0x00, 0x01, 0xc0, 0x00, 0x13, 0x80, 0x80, 0x05, 0x21, 0x00, 0x07, 0xd8, 0x61,
+            // ADTS header with frame length 11
+            0xff, 0xf1, 0x50, 0x80, 0x01, 0x7f, 0xfc, 0x21, 0x1b, 0x94, 0x00,
+        ];
+        assert_eq!(payload(&data), &pes[..]);
+        assert_eq!(muxer.get_audio_count(), 1);
+        assert_eq!(muxer.get_video_count(), 1);
+    }
+}
@@ -1,10 +1,10 @@
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, Bytes, BytesMut};
 use msir_core::transport::Transport;
-use std::{cmp, io::Cursor, time::Duration};
+use std::{cmp, collections::VecDeque, io::Cursor, time::Duration};
 use tracing::{debug, error, info, instrument, trace, warn};
 
-use crate::message::{decode, types::msg_type::*, RtmpMessage, RtmpPayload};
+use crate::message::{decode, decode_aggregate, types::msg_type::*, RtmpMessage, RtmpPayload};
 
 use self::error::ChunkError;
 
@@ -99,6 +99,8 @@ pub struct ChunkCodec {
     // chunk_streams_map: HashMap<u32, ChunkStream>, // TODO: Performance
     chunk_streams: [Option<ChunkStream>; PERF_CHUNK_STREAM_CACHE as usize],
     chunk_header_cache: Vec<u8>,
+    // The sub-messages of aggregate message to be received
+    pending_msgs: VecDeque<RtmpMessage>,
 }
 
 impl ChunkCodec {
@@ -116,6 +118,7 @@ impl ChunkCodec {
                 None, None, None, None, None, None, None, None,
             ],
             chunk_header_cache: Vec::with_capacity(16 * 128),
+            pending_msgs: VecDeque::new(),
         }
     }
 
@@ -143,8 +146,15 @@ impl ChunkCodec {
         self.out_chunk_size = n;
     }
 
+    pub fn get_out_chunk_size(&self) -> usize {
+        self.out_chunk_size
+    }
+
     pub async fn recv_rtmp_message(&mut self) -> Result<RtmpMessage> {
         loop {
+            if let Some(msg) = self.pending_msgs.pop_front() {
+                return Ok(msg);
+            }
             let payload = self.recv_interlaced_message().await?;
             match payload {
                 Some((b, mh)) => {
@@ -154,6 +164,10 @@ impl ChunkCodec {
                         timestamp: mh.timestamp,
                         raw_data: b,
                     };
+                    if data.message_type == AGGREGATE {
+                        self.pending_msgs.extend(decode_aggregate(data)?);
+                        continue;
+                    }
                     let msg = decode(data)?;
                     return Ok(msg);
                 }
@@ -194,6 +208,13 @@ impl ChunkCodec {
         Ok(())
     }
 
+    // Write the chunks encoded by encode_chunks
+    pub async fn send_chunks(&mut self, slices: &[Bytes]) -> Result<()> {
+        self.io.write_vectored_all(slices).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
     fn add_chunk_header(
         &mut self,
         msg: &RtmpPayload,
@@ -204,37 +225,8 @@ impl ChunkCodec {
             self.chunk_header_cache.clear();
         }
         let start = self.chunk_header_cache.len();
-        let perfer_cid = get_perfer_cid(msg.message_type) as u8;
-        if c0 {
-            let basic_header = (RTMP_FMT_TYPE0 << 6) | (perfer_cid & 0x3F);
-            let mut hlen = 1 + 3 + 3 + 1 + 4;
-            WriteBytesExt::write_u8(&mut self.chunk_header_cache, basic_header)?;
-            if msg.timestamp < RTMP_EXTENDED_TIMESTAMP {
-                self.chunk_header_cache
-                    .write_u24::<BigEndian>(msg.timestamp)?;
-            } else {
-                self.chunk_header_cache
-                    .write_u24::<BigEndian>(RTMP_EXTENDED_TIMESTAMP)?;
-            }
-            self.chunk_header_cache
-                .write_u24::<BigEndian>(msg.raw_data.len() as u32)?;
-            WriteBytesExt::write_u8(&mut self.chunk_header_cache, msg.message_type)?;
-            WriteBytesExt::write_u32::<LittleEndian>(&mut self.chunk_header_cache, msg.csid)?;
-            if msg.timestamp >= RTMP_EXTENDED_TIMESTAMP {
-                WriteBytesExt::write_u32::<BigEndian>(&mut self.chunk_header_cache, msg.timestamp)?;
-                hlen += 4;
-            }
-            Ok((start, start + hlen))
-        } else {
-            let basic_header = (RTMP_FMT_TYPE3 << 6) | (perfer_cid & 0x3F);
-            let mut hlen = 1;
-            WriteBytesExt::write_u8(&mut self.chunk_header_cache, basic_header)?;
-            if msg.timestamp >= RTMP_EXTENDED_TIMESTAMP {
-                WriteBytesExt::write_u32::<BigEndian>(&mut self.chunk_header_cache, msg.timestamp)?;
-                hlen += 4;
-            }
-            Ok((start, start + hlen))
-        }
+        write_chunk_header(&mut self.chunk_header_cache, msg, c0)?;
+        Ok((start, self.chunk_header_cache.len()))
     }
 
     async fn read_basic_header(&mut self) -> Result<(u8, u32)> {
@@ -401,6 +393,55 @@ impl ChunkCodec {
     }
 }
 
+// Split the messages into chunks, the headers and the payloads are returned in order as slices
+// without copying the payloads, so that they can be shared and written by write_vectored
+pub fn encode_chunks(msgs: &[RtmpPayload], chunk_size: usize) -> Result<Vec<Bytes>> {
+    let mut headers = Vec::with_capacity(msgs.len() * 16);
+    // (header range, message index, payload range)
+    let mut chunks = Vec::with_capacity(msgs.len());
+    for (i, msg) in msgs.iter().enumerate() {
+        let total = msg.raw_data.len();
+        let mut sent = 0_usize;
+        while sent < total {
+            let length = cmp::min(total - sent, chunk_size);
+            let start = headers.len();
+            write_chunk_header(&mut headers, msg, sent == 0)?;
+            chunks.push((start..headers.len(), i, sent..(sent + length)));
+            sent += length;
+        }
+    }
+    let headers = Bytes::from(headers);
+    let mut slices = Vec::with_capacity(chunks.len() * 2);
+    for (header, i, payload) in chunks {
+        slices.push(headers.slice(header));
+        slices.push(msgs[i].raw_data.slice(payload));
+    }
+    Ok(slices)
+}
+
+fn write_chunk_header(buf: &mut Vec<u8>, msg: &RtmpPayload, c0: bool) -> Result<()> {
+    let perfer_cid = get_perfer_cid(msg.message_type) as u8;
+    if c0 {
+        let basic_header = (RTMP_FMT_TYPE0 << 6) | (perfer_cid & 0x3F);
+        WriteBytesExt::write_u8(buf, basic_header)?;
+        if msg.timestamp < RTMP_EXTENDED_TIMESTAMP {
+            buf.write_u24::<BigEndian>(msg.timestamp)?;
+        } else {
+            buf.write_u24::<BigEndian>(RTMP_EXTENDED_TIMESTAMP)?;
+        }
+        buf.write_u24::<BigEndian>(msg.raw_data.len() as u32)?;
+        WriteBytesExt::write_u8(buf, msg.message_type)?;
+        WriteBytesExt::write_u32::<LittleEndian>(buf, msg.csid)?;
+    } else {
+        let basic_header = (RTMP_FMT_TYPE3 << 6) | (perfer_cid & 0x3F);
+        WriteBytesExt::write_u8(buf, basic_header)?;
+    }
+    if msg.timestamp >= RTMP_EXTENDED_TIMESTAMP {
+        WriteBytesExt::write_u32::<BigEndian>(buf, msg.timestamp)?;
+    }
+    Ok(())
+}
+
 fn get_perfer_cid(typ: u8) -> u32 {
     match typ {
         SET_CHUNK_SIZE | ABORT | ACK | USER_CONTROL | WIN_ACK_SIZE | SET_PEER_BW => {
@@ -414,3 +455,40 @@ fn get_perfer_cid(typ: u8) -> u32 {
         _ => RTMP_CID_PROTOCOL_CONTROL,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_chunks() {
+        let video = RtmpPayload {
+            message_type: VIDEO,
+            csid: 1,
+            timestamp: 0x01020304,
+            raw_data: Bytes::from(vec![0xaa; 300]),
+        };
+        let slices = encode_chunks(&[video], 128).unwrap();
+        // 300 bytes in 3 chunks, each with header and payload
+        assert_eq!(slices.len(), 6);
+        assert_eq!(
+            slices[0].as_ref(),
+            &[
+                0x06, 0xff, 0xff, 0xff, 0x00, 0x01, 0x2c, VIDEO, 0x01, 0x00, 0x00, 0x00, 0x01,
+                0x02, 0x03, 0x04
+            ]
+        );
+        // Type 3 with the extended timestamp
+        assert_eq!(slices[2].as_ref(), &[0xc6, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(slices[4].as_ref(), &[0xc6, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(
+            slices
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .map(|s| s.len())
+                .collect::<Vec<_>>(),
+            vec![128, 128, 44]
+        );
+    }
+}
@@ -1,8 +1,122 @@
 use bytes::Bytes;
 
+// Legacy video codec id of FLV video tag
+pub mod video_codec_id {
+    pub const AVC: u8 = 7;
+    // Not in the FLV spec, but widely used for HEVC before Enhanced RTMP
+    pub const HEVC: u8 = 12;
+}
+
+pub mod video_frame_type {
+    pub const KEY_FRAME: u8 = 1;
+}
+
+// FourCC of Enhanced RTMP
+pub mod fourcc {
+    pub const HEVC: [u8; 4] = *b"hvc1";
+    pub const AV1: [u8; 4] = *b"av01";
+    pub const VP9: [u8; 4] = *b"vp09";
+}
+
+// PacketType of Enhanced RTMP ExVideoTagHeader
+pub mod ex_video_packet_type {
+    pub const SEQUENCE_START: u8 = 0;
+    pub const CODED_FRAMES: u8 = 1;
+    pub const SEQUENCE_END: u8 = 2;
+    // CodedFrames without composition time
+    pub const CODED_FRAMES_X: u8 = 3;
+    pub const METADATA: u8 = 4;
+    pub const MPEG2TS_SEQUENCE_START: u8 = 5;
+}
+
+// Negotiated by fourCcList of connect
+pub const SUPPORTED_FOURCC: [&str; 3] = ["hvc1", "av01", "vp09"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    Avc,
+    Hevc,
+    Av1,
+    Vp9,
+    // Legacy codec id or unknown FourCC
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoPacketType {
+    SequenceHeader,
+    CodedFrames,
+    SequenceEnd,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoTagHeader {
+    pub frame_type: u8,
+    pub codec: VideoCodec,
+    pub packet_type: VideoPacketType,
+    // Whether the ExVideoTagHeader of Enhanced RTMP
+    pub enhanced: bool,
+}
+
+impl VideoTagHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let b = *data.first()?;
+        if b & 0x80 != 0 {
+            // IsExHeader | FrameType(3 bits) | PacketType(4 bits) | FourCC(4 bytes)
+            let fourcc: [u8; 4] = data.get(1..5)?.try_into().ok()?;
+            let codec = match fourcc {
+                fourcc::HEVC => VideoCodec::Hevc,
+                fourcc::AV1 => VideoCodec::Av1,
+                fourcc::VP9 => VideoCodec::Vp9,
+                _ => VideoCodec::Other,
+            };
+            let packet_type = match b & 0x0f {
+                ex_video_packet_type::SEQUENCE_START
+                | ex_video_packet_type::MPEG2TS_SEQUENCE_START => VideoPacketType::SequenceHeader,
+                ex_video_packet_type::CODED_FRAMES | ex_video_packet_type::CODED_FRAMES_X => {
+                    VideoPacketType::CodedFrames
+                }
+                ex_video_packet_type::SEQUENCE_END => VideoPacketType::SequenceEnd,
+                _ => VideoPacketType::Other,
+            };
+            return Some(Self {
+                frame_type: (b >> 4) & 0x07,
+                codec,
+                packet_type,
+                enhanced: true,
+            });
+        }
+
+        // FrameType(4 bits) | CodecID(4 bits) | AVCPacketType(1 byte), HEVC is the same as AVC
+        let codec = match b & 0x0f {
+            video_codec_id::AVC => VideoCodec::Avc,
+            video_codec_id::HEVC => VideoCodec::Hevc,
+            _ => VideoCodec::Other,
+        };
+        let packet_type = match codec {
+            VideoCodec::Other => VideoPacketType::CodedFrames,
+            _ => match *data.get(1)? {
+                0 => VideoPacketType::SequenceHeader,
+                1 => VideoPacketType::CodedFrames,
+                2 => VideoPacketType::SequenceEnd,
+                _ => VideoPacketType::Other,
+            },
+        };
+        Some(Self {
+            frame_type: b >> 4,
+            codec,
+            packet_type,
+            enhanced: false,
+        })
+    }
+}
+
 pub fn is_video_sequence_header(data: &Bytes) -> bool {
-    // This is assuming h264.
-    return data.len() >= 2 && data[0] == 0x17 && data[1] == 0x00;
+    match VideoTagHeader::parse(data) {
+        Some(h) => h.codec != VideoCodec::Other && h.packet_type == VideoPacketType::SequenceHeader,
+        None => false,
+    }
 }
 
 pub fn is_audio_sequence_header(data: &Bytes) -> bool {
@@ -11,6 +125,59 @@ pub fn is_audio_sequence_header(data: &Bytes) -> bool {
 }
 
 pub fn is_video_keyframe(data: &Bytes) -> bool {
-    // assumings h264
-    return data.len() >= 2 && data[0] == 0x17 && data[1] != 0x00; // 0x00 is the sequence header, don't count that for now
+    // The sequence header is not counted as keyframe
+    match VideoTagHeader::parse(data) {
+        Some(h) => {
+            h.frame_type == video_frame_type::KEY_FRAME
+                && h.packet_type == VideoPacketType::CodedFrames
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_tag_header() {
+        let avc_sh = Bytes::from_static(&[0x17, 0x00, 0x00, 0x00, 0x00]);
+        let avc_key = Bytes::from_static(&[0x17, 0x01, 0x00, 0x00, 0x00]);
+        let avc_inter = Bytes::from_static(&[0x27, 0x01, 0x00, 0x00, 0x00]);
+        assert!(is_video_sequence_header(&avc_sh));
+        assert!(!is_video_keyframe(&avc_sh));
+        assert!(is_video_keyframe(&avc_key));
+        assert!(!is_video_keyframe(&avc_inter));
+
+        // IsExHeader | KeyFrame | SequenceStart, hvc1
+        let hevc_sh = Bytes::from_static(&[0x90, b'h', b'v', b'c', b'1', 0x01]);
+        // IsExHeader | KeyFrame | CodedFramesX, av01
+        let av1_key = Bytes::from_static(&[0x93, b'a', b'v', b'0', b'1', 0x00]);
+        // IsExHeader | InterFrame | CodedFrames, vp09
+        let vp9_inter = Bytes::from_static(&[0xa1, b'v', b'p', b'0', b'9', 0x00]);
+        assert!(is_video_sequence_header(&hevc_sh));
+        assert!(!is_video_keyframe(&hevc_sh));
+        assert!(is_video_keyframe(&av1_key));
+        assert!(!is_video_keyframe(&vp9_inter));
+        assert_eq!(
+            VideoTagHeader::parse(&av1_key).unwrap().codec,
+            VideoCodec::Av1
+        );
+
+        // Legacy HEVC with codec id 12
+        let hevc_sh = Bytes::from_static(&[0x1c, 0x00, 0x00, 0x00, 0x00]);
+        let hevc_key = Bytes::from_static(&[0x1c, 0x01, 0x00, 0x00, 0x00]);
+        let hevc_inter = Bytes::from_static(&[0x2c, 0x01, 0x00, 0x00, 0x00]);
+        assert!(is_video_sequence_header(&hevc_sh));
+        assert!(!is_video_keyframe(&hevc_sh));
+        assert!(is_video_keyframe(&hevc_key));
+        assert!(!is_video_keyframe(&hevc_inter));
+        assert_eq!(
+            VideoTagHeader::parse(&hevc_key).unwrap().codec,
+            VideoCodec::Hevc
+        );
+
+        // Truncated FourCC
+        assert!(VideoTagHeader::parse(&[0x90, b'h', b'v']).is_none());
+    }
 }
@@ -0,0 +1,523 @@
+use serde_derive::Serialize;
+
+use crate::codec::{VideoCodec, VideoPacketType, VideoTagHeader};
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+// Codec info parsed from the sequence headers
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CodecInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VideoInfo {
+    pub codec: String,
+    // Only parsed for H.264, the others have the codec name only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AudioInfo {
+    pub codec: String,
+    pub profile: String,
+    pub object_type: u8,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl VideoInfo {
+    // Parse the payload of the video sequence header message
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let header = VideoTagHeader::parse(data)?;
+        if header.packet_type != VideoPacketType::SequenceHeader {
+            return None;
+        }
+        let codec = match header.codec {
+            VideoCodec::Avc => "H264",
+            VideoCodec::Hevc => "H265",
+            VideoCodec::Av1 => "AV1",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Other => return None,
+        };
+        let mut info = Self {
+            codec: codec.to_string(),
+            profile: None,
+            level: None,
+            width: None,
+            height: None,
+            fps: None,
+        };
+        if header.codec == VideoCodec::Avc {
+            // FrameType | CodecID | AVCPacketType | CompositionTime(3 bytes)
+            let record = AvcDecoderConfigurationRecord::parse(data.get(5..)?)?;
+            info.profile = Some(avc_profile_name(record.profile).to_string());
+            info.level = Some(format!("{}.{}", record.level / 10, record.level % 10));
+            if let Some(sps) = record.sps.first().and_then(|sps| Sps::parse(sps)) {
+                info.width = Some(sps.width);
+                info.height = Some(sps.height);
+                info.fps = sps.fps;
+            }
+        }
+        Some(info)
+    }
+}
+
+impl AudioInfo {
+    // Parse the payload of the AAC sequence header message
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        // SoundFormat(4 bits) | SoundRate(2 bits) | SoundSize(1 bit) | SoundType(1 bit) | AACPacketType
+        if data.len() < 2 || data[0] >> 4 != 10 || data[1] != 0 {
+            return None;
+        }
+        let asc = AudioSpecificConfig::parse(&data[2..])?;
+        Some(Self {
+            codec: "AAC".to_string(),
+            profile: aac_profile_name(asc.object_type).to_string(),
+            object_type: asc.object_type,
+            sample_rate: asc.sample_rate,
+            channels: asc.channels,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub profile: u8,
+    pub compatibility: u8,
+    pub level: u8,
+    pub nalu_length_size: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        // configurationVersion must be 1
+        if data.len() < 6 || data[0] != 1 {
+            return None;
+        }
+        let (sps, pos) = read_nalus(data, 6, (data[5] & 0x1f) as usize)?;
+        let (pps, _) = read_nalus(data, pos + 1, *data.get(pos)? as usize)?;
+        Some(Self {
+            profile: data[1],
+            compatibility: data[2],
+            level: data[3],
+            nalu_length_size: (data[4] & 0x03) + 1,
+            sps,
+            pps,
+        })
+    }
+}
+
+// Read the count NALUs prefixed with 16 bits length, return them and the next position
+fn read_nalus(data: &[u8], mut pos: usize, count: usize) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut nalus = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as usize;
+        nalus.push(data.get(pos + 2..pos + 2 + len)?.to_vec());
+        pos += 2 + len;
+    }
+    Some((nalus, pos))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile: u8,
+    pub level: u8,
+    pub width: u32,
+    pub height: u32,
+    // From the timing info of VUI
+    pub fps: Option<f64>,
+}
+
+impl Sps {
+    // Parse the SPS NALU, including the NALU header
+    pub fn parse(nalu: &[u8]) -> Option<Self> {
+        if nalu.first()? & 0x1f != 7 {
+            return None;
+        }
+        let rbsp = remove_emulation_prevention(&nalu[1..]);
+        let mut r = BitReader::new(&rbsp);
+
+        let profile = r.read_bits(8)? as u8;
+        let _constraint_flags = r.read_bits(8)?;
+        let level = r.read_bits(8)? as u8;
+        let _sps_id = r.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        if matches!(
+            profile,
+            100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+        ) {
+            chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc == 3 && r.read_bit()? {
+                // separate_colour_plane_flag, ChromaArrayType is 0
+                chroma_format_idc = 0;
+            }
+            let _bit_depth_luma = r.read_ue()?;
+            let _bit_depth_chroma = r.read_ue()?;
+            let _qpprime_y_zero_transform_bypass = r.read_bit()?;
+            if r.read_bit()? {
+                let count = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..count {
+                    if r.read_bit()? {
+                        r.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let _log2_max_frame_num = r.read_ue()?;
+        match r.read_ue()? {
+            0 => {
+                let _log2_max_pic_order_cnt_lsb = r.read_ue()?;
+            }
+            1 => {
+                let _delta_pic_order_always_zero = r.read_bit()?;
+                let _offset_for_non_ref_pic = r.read_se()?;
+                let _offset_for_top_to_bottom_field = r.read_se()?;
+                for _ in 0..r.read_ue()? {
+                    let _offset_for_ref_frame = r.read_se()?;
+                }
+            }
+            _ => {}
+        }
+        let _max_num_ref_frames = r.read_ue()?;
+        let _gaps_in_frame_num_allowed = r.read_bit()?;
+        let width_in_mbs = r.read_ue()? as u64 + 1;
+        let height_in_map_units = r.read_ue()? as u64 + 1;
+        let frame_mbs_only = r.read_bit()? as u32;
+        if frame_mbs_only == 0 {
+            let _mb_adaptive_frame_field = r.read_bit()?;
+        }
+        let _direct_8x8_inference = r.read_bit()?;
+
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+        if r.read_bit()? {
+            crop_left = r.read_ue()?;
+            crop_right = r.read_ue()?;
+            crop_top = r.read_ue()?;
+            crop_bottom = r.read_ue()?;
+        }
+        let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+            0 => (1, 2 - frame_mbs_only),
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        // In u64 for the invalid SPS with huge values
+        let width = (width_in_mbs * 16)
+            .checked_sub(crop_unit_x as u64 * (crop_left as u64 + crop_right as u64))?;
+        let height = ((2 - frame_mbs_only) as u64 * height_in_map_units * 16)
+            .checked_sub(crop_unit_y as u64 * (crop_top as u64 + crop_bottom as u64))?;
+
+        Some(Self {
+            profile,
+            level,
+            width: width.try_into().ok()?,
+            height: height.try_into().ok()?,
+            fps: r.read_vui_fps(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSpecificConfig {
+    pub object_type: u8,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut r = BitReader::new(data);
+        let mut object_type = r.read_bits(5)? as u8;
+        if object_type == 31 {
+            object_type = 32 + r.read_bits(6)? as u8;
+        }
+        let sample_rate = match r.read_bits(4)? {
+            15 => r.read_bits(24)?,
+            index => *AAC_SAMPLE_RATES.get(index as usize)?,
+        };
+        let channels = r.read_bits(4)? as u8;
+        Some(Self {
+            object_type,
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+fn avc_profile_name(profile: u8) -> &'static str {
+    match profile {
+        66 => "Baseline",
+        77 => "Main",
+        88 => "Extended",
+        100 => "High",
+        110 => "High 10",
+        122 => "High 4:2:2",
+        244 => "High 4:4:4",
+        _ => "Other",
+    }
+}
+
+fn aac_profile_name(object_type: u8) -> &'static str {
+    match object_type {
+        1 => "Main",
+        2 => "LC",
+        3 => "SSR",
+        4 => "LTP",
+        5 => "HE",
+        29 => "HEv2",
+        _ => "Other",
+    }
+}
+
+// Remove the 0x03 of 0x000003
+fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 0x01;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+        Some(value)
+    }
+
+    // Exp-Golomb
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.read_bit()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1_u64 << zeros) as u32 - 1 + self.read_bits(zeros)?)
+    }
+
+    fn read_se(&mut self) -> Option<i32> {
+        let v = self.read_ue()? as i64;
+        match v & 0x01 {
+            1 => Some(((v + 1) / 2) as i32),
+            _ => Some((-v / 2) as i32),
+        }
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let mut last = 8;
+        let mut next = 8;
+        for _ in 0..size {
+            if next != 0 {
+                next = (last + self.read_se()? + 256) % 256;
+            }
+            if next != 0 {
+                last = next;
+            }
+        }
+        Some(())
+    }
+
+    // Skip the VUI until the timing info, None if not present
+    fn read_vui_fps(&mut self) -> Option<f64> {
+        if !self.read_bit()? {
+            return None;
+        }
+        // aspect_ratio_info_present_flag
+        if self.read_bit()? && self.read_bits(8)? == 255 {
+            // Extended_SAR
+            self.read_bits(32)?;
+        }
+        // overscan_info_present_flag
+        if self.read_bit()? {
+            self.read_bit()?;
+        }
+        // video_signal_type_present_flag
+        if self.read_bit()? {
+            self.read_bits(4)?;
+            // colour_description_present_flag
+            if self.read_bit()? {
+                self.read_bits(24)?;
+            }
+        }
+        // chroma_loc_info_present_flag
+        if self.read_bit()? {
+            self.read_ue()?;
+            self.read_ue()?;
+        }
+        // timing_info_present_flag
+        if !self.read_bit()? {
+            return None;
+        }
+        let num_units_in_tick = self.read_bits(32)?;
+        let time_scale = self.read_bits(32)?;
+        if num_units_in_tick == 0 {
+            return None;
+        }
+        Some(time_scale as f64 / (2 * num_units_in_tick as u64) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write_bits(&mut self, n: u32, value: u32) {
+            for i in (0..n).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                let bit = ((value >> i) & 0x01) as u8;
+                *self.data.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn write_ue(&mut self, value: u32) {
+            let v = value + 1;
+            let len = 32 - v.leading_zeros();
+            self.write_bits(len - 1, 0);
+            self.write_bits(len, v);
+        }
+    }
+
+    // High profile 1920x1080 with 30fps timing info
+    fn sps_1080p() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.write_bits(8, 100); // profile_idc
+        w.write_bits(8, 0); // constraint flags
+        w.write_bits(8, 40); // level_idc
+        w.write_ue(0); // sps_id
+        w.write_ue(1); // chroma_format_idc
+        w.write_ue(0); // bit_depth_luma_minus8
+        w.write_ue(0); // bit_depth_chroma_minus8
+        w.write_bits(1, 0); // qpprime_y_zero_transform_bypass_flag
+        w.write_bits(1, 0); // seq_scaling_matrix_present_flag
+        w.write_ue(0); // log2_max_frame_num_minus4
+        w.write_ue(0); // pic_order_cnt_type
+        w.write_ue(2); // log2_max_pic_order_cnt_lsb_minus4
+        w.write_ue(4); // max_num_ref_frames
+        w.write_bits(1, 0); // gaps_in_frame_num_value_allowed_flag
+        w.write_ue(119); // pic_width_in_mbs_minus1
+        w.write_ue(67); // pic_height_in_map_units_minus1
+        w.write_bits(1, 1); // frame_mbs_only_flag
+        w.write_bits(1, 1); // direct_8x8_inference_flag
+        w.write_bits(1, 1); // frame_cropping_flag
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(4); // crop 8 lines of the bottom
+        w.write_bits(1, 1); // vui_parameters_present_flag
+        w.write_bits(1, 0); // aspect_ratio_info_present_flag
+        w.write_bits(1, 0); // overscan_info_present_flag
+        w.write_bits(1, 0); // video_signal_type_present_flag
+        w.write_bits(1, 0); // chroma_loc_info_present_flag
+        w.write_bits(1, 1); // timing_info_present_flag
+        w.write_bits(32, 1); // num_units_in_tick
+        w.write_bits(32, 60); // time_scale
+        w.write_bits(1, 1); // fixed_frame_rate_flag
+        let mut nalu = vec![0x67];
+        nalu.extend(w.data);
+        nalu
+    }
+
+    #[test]
+    fn test_video_info() {
+        let sps = sps_1080p();
+        let pps = [0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+        let mut payload = vec![0x17, 0x00, 0x00, 0x00, 0x00];
+        payload.extend_from_slice(&[0x01, 100, 0x00, 40, 0xff, 0xe1]);
+        payload.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&sps);
+        payload.push(0x01);
+        payload.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&pps);
+
+        let record = AvcDecoderConfigurationRecord::parse(&payload[5..]).unwrap();
+        assert_eq!(record.nalu_length_size, 4);
+        assert_eq!(record.sps, vec![sps]);
+        assert_eq!(record.pps, vec![pps.to_vec()]);
+
+        let info = VideoInfo::parse(&payload).unwrap();
+        assert_eq!(info.codec, "H264");
+        assert_eq!(info.profile.as_deref(), Some("High"));
+        assert_eq!(info.level.as_deref(), Some("4.0"));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+        assert_eq!(info.fps, Some(30.0));
+
+        // Enhanced RTMP HEVC has the codec name only
+        let hevc = VideoInfo::parse(&[0x90, b'h', b'v', b'c', b'1', 0x01]).unwrap();
+        assert_eq!(hevc.codec, "H265");
+        assert_eq!(hevc.width, None);
+    }
+
+    #[test]
+    fn test_audio_info() {
+        // AAC LC, 44100Hz, stereo
+        let info = AudioInfo::parse(&[0xaf, 0x00, 0x12, 0x10]).unwrap();
+        assert_eq!(info.profile, "LC");
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 2);
+        // Not a sequence header
+        assert!(AudioInfo::parse(&[0xaf, 0x01, 0x12, 0x10]).is_none());
+    }
+
+    #[test]
+    fn test_emulation_prevention() {
+        assert_eq!(
+            remove_emulation_prevention(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03]),
+            vec![0x00, 0x00, 0x01, 0x00, 0x00]
+        );
+    }
+}
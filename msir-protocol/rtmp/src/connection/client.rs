@@ -1,14 +1,30 @@
 use msir_core::transport::Transport;
 use rml_amf0::Amf0Value;
-use std::time::Duration;
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
 use tracing::info;
+use url::Url;
 
 use crate::{
     handshake,
     message::{
-        request::Request,
-        types::{amf0_command_type::*, user_ctrl_ev_type::SET_BUFFER_LENGTH, DEFAULT_SID},
+        request::{ClientInfo, Request},
+        types::{
+            amf0_command_type::*,
+            rtmp_status::{
+                STATUS_CODE_DATA_START, STATUS_CODE_PUBLISH_START, STATUS_CODE_STREAM_RESET,
+                STATUS_CODE_STREAM_START,
+            },
+            user_ctrl_ev_type::SET_BUFFER_LENGTH,
+            DEFAULT_SID,
+        },
         RtmpMessage,
     },
 };
@@ -22,13 +38,24 @@ pub struct Client {
 
 impl Client {
     pub async fn new(tc_url: String, stream: String) -> Result<Self, ConnectionError> {
+        let req = Request::parse_from(tc_url.clone())?;
+
+        // TCP or TLS connect to peer
+        let (io, info) = connect(&req.tc_url).await?;
+        let mut client = Self::from_transport(io, tc_url, stream).await?;
+        client.req.client = info;
+        Ok(client)
+    }
+
+    // Over a connected transport, e.g. a tunnel or an in-memory pipe
+    pub async fn from_transport(
+        mut io: Transport,
+        tc_url: String,
+        stream: String,
+    ) -> Result<Self, ConnectionError> {
         let mut req = Request::parse_from(tc_url)?;
         req.stream = Some(stream);
 
-        // TCP connect to peer
-        let addr = req.tc_url.socket_addrs(|| Some(1935))?;
-        let mut io = Transport::new(TcpStream::connect(addr[0]).await?);
-
         // Handshake with peer
         let mut hc = handshake::Client::new();
         hc.handshake(&mut io).await?;
@@ -63,6 +90,14 @@ impl Client {
         self.ctx.get_in_video_count()
     }
 
+    pub fn get_out_audio_count(&mut self) -> u64 {
+        self.ctx.get_out_audio_count()
+    }
+
+    pub fn get_out_video_count(&mut self) -> u64 {
+        self.ctx.get_out_video_count()
+    }
+
     pub async fn recv_message(&mut self) -> Result<RtmpMessage, ConnectionError> {
         self.ctx.recv_message().await
     }
@@ -76,7 +111,21 @@ impl Client {
         self.ctx.send_message(msg, timestamp, csid).await
     }
 
+    pub async fn send_messages(
+        &mut self,
+        msgs: &[RtmpMessage],
+        timestamp: u32,
+        csid: u32,
+    ) -> Result<(), ConnectionError> {
+        self.ctx.send_messages(msgs, timestamp, csid).await
+    }
+
     pub async fn connect(&mut self, uid: String) -> Result<f64, ConnectionError> {
+        self.connect_app(uid).await?;
+        self.create_stream(2.0).await
+    }
+
+    async fn connect_app(&mut self, uid: String) -> Result<(), ConnectionError> {
         // Connect app
         self.send_message(RtmpMessage::new_connect_app(&self.req, uid), 0, 0)
             .await?;
@@ -95,13 +144,16 @@ impl Client {
         self.ctx.expect_amf_command(&[COMMAND_RESULT]).await?;
 
         // TODO: Get server info
+        Ok(())
+    }
 
+    async fn create_stream(&mut self, transaction_id: f64) -> Result<f64, ConnectionError> {
         // Create stream
-        self.send_message(RtmpMessage::new_create_stream(), 0, 0)
+        self.send_message(RtmpMessage::new_create_stream(transaction_id), 0, 0)
             .await?;
 
         // Expect create stream _result, get stream_id
-        match self.ctx.expect_amf_command(&[COMMAND_RESULT]).await? {
+        match self.ctx.expect_result_or_error(transaction_id).await? {
             RtmpMessage::Amf0Command {
                 additional_arguments,
                 ..
@@ -140,6 +192,19 @@ impl Client {
         )
         .await?;
 
+        // Expect onStatus NetStream.Play.Start, e.g. rejected if the stream is not found
+        loop {
+            let msg = self
+                .ctx
+                .expect_amf_command(&[COMMAND_ON_STATUS, COMMAND_ERROR])
+                .await?;
+            match msg.status_code() {
+                Some(STATUS_CODE_STREAM_START) => break,
+                Some(STATUS_CODE_STREAM_RESET) | Some(STATUS_CODE_DATA_START) | None => continue,
+                Some(code) => return Err(ConnectionError::Rejected(code.to_string())),
+            }
+        }
+
         // SetChunkSize
         self.send_message(RtmpMessage::SetChunkSize { chunk_size: 60000 }, 0, 0)
             .await?;
@@ -151,4 +216,80 @@ impl Client {
         );
         Ok(())
     }
+
+    pub async fn publish(&mut self, uid: String) -> Result<u32, ConnectionError> {
+        let stream = self.req.stream().to_string();
+        self.connect_app(uid).await?;
+
+        // The results of releaseStream and FCPublish are ignored, some servers do not response them
+        self.send_message(RtmpMessage::new_release_stream(stream.clone(), 2.0), 0, 0)
+            .await?;
+        self.send_message(RtmpMessage::new_fcpublish(stream.clone(), 3.0), 0, 0)
+            .await?;
+        let stream_id = self.create_stream(4.0).await? as u32;
+
+        // Publish stream
+        self.send_message(RtmpMessage::new_publish_stream(stream, 5.0), 0, stream_id)
+            .await?;
+
+        // Expect onStatus NetStream.Publish.Start
+        loop {
+            let msg = self
+                .ctx
+                .expect_amf_command(&[COMMAND_ON_STATUS, COMMAND_ERROR])
+                .await?;
+            match msg.status_code() {
+                Some(STATUS_CODE_PUBLISH_START) => break,
+                Some(code) => return Err(ConnectionError::Rejected(code.to_string())),
+                None => continue,
+            }
+        }
+
+        // SetChunkSize
+        self.send_message(RtmpMessage::SetChunkSize { chunk_size: 60000 }, 0, 0)
+            .await?;
+
+        info!(
+            "Publish tc_url:{}, stream:{} succeed",
+            self.req.tc_url,
+            self.req.stream()
+        );
+        Ok(stream_id)
+    }
+}
+
+// RTMPS is RTMP over TLS, and defaults to port 443
+async fn connect(tc_url: &Url) -> Result<(Transport, ClientInfo), ConnectionError> {
+    let tls = tc_url.scheme() == "rtmps";
+    let addr = tc_url.socket_addrs(|| Some(if tls { 443 } else { 1935 }))?;
+    let tcp = TcpStream::connect(addr[0]).await?;
+    let info = ClientInfo {
+        remote_addr: tcp.peer_addr().ok(),
+        local_addr: tcp.local_addr().ok(),
+        ..Default::default()
+    };
+    if !tls {
+        return Ok((Transport::new(tcp), info));
+    }
+    let host = tc_url.host_str().unwrap_or_default().to_string();
+    let name =
+        ServerName::try_from(host.clone()).map_err(|_| ConnectionError::InvalidServerName(host))?;
+    let connector = TlsConnector::from(tls_config());
+    Ok((Transport::new(connector.connect(name, tcp).await?), info))
+}
+
+// Verified by the Mozilla root certificates
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
 }
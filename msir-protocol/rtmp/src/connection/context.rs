@@ -1,13 +1,13 @@
 use crate::{
     chunk::ChunkCodec,
-    message::{types::*, RtmpMessage},
+    message::{error::MessageEncodeError, types::*, RtmpMessage, RtmpPayload},
 };
 
 use msir_core::transport::Transport;
 use std::{collections::HashMap, time::Duration};
 use tracing::{debug, error, info, trace, warn};
 
-use super::error::ConnectionError;
+use super::{error::ConnectionError, EncodedChunks};
 
 #[derive(Default)]
 struct AckWindowSize {
@@ -25,6 +25,8 @@ pub struct Context {
     in_video_count: u64,
     out_audio_count: u64,
     out_video_count: u64,
+    // Commands are sent in AMF3 if the client connects with objectEncoding 3
+    amf3: bool,
 }
 
 impl Context {
@@ -39,6 +41,7 @@ impl Context {
             in_video_count: 0,
             out_audio_count: 0,
             out_video_count: 0,
+            amf3: false,
         }
     }
 
@@ -50,6 +53,14 @@ impl Context {
         self.chunk_io.set_send_timeout(tm);
     }
 
+    pub fn get_out_chunk_size(&self) -> usize {
+        self.chunk_io.get_out_chunk_size()
+    }
+
+    pub fn set_object_encoding(&mut self, object_encoding: f64) {
+        self.amf3 = object_encoding == rtmp_sig::RTMP_SIG_AMF3_VER;
+    }
+
     pub fn get_recv_bytes(&mut self) -> u64 {
         self.chunk_io.get_recv_bytes()
     }
@@ -132,7 +143,7 @@ impl Context {
         csid: u32,
     ) -> Result<(), ConnectionError> {
         self.on_send_message(&msg)?;
-        let payload = crate::message::encode(msg, timestamp, csid)?;
+        let payload = self.encode(msg, timestamp, csid)?;
         self.chunk_io.send_rtmp_message(payload).await?;
         Ok(())
     }
@@ -146,12 +157,35 @@ impl Context {
         let mut payloads = Vec::with_capacity(msgs.len());
         for msg in msgs {
             self.on_send_message(msg)?;
-            payloads.push(crate::message::encode(msg.clone(), timestamp, csid)?);
+            payloads.push(self.encode(msg.clone(), timestamp, csid)?);
         }
         self.chunk_io.send_rtmp_messages(&payloads).await?;
         Ok(())
     }
 
+    pub async fn send_chunks(&mut self, chunks: &[&EncodedChunks]) -> Result<(), ConnectionError> {
+        let mut slices = Vec::with_capacity(chunks.iter().map(|c| c.slices.len()).sum());
+        for c in chunks {
+            self.out_audio_count += c.audio;
+            self.out_video_count += c.video;
+            slices.extend_from_slice(&c.slices);
+        }
+        self.chunk_io.send_chunks(&slices).await?;
+        Ok(())
+    }
+
+    fn encode(
+        &self,
+        msg: RtmpMessage,
+        timestamp: u32,
+        csid: u32,
+    ) -> Result<RtmpPayload, MessageEncodeError> {
+        match self.amf3 {
+            true => crate::message::encode_amf3(msg, timestamp, csid),
+            false => crate::message::encode(msg, timestamp, csid),
+        }
+    }
+
     fn on_send_message(&mut self, msg: &RtmpMessage) -> Result<(), ConnectionError> {
         trace!("Send {}", msg);
         match msg {
@@ -179,7 +213,32 @@ impl Context {
             }
         }
     }
-    // pub async fn expect_result_or_error(&mut self, transcation_id: f64) -> Result<RtmpMessage, ConnectionError> {
 
-    // }
+    // Expect the _result of the request with transaction_id, or fail with its _error
+    pub async fn expect_result_or_error(
+        &mut self,
+        expect_id: f64,
+    ) -> Result<RtmpMessage, ConnectionError> {
+        loop {
+            let msg = self.recv_message().await?;
+            if let RtmpMessage::Amf0Command {
+                command_name,
+                transaction_id,
+                ..
+            } = &msg
+            {
+                if *transaction_id != expect_id {
+                    continue;
+                }
+                if command_name == amf0_command_type::COMMAND_RESULT {
+                    return Ok(msg);
+                }
+                if command_name == amf0_command_type::COMMAND_ERROR {
+                    return Err(ConnectionError::Rejected(
+                        msg.status_code().unwrap_or("").to_string(),
+                    ));
+                }
+            }
+        }
+    }
 }
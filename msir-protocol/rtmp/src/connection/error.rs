@@ -34,12 +34,18 @@ pub enum ConnectionError {
     #[error("The publish msg is invalid")]
     InvalidPublish,
 
+    #[error("The request is rejected by peer: {0}")]
+    Rejected(String),
+
     #[error("Encode rtmp message failed: {0}")]
     RtmpMessageEncode(#[from] MessageEncodeError),
 
     #[error("Invalid chunk size: {0}")]
     InvalidChunkSize(usize),
 
+    #[error("Invalid TLS server name: {0}")]
+    InvalidServerName(String),
+
     // Failed to read the values
     #[error("An IO error occurred: {0}")]
     Io(#[from] io::Error),
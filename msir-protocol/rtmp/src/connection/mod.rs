@@ -1,5 +1,13 @@
+use bytes::Bytes;
 use serde_derive::Serialize;
 
+use crate::{
+    chunk,
+    message::{encode, RtmpMessage},
+};
+
+use self::error::ConnectionError;
+
 pub mod client;
 mod context;
 pub mod error;
@@ -9,6 +17,10 @@ pub mod server;
 pub enum RtmpConnType {
     Play,
     FlvPlay,
+    HlsPlay,
+    TsPlay,
+    Dvr,
+    Forward,
     Pull,
     FmlePublish,
     FlashPublish,
@@ -29,7 +41,12 @@ impl RtmpConnType {
 
     pub fn is_play(&self) -> bool {
         match self {
-            RtmpConnType::FlvPlay | RtmpConnType::Play => true,
+            RtmpConnType::FlvPlay
+            | RtmpConnType::HlsPlay
+            | RtmpConnType::TsPlay
+            | RtmpConnType::Dvr
+            | RtmpConnType::Forward
+            | RtmpConnType::Play => true,
             _ => false,
         }
     }
@@ -39,6 +56,10 @@ impl RtmpConnType {
             RtmpConnType::Pull => "pull",
             RtmpConnType::Play => "play",
             RtmpConnType::FlvPlay => "flv-play",
+            RtmpConnType::HlsPlay => "hls-play",
+            RtmpConnType::TsPlay => "ts-play",
+            RtmpConnType::Dvr => "dvr",
+            RtmpConnType::Forward => "forward",
             RtmpConnType::FmlePublish => "publish",
             RtmpConnType::FlashPublish => "publish",
             RtmpConnType::HaivisionPublish => "publish",
@@ -55,3 +76,144 @@ pub enum RtmpCtrlAction {
     // From player
     Close,
 }
+
+// Media messages encoded into chunks once, then shared by the players with the same out chunk size
+#[derive(Debug, Default)]
+pub struct EncodedChunks {
+    slices: Vec<Bytes>,
+    audio: u64,
+    video: u64,
+}
+
+impl EncodedChunks {
+    pub fn encode(msgs: &[RtmpMessage], chunk_size: usize) -> Result<Self, ConnectionError> {
+        let (mut audio, mut video) = (0, 0);
+        let mut payloads = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            match msg {
+                RtmpMessage::AudioData { .. } => audio += 1,
+                RtmpMessage::VideoData { .. } => video += 1,
+                _ => {}
+            }
+            payloads.push(encode(msg.clone(), 0, 0)?);
+        }
+        Ok(Self {
+            slices: chunk::encode_chunks(&payloads, chunk_size)?,
+            audio,
+            video,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client::Client, server::Server, *};
+    use msir_core::transport::Transport;
+    use tokio::io::duplex;
+
+    const TC_URL: &str = "rtmp://127.0.0.1/live";
+
+    fn av_msgs() -> Vec<RtmpMessage> {
+        vec![
+            RtmpMessage::VideoData {
+                stream_id: 1,
+                timestamp: 0,
+                payload: Bytes::from(vec![0x17; 100000]),
+            },
+            RtmpMessage::AudioData {
+                stream_id: 1,
+                timestamp: 23,
+                payload: Bytes::from(vec![0xaf; 300]),
+            },
+        ]
+    }
+
+    fn assert_av(msgs: &[RtmpMessage]) {
+        assert!(matches!(
+            &msgs[0],
+            RtmpMessage::VideoData { timestamp: 0, payload, .. } if payload.len() == 100000
+        ));
+        assert!(matches!(
+            &msgs[1],
+            RtmpMessage::AudioData { timestamp: 23, payload, .. } if payload[..] == [0xaf; 300]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_publish_over_duplex() {
+        let (c, s) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = Server::new(Transport::new(s)).await.unwrap();
+            let req = server.identify_client().await.unwrap();
+            assert!(matches!(req.conn_type, RtmpConnType::FmlePublish));
+            assert_eq!(req.stream(), "test");
+            // The connect command object is kept
+            assert_eq!(req.client.app.as_deref(), Some("live"));
+            assert_eq!(req.client.flash_ver.as_deref(), Some("WIN 15,0,0,239"));
+            assert_eq!(req.client.tc_url.as_deref(), Some(TC_URL));
+            assert_eq!(req.client.object_encoding, Some(0.0));
+            server.start_fmle_publish().await.unwrap();
+
+            let mut msgs = Vec::new();
+            while msgs.len() < 2 {
+                let msg = server.recv_message().await.unwrap();
+                if matches!(
+                    msg,
+                    RtmpMessage::VideoData { .. } | RtmpMessage::AudioData { .. }
+                ) {
+                    msgs.push(msg);
+                }
+            }
+            assert_av(&msgs);
+            (server.get_audio_count(), server.get_video_count())
+        });
+
+        let io = Transport::new(c);
+        let mut client = Client::from_transport(io, TC_URL.to_string(), "test".to_string())
+            .await
+            .unwrap();
+        let sid = client.publish("uid".to_string()).await.unwrap();
+        client.send_messages(&av_msgs(), 0, sid).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), (1, 1));
+        assert!(client.get_send_bytes() > 100300);
+    }
+
+    #[tokio::test]
+    async fn test_play_over_duplex() {
+        let (c, s) = duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut server = Server::new(Transport::new(s)).await.unwrap();
+            let req = server.identify_client().await.unwrap();
+            assert!(matches!(req.conn_type, RtmpConnType::Play));
+            assert_eq!(req.stream(), "test");
+            server.start_play().await.unwrap();
+
+            // Sent by the pre-encoded chunks as the Hub does
+            let chunks = EncodedChunks::encode(&av_msgs(), server.get_out_chunk_size()).unwrap();
+            server.send_chunks(&[&chunks]).await.unwrap();
+            server.get_send_bytes()
+        });
+
+        let io = Transport::new(c);
+        let mut client = Client::from_transport(io, TC_URL.to_string(), "test".to_string())
+            .await
+            .unwrap();
+        let sid = client.connect("uid".to_string()).await.unwrap();
+        client.play(sid as u32).await.unwrap();
+
+        let mut msgs = Vec::new();
+        while msgs.len() < 2 {
+            let msg = client.recv_message().await.unwrap();
+            if matches!(
+                msg,
+                RtmpMessage::VideoData { .. } | RtmpMessage::AudioData { .. }
+            ) {
+                msgs.push(msg);
+            }
+        }
+        assert_av(&msgs);
+        assert_eq!((client.get_audio_count(), client.get_video_count()), (1, 1));
+        assert!(server.await.unwrap() > 100300);
+    }
+}
@@ -1,12 +1,12 @@
 use msir_core::transport::Transport;
 use rml_amf0::Amf0Value;
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 use tracing::{info, trace, warn};
 
 use crate::{
-    handshake,
+    codec, handshake,
     message::{
-        request::Request,
+        request::{ClientInfo, Request},
         types::{
             amf0_command_type::*, peer_bw_limit_type, rtmp_sig::*, user_ctrl_ev_type::*,
             DEFAULT_SID,
@@ -15,7 +15,9 @@ use crate::{
     },
 };
 
-use super::{context::Context, error::ConnectionError, RtmpConnType, RtmpCtrlAction};
+use super::{
+    context::Context, error::ConnectionError, EncodedChunks, RtmpConnType, RtmpCtrlAction,
+};
 
 pub struct Server {
     ctx: Context,
@@ -84,6 +86,15 @@ impl Server {
         self.ctx.send_messages(&msgs, timestamp, csid).await
     }
 
+    // The chunks are encoded with get_out_chunk_size
+    pub async fn send_chunks(&mut self, chunks: &[&EncodedChunks]) -> Result<(), ConnectionError> {
+        self.ctx.send_chunks(chunks).await
+    }
+
+    pub fn get_out_chunk_size(&self) -> usize {
+        self.ctx.get_out_chunk_size()
+    }
+
     pub async fn identify_client(&mut self) -> Result<Request, ConnectionError> {
         let mut req = self.connect_app().await?;
         loop {
@@ -141,6 +152,22 @@ impl Server {
                 Amf0Value::Object(properties) => properties,
                 _ => return Err(ConnectionError::InvalidConnectApp),
             };
+            // Kept for tracing the client
+            let client = ClientInfo {
+                flash_ver: take_string(&mut properties, "flashVer"),
+                swf_url: take_string(&mut properties, "swfUrl"),
+                page_url: take_string(&mut properties, "pageUrl"),
+                tc_url: match properties.get("tcUrl") {
+                    Some(Amf0Value::Utf8String(tc_url)) => Some(tc_url.clone()),
+                    _ => None,
+                },
+                app: take_string(&mut properties, "app"),
+                object_encoding: match properties.get("objectEncoding") {
+                    Some(Amf0Value::Number(number)) => Some(*number),
+                    _ => None,
+                },
+                ..Default::default()
+            };
             let tc_url = match properties.remove("tcUrl") {
                 Some(value) => match value {
                     Amf0Value::Utf8String(tc_url) => tc_url,
@@ -157,7 +184,23 @@ impl Server {
                 None => RTMP_SIG_AMF0_VER,
             };
 
-            let request = Request::parse_from(tc_url)?;
+            // Enhanced RTMP, "*" means any codec
+            let fourcc_list: Vec<String> = match properties.remove("fourCcList") {
+                Some(Amf0Value::StrictArray(list)) => codec::SUPPORTED_FOURCC
+                    .iter()
+                    .filter(|f| {
+                        list.iter().any(|v| match v {
+                            Amf0Value::Utf8String(s) => s == *f || s == "*",
+                            _ => false,
+                        })
+                    })
+                    .map(|f| f.to_string())
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+            let mut request = Request::parse_from(tc_url)?;
+            request.client = client;
 
             // Set in_win_ack, default = 0
             self.ctx.set_in_window_ack_size(0);
@@ -187,9 +230,14 @@ impl Server {
             self.send_message(RtmpMessage::SetChunkSize { chunk_size: 60000 }, 0, 0)
                 .await?;
 
-            // Response connect
-            self.send_message(RtmpMessage::new_connect_app_res(object_encoding), 0, 0)
-                .await?;
+            // Response connect, in the same object encoding as the client
+            self.ctx.set_object_encoding(object_encoding);
+            self.send_message(
+                RtmpMessage::new_connect_app_res(object_encoding, &fourcc_list),
+                0,
+                0,
+            )
+            .await?;
 
             // on bw_done
             self.send_message(RtmpMessage::new_on_bw_done(), 0, 0)
@@ -256,7 +304,7 @@ impl Server {
             return Err(ConnectionError::ReleaseStreamWithoutStream);
         }
         match &additional_arguments[0] {
-            Amf0Value::Utf8String(stream) => req.stream = Some(stream.clone()),
+            Amf0Value::Utf8String(stream) => req.set_stream(stream),
             _ => return Err(ConnectionError::ReleaseStreamWithoutStream),
         }
         // Response releaseStream
@@ -275,7 +323,7 @@ impl Server {
             return Err(ConnectionError::InvalidPublish);
         }
         match &additional_arguments[0] {
-            Amf0Value::Utf8String(stream) => req.stream = Some(stream.clone()),
+            Amf0Value::Utf8String(stream) => req.set_stream(stream),
             _ => return Err(ConnectionError::InvalidPublish),
         }
         Ok(())
@@ -292,7 +340,7 @@ impl Server {
             return Err(ConnectionError::InvalidPublish);
         }
         match &additional_arguments[0] {
-            Amf0Value::Utf8String(stream) => req.stream = Some(stream.clone()),
+            Amf0Value::Utf8String(stream) => req.set_stream(stream),
             _ => return Err(ConnectionError::InvalidPublish),
         }
         // Response FCPublish
@@ -311,7 +359,7 @@ impl Server {
             return Err(ConnectionError::InvalidPlay);
         }
         match &additional_arguments[0] {
-            Amf0Value::Utf8String(stream) => req.stream = Some(stream.clone()),
+            Amf0Value::Utf8String(stream) => req.set_stream(stream),
             _ => return Err(ConnectionError::InvalidPlay),
         }
         if additional_arguments.len() >= 3 {
@@ -419,6 +467,35 @@ impl Server {
         Ok(())
     }
 
+    pub async fn reject_publish(&mut self, description: &str) -> Result<(), ConnectionError> {
+        // response onStatus(NetStream.Publish.BadName)
+        self.send_message(
+            RtmpMessage::new_on_status_publish_bad_name(description),
+            0,
+            0,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn reject_play(&mut self, description: &str) -> Result<(), ConnectionError> {
+        // response _error(NetConnection.Connect.Rejected)
+        self.send_message(RtmpMessage::new_error_rejected(description), 0, 0)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn kick_play(&mut self, description: &str) -> Result<(), ConnectionError> {
+        // onStatus(NetStream.Play.UnpublishNotify)
+        self.send_message(
+            RtmpMessage::new_on_status_play_unpublish_notify(description),
+            0,
+            0,
+        )
+        .await?;
+        Ok(())
+    }
+
     pub async fn process_amf_command(
         &mut self,
         msg: RtmpMessage,
@@ -519,3 +596,10 @@ impl Server {
         }
     }
 }
+
+fn take_string(properties: &mut HashMap<String, Amf0Value>, key: &str) -> Option<String> {
+    match properties.remove(key) {
+        Some(Amf0Value::Utf8String(s)) => Some(s),
+        _ => None,
+    }
+}
@@ -1,6 +1,58 @@
 use super::{context::Context, error::HandshakeError, RTMP_HANDSHAKE_SIZE, RTMP_VERSION};
-use msir_core::transport::Transport;
-use tracing::{debug, error, info, info_span, instrument, trace};
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use msir_core::{transport::Transport, utils};
+use rand::Rng;
+use sha2::Sha256;
+use tracing::{debug, trace};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 68 bytes = "Genuine Adobe Flash Media Server 001" + 32 bytes random
+const GENUINE_FMS_KEY: [u8; 68] = [
+    0x47, 0x65, 0x6e, 0x75, 0x69, 0x6e, 0x65, 0x20, 0x41, 0x64, 0x6f, 0x62, 0x65, 0x20, 0x46, 0x6c,
+    0x61, 0x73, 0x68, 0x20, 0x4d, 0x65, 0x64, 0x69, 0x61, 0x20, 0x53, 0x65, 0x72, 0x76, 0x65, 0x72,
+    0x20, 0x30, 0x30, 0x31, 0xf0, 0xee, 0xc2, 0x4a, 0x80, 0x68, 0xbe, 0xe8, 0x2e, 0x00, 0xd0, 0xd1,
+    0x02, 0x9e, 0x7e, 0x57, 0x6e, 0xec, 0x5d, 0x2d, 0x29, 0x80, 0x6f, 0xab, 0x93, 0xb8, 0xe6, 0x36,
+    0xcf, 0xeb, 0x31, 0xae,
+];
+// 62 bytes = "Genuine Adobe Flash Player 001" + 32 bytes random
+const GENUINE_FP_KEY: [u8; 62] = [
+    0x47, 0x65, 0x6e, 0x75, 0x69, 0x6e, 0x65, 0x20, 0x41, 0x64, 0x6f, 0x62, 0x65, 0x20, 0x46, 0x6c,
+    0x61, 0x73, 0x68, 0x20, 0x50, 0x6c, 0x61, 0x79, 0x65, 0x72, 0x20, 0x30, 0x30, 0x31, 0xf0, 0xee,
+    0xc2, 0x4a, 0x80, 0x68, 0xbe, 0xe8, 0x2e, 0x00, 0xd0, 0xd1, 0x02, 0x9e, 0x7e, 0x57, 0x6e, 0xec,
+    0x5d, 0x2d, 0x29, 0x80, 0x6f, 0xab, 0x93, 0xb8, 0xe6, 0x36, 0xcf, 0xeb, 0x31, 0xae,
+];
+// The text part of keys, used to sign c1/s1
+const GENUINE_FMS_KEY_TEXT_LEN: usize = 36;
+const GENUINE_FP_KEY_TEXT_LEN: usize = 30;
+
+const DIGEST_LEN: usize = 32;
+// Both key and digest block are 764 bytes
+const BLOCK_SIZE: usize = 764;
+// The digest offset is in [0, 764-4-32)
+const DIGEST_OFFSET_MOD: usize = BLOCK_SIZE - 4 - DIGEST_LEN;
+
+// Version of c1/s1, non-zero means complex handshake
+const CLIENT_VERSION: [u8; 4] = [0x0c, 0x00, 0x0d, 0x0e];
+const SERVER_VERSION: [u8; 4] = [0x04, 0x05, 0x00, 0x01];
+
+// c1s1 schema0: time(4) version(4) key(764) digest(764)
+// c1s1 schema1: time(4) version(4) digest(764) key(764)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Schema {
+    Schema0,
+    Schema1,
+}
+
+impl Schema {
+    fn digest_block_start(&self) -> usize {
+        match self {
+            Schema::Schema0 => 8 + BLOCK_SIZE,
+            Schema::Schema1 => 8,
+        }
+    }
+}
 
 pub struct ComplexHandshake {}
 
@@ -10,17 +62,225 @@ impl ComplexHandshake {
         ctx: &mut Context,
         io: &mut Transport,
     ) -> Result<(), HandshakeError> {
-        trace!("Complex handshake do not implement, try simple");
+        create_c0c1(ctx, Schema::Schema1);
+
+        io.write_all(&ctx.c0c1[0..]).await?;
+        io.flush().await?;
+
+        ctx.read_s0s1s2(io).await?;
+
+        if ctx.s0s1s2[0] != RTMP_VERSION {
+            return Err(HandshakeError::InvalidVersion(ctx.s0s1s2[0]));
+        }
+
+        // Server responsed a simple s1, the simple handshake will finish c2
+        let s1 = &ctx.s0s1s2[1..RTMP_HANDSHAKE_SIZE + 1];
+        let s1_digest = match validate_c1s1(s1, &GENUINE_FMS_KEY[..GENUINE_FMS_KEY_TEXT_LEN]) {
+            Some((schema, offset)) => {
+                trace!("Validate s1 ok, {:?} digest offset {}", schema, offset);
+                s1[offset..offset + DIGEST_LEN].to_vec()
+            }
+            None => {
+                trace!("Validate s1 failed, try simple");
+                return Err(HandshakeError::TrySimpleHandshake);
+            }
+        };
+
+        create_c2s2(&mut ctx.c2, &GENUINE_FP_KEY, &s1_digest);
+
+        io.write_all(&ctx.c2[0..]).await?;
+        io.flush().await?;
+
+        debug!("Complex handshake completed");
 
-        Err(HandshakeError::TrySimpleHandshake)
+        Ok(())
     }
     pub async fn handshake_with_client(
         &self,
         ctx: &mut Context,
         io: &mut Transport,
     ) -> Result<(), HandshakeError> {
-        trace!("Complex handshake do not implement, try simple");
+        ctx.read_c0c1(io).await?;
+
+        if ctx.c0c1[0] != RTMP_VERSION {
+            return Err(HandshakeError::InvalidVersion(ctx.c0c1[0]));
+        }
+
+        // Zero version means the client only support simple handshake
+        let c1 = &ctx.c0c1[1..RTMP_HANDSHAKE_SIZE + 1];
+        if c1[4..8] == [0, 0, 0, 0] {
+            trace!("Zero version of c1, try simple");
+            return Err(HandshakeError::TrySimpleHandshake);
+        }
+
+        let (schema, c1_digest) =
+            match validate_c1s1(c1, &GENUINE_FP_KEY[..GENUINE_FP_KEY_TEXT_LEN]) {
+                Some((schema, offset)) => {
+                    trace!("Validate c1 ok, {:?} digest offset {}", schema, offset);
+                    (schema, c1[offset..offset + DIGEST_LEN].to_vec())
+                }
+                None => {
+                    trace!("Validate c1 failed, try simple");
+                    return Err(HandshakeError::TrySimpleHandshake);
+                }
+            };
+
+        create_s0s1s2(ctx, schema, &c1_digest);
+
+        io.write_all(&ctx.s0s1s2).await?;
+        io.flush().await?;
+
+        trace!("Send s0s1s2 len {}", ctx.s0s1s2.len());
+
+        // Flash player does not care about the c2, neither do we
+        ctx.read_c2(io).await?;
+
+        trace!("Read c2 len {}", ctx.c2.len());
+
+        debug!("Complex handshake completed");
+
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// The absolute offset of the digest in c1s1
+fn digest_offset(c1s1: &[u8], schema: Schema) -> usize {
+    let start = schema.digest_block_start();
+    let sum: usize = c1s1[start..start + 4].iter().map(|b| *b as usize).sum();
+    start + 4 + sum % DIGEST_OFFSET_MOD
+}
+
+// Sign the c1s1 except the 32 bytes digest
+fn c1s1_digest(c1s1: &[u8], offset: usize, key: &[u8]) -> [u8; DIGEST_LEN] {
+    hmac_sha256(key, &[&c1s1[..offset], &c1s1[offset + DIGEST_LEN..]])
+}
+
+fn validate_c1s1(c1s1: &[u8], key: &[u8]) -> Option<(Schema, usize)> {
+    for schema in [Schema::Schema0, Schema::Schema1] {
+        let offset = digest_offset(c1s1, schema);
+        if c1s1_digest(c1s1, offset, key) == c1s1[offset..offset + DIGEST_LEN] {
+            return Some((schema, offset));
+        }
+    }
+    None
+}
+
+fn put_c1s1(buf: &mut BytesMut, version: &[u8; 4], schema: Schema, key: &[u8]) {
+    let start = buf.len();
+    buf.put_u32(utils::current_time());
+    buf.put_slice(version);
+    let mut rng = rand::thread_rng();
+    for _ in 0..(RTMP_HANDSHAKE_SIZE - 8) {
+        buf.put_u8(rng.gen());
+    }
+    let c1s1 = &mut buf[start..start + RTMP_HANDSHAKE_SIZE];
+    let offset = digest_offset(c1s1, schema);
+    let digest = c1s1_digest(c1s1, offset, key);
+    c1s1[offset..offset + DIGEST_LEN].copy_from_slice(&digest);
+}
+
+// c2s2: random(1504) digest(32)
+fn put_c2s2(buf: &mut BytesMut, key: &[u8], peer_digest: &[u8]) {
+    let start = buf.len();
+    let mut rng = rand::thread_rng();
+    for _ in 0..(RTMP_HANDSHAKE_SIZE - DIGEST_LEN) {
+        buf.put_u8(rng.gen());
+    }
+    let temp_key = hmac_sha256(key, &[peer_digest]);
+    let digest = hmac_sha256(&temp_key, &[&buf[start..]]);
+    buf.put_slice(&digest);
+}
+
+fn create_c0c1(ctx: &mut Context, schema: Schema) {
+    if ctx.c0c1.is_empty() {
+        ctx.c0c1.reserve(1 + RTMP_HANDSHAKE_SIZE);
+        ctx.c0c1.put_u8(RTMP_VERSION);
+        put_c1s1(
+            &mut ctx.c0c1,
+            &CLIENT_VERSION,
+            schema,
+            &GENUINE_FP_KEY[..GENUINE_FP_KEY_TEXT_LEN],
+        );
+    }
+}
+
+fn create_s0s1s2(ctx: &mut Context, schema: Schema, c1_digest: &[u8]) {
+    if ctx.s0s1s2.is_empty() {
+        ctx.s0s1s2.reserve(1 + RTMP_HANDSHAKE_SIZE * 2);
+        ctx.s0s1s2.put_u8(RTMP_VERSION);
+        put_c1s1(
+            &mut ctx.s0s1s2,
+            &SERVER_VERSION,
+            schema,
+            &GENUINE_FMS_KEY[..GENUINE_FMS_KEY_TEXT_LEN],
+        );
+        put_c2s2(&mut ctx.s0s1s2, &GENUINE_FMS_KEY, c1_digest);
+    }
+}
+
+fn create_c2s2(buf: &mut BytesMut, key: &[u8], peer_digest: &[u8]) {
+    if buf.is_empty() {
+        buf.reserve(RTMP_HANDSHAKE_SIZE);
+        put_c2s2(buf, key, peer_digest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_c1_schemas() {
+        for schema in [Schema::Schema0, Schema::Schema1] {
+            let mut ctx = Context::new();
+            create_c0c1(&mut ctx, schema);
+            let c1 = &ctx.c0c1[1..];
+            let (found, offset) =
+                validate_c1s1(c1, &GENUINE_FP_KEY[..GENUINE_FP_KEY_TEXT_LEN]).unwrap();
+            assert_eq!(found, schema);
+            assert_eq!(offset, digest_offset(c1, schema));
+            // Signed with the player key, must not pass as a server s1
+            assert!(validate_c1s1(c1, &GENUINE_FMS_KEY[..GENUINE_FMS_KEY_TEXT_LEN]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_s1s2() {
+        let mut client = Context::new();
+        create_c0c1(&mut client, Schema::Schema0);
+        let c1 = &client.c0c1[1..];
+        let offset = digest_offset(c1, Schema::Schema0);
+        let c1_digest = c1[offset..offset + DIGEST_LEN].to_vec();
+
+        let mut server = Context::new();
+        create_s0s1s2(&mut server, Schema::Schema0, &c1_digest);
+        assert_eq!(server.s0s1s2.len(), 1 + RTMP_HANDSHAKE_SIZE * 2);
+
+        let s1 = &server.s0s1s2[1..RTMP_HANDSHAKE_SIZE + 1];
+        let (schema, _) = validate_c1s1(s1, &GENUINE_FMS_KEY[..GENUINE_FMS_KEY_TEXT_LEN]).unwrap();
+        assert_eq!(schema, Schema::Schema0);
+
+        // s2 is signed by the key derived from c1 digest
+        let s2 = &server.s0s1s2[RTMP_HANDSHAKE_SIZE + 1..];
+        let temp_key = hmac_sha256(&GENUINE_FMS_KEY, &[&c1_digest]);
+        let digest = hmac_sha256(&temp_key, &[&s2[..RTMP_HANDSHAKE_SIZE - DIGEST_LEN]]);
+        assert_eq!(digest, s2[RTMP_HANDSHAKE_SIZE - DIGEST_LEN..]);
+    }
 
-        Err(HandshakeError::TrySimpleHandshake)
+    #[test]
+    fn test_simple_c1() {
+        let mut ctx = Context::new();
+        ctx.create_c0c1().unwrap();
+        assert!(
+            validate_c1s1(&ctx.c0c1[1..], &GENUINE_FP_KEY[..GENUINE_FP_KEY_TEXT_LEN]).is_none()
+        );
     }
 }
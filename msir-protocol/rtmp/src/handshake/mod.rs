@@ -41,6 +41,7 @@ impl Server {
 
 pub struct Client {
     simple: simple_hs::SimpleHandshake,
+    complex: complex_hs::ComplexHandshake,
     ctx: context::Context,
 }
 
@@ -48,10 +49,48 @@ impl Client {
     pub fn new() -> Self {
         Self {
             simple: simple_hs::SimpleHandshake {},
+            complex: complex_hs::ComplexHandshake {},
             ctx: context::Context::new(),
         }
     }
     pub async fn handshake(&mut self, io: &mut Transport) -> Result<(), HandshakeError> {
-        self.simple.handshake_with_server(&mut self.ctx, io).await
+        match self.complex.handshake_with_server(&mut self.ctx, io).await {
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                if let HandshakeError::TrySimpleHandshake = err {
+                    self.simple.handshake_with_server(&mut self.ctx, io).await?;
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_complex_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (inbound, _) = listener.accept().await.unwrap();
+            let mut io = Transport::new(inbound);
+            let mut hs = Server::new();
+            hs.handshake(&mut io).await.unwrap();
+            hs.ctx.s0s1s2[5..9].to_vec()
+        });
+
+        let mut io = Transport::new(TcpStream::connect(addr).await.unwrap());
+        let mut hc = Client::new();
+        hc.handshake(&mut io).await.unwrap();
+
+        // Non-zero s1 version means the server responsed complex s1
+        assert_ne!(server.await.unwrap(), vec![0, 0, 0, 0]);
+        assert_eq!(hc.ctx.c2.len(), RTMP_HANDSHAKE_SIZE);
     }
 }
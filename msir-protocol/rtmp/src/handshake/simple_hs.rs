@@ -10,10 +10,13 @@ impl SimpleHandshake {
         ctx: &mut Context,
         io: &mut Transport,
     ) -> Result<(), HandshakeError> {
-        ctx.create_c0c1()?;
+        // c0c1 had been sent if fallback from complex handshake
+        if ctx.s0s1s2.is_empty() {
+            ctx.create_c0c1()?;
 
-        io.write_all(&ctx.c0c1[0..]).await?;
-        io.flush().await?;
+            io.write_all(&ctx.c0c1[0..]).await?;
+            io.flush().await?;
+        }
 
         ctx.read_s0s1s2(io).await?;
 
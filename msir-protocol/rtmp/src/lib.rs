@@ -1,5 +1,6 @@
 pub mod chunk;
 pub mod codec;
+pub mod codec_info;
 pub mod connection;
 pub mod error;
 pub mod handshake;
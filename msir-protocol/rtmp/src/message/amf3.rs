@@ -0,0 +1,683 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use rml_amf0::Amf0Value;
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+use super::error::Amf3Error;
+
+// AMF3 markers
+const UNDEFINED_MARKER: u8 = 0x00;
+const NULL_MARKER: u8 = 0x01;
+const FALSE_MARKER: u8 = 0x02;
+const TRUE_MARKER: u8 = 0x03;
+const INTEGER_MARKER: u8 = 0x04;
+const DOUBLE_MARKER: u8 = 0x05;
+const STRING_MARKER: u8 = 0x06;
+const XML_DOC_MARKER: u8 = 0x07;
+const DATE_MARKER: u8 = 0x08;
+const ARRAY_MARKER: u8 = 0x09;
+const OBJECT_MARKER: u8 = 0x0a;
+const XML_MARKER: u8 = 0x0b;
+const BYTE_ARRAY_MARKER: u8 = 0x0c;
+
+// AMF0 markers in the body of AMF3 command or data
+const AMF0_NUMBER_MARKER: u8 = 0x00;
+const AMF0_BOOLEAN_MARKER: u8 = 0x01;
+const AMF0_STRING_MARKER: u8 = 0x02;
+const AMF0_OBJECT_MARKER: u8 = 0x03;
+const AMF0_NULL_MARKER: u8 = 0x05;
+const AMF0_UNDEFINED_MARKER: u8 = 0x06;
+const AMF0_ECMA_ARRAY_MARKER: u8 = 0x08;
+const AMF0_OBJECT_END_MARKER: u8 = 0x09;
+const AMF0_STRICT_ARRAY_MARKER: u8 = 0x0a;
+const AMF0_DATE_MARKER: u8 = 0x0b;
+const AMF0_LONG_STRING_MARKER: u8 = 0x0c;
+const AMF0_XML_DOC_MARKER: u8 = 0x0f;
+const AMF0_TYPED_OBJECT_MARKER: u8 = 0x10;
+// Switch to AMF3 for the next value
+const AMF0_AVMPLUS_MARKER: u8 = 0x11;
+
+const INTEGER_MAX: i32 = 0x0fff_ffff;
+const INTEGER_MIN: i32 = -0x1000_0000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amf3Value {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32),
+    Double(f64),
+    String(String),
+    Xml(String),
+    // Milliseconds since epoch
+    Date(f64),
+    Array {
+        assoc: Vec<(String, Amf3Value)>,
+        dense: Vec<Amf3Value>,
+    },
+    Object {
+        class_name: String,
+        properties: Vec<(String, Amf3Value)>,
+    },
+    ByteArray(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Traits {
+    class_name: String,
+    dynamic: bool,
+    sealed: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Decoder {
+    strings: Vec<String>,
+    objects: Vec<Amf3Value>,
+    traits: Vec<Traits>,
+}
+
+impl Decoder {
+    pub fn decode<R: Read>(&mut self, r: &mut R) -> Result<Amf3Value, Amf3Error> {
+        let marker = r.read_u8()?;
+        Ok(match marker {
+            UNDEFINED_MARKER => Amf3Value::Undefined,
+            NULL_MARKER => Amf3Value::Null,
+            FALSE_MARKER => Amf3Value::Boolean(false),
+            TRUE_MARKER => Amf3Value::Boolean(true),
+            INTEGER_MARKER => Amf3Value::Integer(read_i29(r)?),
+            DOUBLE_MARKER => Amf3Value::Double(r.read_f64::<BigEndian>()?),
+            STRING_MARKER => Amf3Value::String(self.read_string(r)?),
+            XML_DOC_MARKER | XML_MARKER => {
+                let len = match self.read_reference(r)? {
+                    Ok(value) => return Ok(value),
+                    Err(len) => len,
+                };
+                let value = Amf3Value::Xml(read_utf8(r, len)?);
+                self.objects.push(value.clone());
+                value
+            }
+            DATE_MARKER => {
+                if let Ok(value) = self.read_reference(r)? {
+                    return Ok(value);
+                }
+                let value = Amf3Value::Date(r.read_f64::<BigEndian>()?);
+                self.objects.push(value.clone());
+                value
+            }
+            BYTE_ARRAY_MARKER => {
+                let len = match self.read_reference(r)? {
+                    Ok(value) => return Ok(value),
+                    Err(len) => len,
+                };
+                let value = Amf3Value::ByteArray(read_bytes(r, len)?);
+                self.objects.push(value.clone());
+                value
+            }
+            ARRAY_MARKER => {
+                let count = match self.read_reference(r)? {
+                    Ok(value) => return Ok(value),
+                    Err(count) => count,
+                };
+                // Reserve the reference index before the members
+                let index = self.objects.len();
+                self.objects.push(Amf3Value::Null);
+                let mut assoc = Vec::new();
+                loop {
+                    let key = self.read_string(r)?;
+                    if key.is_empty() {
+                        break;
+                    }
+                    assoc.push((key, self.decode(r)?));
+                }
+                let mut dense = Vec::with_capacity(count.min(1024));
+                for _ in 0..count {
+                    dense.push(self.decode(r)?);
+                }
+                let value = Amf3Value::Array { assoc, dense };
+                self.objects[index] = value.clone();
+                value
+            }
+            OBJECT_MARKER => self.read_object(r)?,
+            _ => return Err(Amf3Error::UnknownMarker(marker)),
+        })
+    }
+
+    // Ok for the referenced value, or Err for the inline length
+    fn read_reference<R: Read>(
+        &mut self,
+        r: &mut R,
+    ) -> Result<Result<Amf3Value, usize>, Amf3Error> {
+        let u = read_u29(r)? as usize;
+        if u & 0x01 == 0 {
+            return match self.objects.get(u >> 1) {
+                Some(value) => Ok(Ok(value.clone())),
+                None => Err(Amf3Error::BadReference(u >> 1)),
+            };
+        }
+        Ok(Err(u >> 1))
+    }
+
+    fn read_string<R: Read>(&mut self, r: &mut R) -> Result<String, Amf3Error> {
+        let u = read_u29(r)? as usize;
+        if u & 0x01 == 0 {
+            return match self.strings.get(u >> 1) {
+                Some(s) => Ok(s.clone()),
+                None => Err(Amf3Error::BadReference(u >> 1)),
+            };
+        }
+        let s = read_utf8(r, u >> 1)?;
+        // Empty string is never sent by reference
+        if !s.is_empty() {
+            self.strings.push(s.clone());
+        }
+        Ok(s)
+    }
+
+    fn read_object<R: Read>(&mut self, r: &mut R) -> Result<Amf3Value, Amf3Error> {
+        let u = read_u29(r)? as usize;
+        if u & 0x01 == 0 {
+            return match self.objects.get(u >> 1) {
+                Some(value) => Ok(value.clone()),
+                None => Err(Amf3Error::BadReference(u >> 1)),
+            };
+        }
+        let index = self.objects.len();
+        self.objects.push(Amf3Value::Null);
+
+        let traits = if u & 0x02 == 0 {
+            match self.traits.get(u >> 2) {
+                Some(traits) => traits.clone(),
+                None => return Err(Amf3Error::BadReference(u >> 2)),
+            }
+        } else if u & 0x04 != 0 {
+            return Err(Amf3Error::Unsupported("externalizable object".to_string()));
+        } else {
+            let class_name = self.read_string(r)?;
+            let mut sealed = Vec::with_capacity((u >> 4).min(1024));
+            for _ in 0..(u >> 4) {
+                sealed.push(self.read_string(r)?);
+            }
+            let traits = Traits {
+                class_name,
+                dynamic: u & 0x08 != 0,
+                sealed,
+            };
+            self.traits.push(traits.clone());
+            traits
+        };
+
+        let mut properties = Vec::with_capacity(traits.sealed.len());
+        for key in traits.sealed {
+            properties.push((key, self.decode(r)?));
+        }
+        if traits.dynamic {
+            loop {
+                let key = self.read_string(r)?;
+                if key.is_empty() {
+                    break;
+                }
+                properties.push((key, self.decode(r)?));
+            }
+        }
+        let value = Amf3Value::Object {
+            class_name: traits.class_name,
+            properties,
+        };
+        self.objects[index] = value.clone();
+        Ok(value)
+    }
+}
+
+// Objects and traits are always sent inline, only strings are sent by reference
+#[derive(Debug, Default)]
+pub struct Encoder {
+    strings: HashMap<String, usize>,
+}
+
+impl Encoder {
+    pub fn encode(&mut self, w: &mut Vec<u8>, value: &Amf3Value) -> Result<(), Amf3Error> {
+        match value {
+            Amf3Value::Undefined => w.push(UNDEFINED_MARKER),
+            Amf3Value::Null => w.push(NULL_MARKER),
+            Amf3Value::Boolean(false) => w.push(FALSE_MARKER),
+            Amf3Value::Boolean(true) => w.push(TRUE_MARKER),
+            Amf3Value::Integer(v) if (INTEGER_MIN..=INTEGER_MAX).contains(v) => {
+                w.push(INTEGER_MARKER);
+                write_u29(w, *v as u32 & 0x1fff_ffff);
+            }
+            Amf3Value::Integer(v) => {
+                w.push(DOUBLE_MARKER);
+                w.extend_from_slice(&(*v as f64).to_be_bytes());
+            }
+            Amf3Value::Double(v) => {
+                w.push(DOUBLE_MARKER);
+                w.extend_from_slice(&v.to_be_bytes());
+            }
+            Amf3Value::String(s) => {
+                w.push(STRING_MARKER);
+                self.write_string(w, s)?;
+            }
+            Amf3Value::Xml(s) => {
+                w.push(XML_MARKER);
+                write_u29(w, inline_len(s.len())?);
+                w.extend_from_slice(s.as_bytes());
+            }
+            Amf3Value::Date(v) => {
+                w.push(DATE_MARKER);
+                write_u29(w, 0x01);
+                w.extend_from_slice(&v.to_be_bytes());
+            }
+            Amf3Value::Array { assoc, dense } => {
+                w.push(ARRAY_MARKER);
+                write_u29(w, inline_len(dense.len())?);
+                for (key, value) in assoc {
+                    self.write_string(w, key)?;
+                    self.encode(w, value)?;
+                }
+                self.write_string(w, "")?;
+                for value in dense {
+                    self.encode(w, value)?;
+                }
+            }
+            Amf3Value::Object {
+                class_name,
+                properties,
+            } => {
+                w.push(OBJECT_MARKER);
+                // Inline object with inline traits, dynamic, no sealed member
+                write_u29(w, 0x0b);
+                self.write_string(w, class_name)?;
+                for (key, value) in properties {
+                    self.write_string(w, key)?;
+                    self.encode(w, value)?;
+                }
+                self.write_string(w, "")?;
+            }
+            Amf3Value::ByteArray(data) => {
+                w.push(BYTE_ARRAY_MARKER);
+                write_u29(w, inline_len(data.len())?);
+                w.extend_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_string(&mut self, w: &mut Vec<u8>, s: &str) -> Result<(), Amf3Error> {
+        if s.is_empty() {
+            write_u29(w, 0x01);
+            return Ok(());
+        }
+        if let Some(index) = self.strings.get(s) {
+            write_u29(w, (*index as u32) << 1);
+            return Ok(());
+        }
+        write_u29(w, inline_len(s.len())?);
+        w.extend_from_slice(s.as_bytes());
+        self.strings.insert(s.to_string(), self.strings.len());
+        Ok(())
+    }
+}
+
+// Decode the body of AMF3 command or data, which is AMF0 values with a leading 0x00,
+// and the AMF3 values are switched by the avmplus marker
+pub fn decode_body(data: &[u8]) -> Result<Vec<Amf0Value>, Amf3Error> {
+    let mut cursor = Cursor::new(data);
+    if data.first() == Some(&0x00) {
+        cursor.set_position(1);
+    }
+    let mut values = Vec::new();
+    while (cursor.position() as usize) < data.len() {
+        let marker = cursor.read_u8()?;
+        values.push(read_amf0(&mut cursor, marker)?);
+    }
+    Ok(values)
+}
+
+// Encode the body of AMF3 command, the objects and arrays are encoded in AMF3
+pub fn encode_body(values: &[Amf0Value]) -> Result<Vec<u8>, Amf3Error> {
+    let mut w = vec![0x00];
+    for value in values {
+        match value {
+            Amf0Value::Number(v) => {
+                w.push(AMF0_NUMBER_MARKER);
+                w.extend_from_slice(&v.to_be_bytes());
+            }
+            Amf0Value::Boolean(v) => {
+                w.push(AMF0_BOOLEAN_MARKER);
+                w.push(*v as u8);
+            }
+            Amf0Value::Utf8String(s) if s.len() <= u16::MAX as usize => {
+                w.push(AMF0_STRING_MARKER);
+                w.extend_from_slice(&(s.len() as u16).to_be_bytes());
+                w.extend_from_slice(s.as_bytes());
+            }
+            Amf0Value::Utf8String(s) => {
+                w.push(AMF0_LONG_STRING_MARKER);
+                w.extend_from_slice(&(s.len() as u32).to_be_bytes());
+                w.extend_from_slice(s.as_bytes());
+            }
+            Amf0Value::Null => w.push(AMF0_NULL_MARKER),
+            Amf0Value::Undefined => w.push(AMF0_UNDEFINED_MARKER),
+            Amf0Value::Object(_) | Amf0Value::StrictArray(_) => {
+                w.push(AMF0_AVMPLUS_MARKER);
+                Encoder::default().encode(&mut w, &Amf3Value::from(value))?;
+            }
+        }
+    }
+    Ok(w)
+}
+
+fn read_amf0<R: Read>(r: &mut R, marker: u8) -> Result<Amf0Value, Amf3Error> {
+    Ok(match marker {
+        AMF0_NUMBER_MARKER => Amf0Value::Number(r.read_f64::<BigEndian>()?),
+        AMF0_BOOLEAN_MARKER => Amf0Value::Boolean(r.read_u8()? != 0),
+        AMF0_STRING_MARKER => {
+            let len = r.read_u16::<BigEndian>()? as usize;
+            Amf0Value::Utf8String(read_utf8(r, len)?)
+        }
+        AMF0_LONG_STRING_MARKER | AMF0_XML_DOC_MARKER => {
+            let len = r.read_u32::<BigEndian>()? as usize;
+            Amf0Value::Utf8String(read_utf8(r, len)?)
+        }
+        AMF0_OBJECT_MARKER => Amf0Value::Object(read_amf0_properties(r)?),
+        AMF0_TYPED_OBJECT_MARKER => {
+            let len = r.read_u16::<BigEndian>()? as usize;
+            let _class_name = read_utf8(r, len)?;
+            Amf0Value::Object(read_amf0_properties(r)?)
+        }
+        AMF0_ECMA_ARRAY_MARKER => {
+            let _count = r.read_u32::<BigEndian>()?;
+            Amf0Value::Object(read_amf0_properties(r)?)
+        }
+        AMF0_STRICT_ARRAY_MARKER => {
+            let count = r.read_u32::<BigEndian>()?;
+            let mut values = Vec::with_capacity((count as usize).min(1024));
+            for _ in 0..count {
+                let marker = r.read_u8()?;
+                values.push(read_amf0(r, marker)?);
+            }
+            Amf0Value::StrictArray(values)
+        }
+        AMF0_DATE_MARKER => {
+            let date = r.read_f64::<BigEndian>()?;
+            let _timezone = r.read_i16::<BigEndian>()?;
+            Amf0Value::Number(date)
+        }
+        AMF0_NULL_MARKER => Amf0Value::Null,
+        AMF0_UNDEFINED_MARKER => Amf0Value::Undefined,
+        // Every switch starts a new AMF3 context
+        AMF0_AVMPLUS_MARKER => Decoder::default().decode(r)?.into(),
+        _ => return Err(Amf3Error::UnknownMarker(marker)),
+    })
+}
+
+fn read_amf0_properties<R: Read>(r: &mut R) -> Result<HashMap<String, Amf0Value>, Amf3Error> {
+    let mut properties = HashMap::new();
+    loop {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        let key = read_utf8(r, len)?;
+        let marker = r.read_u8()?;
+        if key.is_empty() && marker == AMF0_OBJECT_END_MARKER {
+            break;
+        }
+        properties.insert(key, read_amf0(r, marker)?);
+    }
+    Ok(properties)
+}
+
+fn read_utf8<R: Read>(r: &mut R, len: usize) -> Result<String, Amf3Error> {
+    Ok(String::from_utf8(read_bytes(r, len)?)?)
+}
+
+// The length is from the peer, so never allocate more than the bytes left
+fn read_bytes<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>, Amf3Error> {
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(Amf3Error::TooLong(len));
+    }
+    Ok(data)
+}
+
+fn read_u29<R: Read>(r: &mut R) -> Result<u32, Amf3Error> {
+    let mut value = 0_u32;
+    for _ in 0..3 {
+        let b = r.read_u8()? as u32;
+        value = (value << 7) | (b & 0x7f);
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    // The 4th byte uses all the 8 bits
+    Ok((value << 8) | r.read_u8()? as u32)
+}
+
+fn read_i29<R: Read>(r: &mut R) -> Result<i32, Amf3Error> {
+    let value = read_u29(r)?;
+    match value & 0x1000_0000 {
+        0 => Ok(value as i32),
+        _ => Ok(value as i32 - 0x2000_0000),
+    }
+}
+
+fn write_u29(w: &mut Vec<u8>, value: u32) {
+    if value < 0x80 {
+        w.push(value as u8);
+    } else if value < 0x4000 {
+        w.push((value >> 7 | 0x80) as u8);
+        w.push((value & 0x7f) as u8);
+    } else if value < 0x20_0000 {
+        w.push((value >> 14 | 0x80) as u8);
+        w.push((value >> 7 & 0x7f | 0x80) as u8);
+        w.push((value & 0x7f) as u8);
+    } else {
+        w.push((value >> 22 | 0x80) as u8);
+        w.push((value >> 15 & 0x7f | 0x80) as u8);
+        w.push((value >> 8 & 0x7f | 0x80) as u8);
+        w.push((value & 0xff) as u8);
+    }
+}
+
+// The U29 of inline value, length << 1 | 1
+fn inline_len(len: usize) -> Result<u32, Amf3Error> {
+    if len > 0x0fff_ffff {
+        return Err(Amf3Error::TooLong(len));
+    }
+    Ok((len as u32) << 1 | 0x01)
+}
+
+impl From<Amf3Value> for Amf0Value {
+    fn from(value: Amf3Value) -> Self {
+        match value {
+            Amf3Value::Undefined => Amf0Value::Undefined,
+            Amf3Value::Null => Amf0Value::Null,
+            Amf3Value::Boolean(v) => Amf0Value::Boolean(v),
+            Amf3Value::Integer(v) => Amf0Value::Number(v as f64),
+            Amf3Value::Double(v) | Amf3Value::Date(v) => Amf0Value::Number(v),
+            Amf3Value::String(s) | Amf3Value::Xml(s) => Amf0Value::Utf8String(s),
+            Amf3Value::Array { assoc, dense } => {
+                if assoc.is_empty() {
+                    return Amf0Value::StrictArray(dense.into_iter().map(Into::into).collect());
+                }
+                let mut properties: HashMap<String, Amf0Value> =
+                    assoc.into_iter().map(|(k, v)| (k, v.into())).collect();
+                for (i, v) in dense.into_iter().enumerate() {
+                    properties.insert(i.to_string(), v.into());
+                }
+                Amf0Value::Object(properties)
+            }
+            Amf3Value::Object { properties, .. } => {
+                Amf0Value::Object(properties.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+            Amf3Value::ByteArray(data) => Amf0Value::StrictArray(
+                data.into_iter()
+                    .map(|b| Amf0Value::Number(b as f64))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&Amf0Value> for Amf3Value {
+    fn from(value: &Amf0Value) -> Self {
+        match value {
+            Amf0Value::Number(v) => {
+                if v.fract() == 0.0 && *v >= INTEGER_MIN as f64 && *v <= INTEGER_MAX as f64 {
+                    Amf3Value::Integer(*v as i32)
+                } else {
+                    Amf3Value::Double(*v)
+                }
+            }
+            Amf0Value::Boolean(v) => Amf3Value::Boolean(*v),
+            Amf0Value::Utf8String(s) => Amf3Value::String(s.clone()),
+            Amf0Value::Object(properties) => {
+                // Sorted for the stable output
+                let mut properties: Vec<(String, Amf3Value)> = properties
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.into()))
+                    .collect();
+                properties.sort_by(|a, b| a.0.cmp(&b.0));
+                Amf3Value::Object {
+                    class_name: String::new(),
+                    properties,
+                }
+            }
+            Amf0Value::StrictArray(values) => Amf3Value::Array {
+                assoc: Vec::new(),
+                dense: values.iter().map(Into::into).collect(),
+            },
+            Amf0Value::Null => Amf3Value::Null,
+            Amf0Value::Undefined => Amf3Value::Undefined,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(value: Amf3Value) -> Amf3Value {
+        let mut data = Vec::new();
+        Encoder::default().encode(&mut data, &value).unwrap();
+        Decoder::default().decode(&mut Cursor::new(data)).unwrap()
+    }
+
+    #[test]
+    fn test_integer() {
+        for v in [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            INTEGER_MAX,
+            -1,
+            INTEGER_MIN,
+        ] {
+            assert_eq!(roundtrip(Amf3Value::Integer(v)), Amf3Value::Integer(v));
+        }
+        // Out of range is encoded as double
+        let mut data = Vec::new();
+        Encoder::default()
+            .encode(&mut data, &Amf3Value::Integer(INTEGER_MAX + 1))
+            .unwrap();
+        assert_eq!(data[0], DOUBLE_MARKER);
+
+        let mut data = Vec::new();
+        Encoder::default()
+            .encode(&mut data, &Amf3Value::Integer(-1))
+            .unwrap();
+        assert_eq!(data, [INTEGER_MARKER, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_object_with_references() {
+        let value = Amf3Value::Array {
+            assoc: vec![("app".to_string(), Amf3Value::String("live".to_string()))],
+            dense: vec![
+                Amf3Value::String("live".to_string()),
+                Amf3Value::Object {
+                    class_name: String::new(),
+                    properties: vec![
+                        ("live".to_string(), Amf3Value::Double(1.5)),
+                        ("date".to_string(), Amf3Value::Date(1686900000000.0)),
+                        ("bytes".to_string(), Amf3Value::ByteArray(vec![1, 2, 3])),
+                    ],
+                },
+            ],
+        };
+        let mut data = Vec::new();
+        Encoder::default().encode(&mut data, &value).unwrap();
+        // "live" is written once and referenced twice
+        assert_eq!(data.windows(4).filter(|w| w == b"live").count(), 1);
+        assert_eq!(
+            Decoder::default().decode(&mut Cursor::new(data)).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn test_sealed_traits_reference() {
+        // Two typed objects with sealed member "a", the second one references the traits and strings
+        let data = [
+            ARRAY_MARKER,
+            0x05,
+            0x01, // dense array with 2 items
+            OBJECT_MARKER,
+            0x13,
+            0x03,
+            b'T',
+            0x03,
+            b'a',
+            INTEGER_MARKER,
+            0x01, // T{a:1}
+            OBJECT_MARKER,
+            0x01,
+            INTEGER_MARKER,
+            0x02, // traits reference 0, T{a:2}
+        ];
+        let value = Decoder::default().decode(&mut Cursor::new(data)).unwrap();
+        let object = |v| Amf3Value::Object {
+            class_name: "T".to_string(),
+            properties: vec![("a".to_string(), Amf3Value::Integer(v))],
+        };
+        assert_eq!(
+            value,
+            Amf3Value::Array {
+                assoc: vec![],
+                dense: vec![object(1), object(2)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_body() {
+        let mut properties = HashMap::new();
+        properties.insert("objectEncoding".to_string(), Amf0Value::Number(3.0));
+        properties.insert("app".to_string(), Amf0Value::Utf8String("live".to_string()));
+        let values = vec![
+            Amf0Value::Utf8String("connect".to_string()),
+            Amf0Value::Number(1.0),
+            Amf0Value::Object(properties),
+            Amf0Value::Null,
+        ];
+        let data = encode_body(&values).unwrap();
+        assert_eq!(data[0], 0x00);
+        assert_eq!(decode_body(&data).unwrap(), values);
+    }
+
+    #[test]
+    fn test_length_overflow() {
+        // Length 0x0fffffff but only 2 bytes left
+        for marker in [BYTE_ARRAY_MARKER, STRING_MARKER] {
+            let data = [marker, 0xff, 0xff, 0xff, 0xff, 0x01, 0x02];
+            assert!(matches!(
+                Decoder::default().decode(&mut Cursor::new(data)),
+                Err(Amf3Error::TooLong(0x0fffffff))
+            ));
+        }
+    }
+}
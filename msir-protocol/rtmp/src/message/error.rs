@@ -2,7 +2,7 @@ use rml_amf0::{Amf0DeserializationError, Amf0SerializationError};
 use thiserror::Error;
 use url::ParseError;
 
-use std::io;
+use std::{io, string::FromUtf8Error};
 
 /// Error state when deserialization errors occur
 /// Enumeration that represents the various errors that may occur while trying to
@@ -21,6 +21,9 @@ pub enum MessageDecodeError {
     #[error("Can not decode message: {0}")]
     AmfDecodeFailed(#[from] Amf0DeserializationError),
 
+    #[error("Can not decode amf3 message: {0}")]
+    Amf3DecodeFailed(#[from] Amf3Error),
+
     /// Failed to read the values from the input buffer
     #[error("An IO error occurred while reading the input: {0}")]
     Io(#[from] io::Error),
@@ -36,11 +39,35 @@ pub enum MessageEncodeError {
     #[error("Can not encode message: {0}")]
     AmfDecodeFailed(#[from] Amf0SerializationError),
 
+    #[error("Can not encode amf3 message: {0}")]
+    Amf3EncodeFailed(#[from] Amf3Error),
+
     /// Failed to read the values from the input buffer
     #[error("An IO error occurred while reading the input: {0}")]
     Io(#[from] io::Error),
 }
 
+#[derive(Debug, Error)]
+pub enum Amf3Error {
+    #[error("Unknown marker: {0}")]
+    UnknownMarker(u8),
+
+    #[error("Invalid reference: {0}")]
+    BadReference(usize),
+
+    #[error("Unsupported {0}")]
+    Unsupported(String),
+
+    #[error("Length {0} is too long")]
+    TooLong(usize),
+
+    #[error("Invalid utf8 string: {0}")]
+    InvalidString(#[from] FromUtf8Error),
+
+    #[error("An IO error occurred: {0}")]
+    Io(#[from] io::Error),
+}
+
 #[derive(Debug, Error)]
 pub enum ReuquestError {
     #[error("Invalid tcUrl {0}")]
@@ -11,6 +11,7 @@ use rml_amf0;
 use rml_amf0::Amf0Value;
 use std::{collections::HashMap, fmt, io::Cursor};
 
+pub mod amf3;
 pub mod error;
 pub mod request;
 pub mod types;
@@ -101,13 +102,40 @@ impl RtmpMessage {
             additional_arguments: vec![Amf0Value::Utf8String(stream_name)],
         };
     }
-    pub fn new_create_stream() -> Self {
-        return RtmpMessage::Amf0Command {
+    pub fn new_create_stream(transaction_id: f64) -> Self {
+        RtmpMessage::Amf0Command {
             command_name: COMMAND_CREATE_STREAM.to_string(),
-            transaction_id: 2.0,
+            transaction_id,
             command_object: Amf0Value::Null,
             additional_arguments: vec![],
-        };
+        }
+    }
+    pub fn new_release_stream(stream_name: String, transaction_id: f64) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_RELEASE_STREAM.to_string(),
+            transaction_id,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![Amf0Value::Utf8String(stream_name)],
+        }
+    }
+    pub fn new_fcpublish(stream_name: String, transaction_id: f64) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_FC_PUBLISH.to_string(),
+            transaction_id,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![Amf0Value::Utf8String(stream_name)],
+        }
+    }
+    pub fn new_publish_stream(stream_name: String, transaction_id: f64) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_PUBLISH.to_string(),
+            transaction_id,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![
+                Amf0Value::Utf8String(stream_name),
+                Amf0Value::Utf8String("live".to_string()),
+            ],
+        }
     }
     pub fn new_create_stream_res(transaction_id: f64) -> Self {
         return RtmpMessage::Amf0Command {
@@ -198,6 +226,52 @@ impl RtmpMessage {
             ])],
         };
     }
+    pub fn new_on_status_publish_bad_name(description: &str) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_ON_STATUS.to_string(),
+            transaction_id: 0.0,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![fast_create_amf0_obj(vec![
+                (
+                    STATUS_LEVEL,
+                    Amf0Value::Utf8String(STATUS_LEVEL_ERROR.to_string()),
+                ),
+                (
+                    STATUS_CODE,
+                    Amf0Value::Utf8String(STATUS_CODE_PUBLISH_BAD_NAME.to_string()),
+                ),
+                (
+                    STATUS_DESCRIPTION,
+                    Amf0Value::Utf8String(description.to_string()),
+                ),
+                (
+                    STATUS_CLIENT_ID,
+                    Amf0Value::Utf8String(RTMP_SIG_CLIENT_ID.to_string()),
+                ),
+            ])],
+        }
+    }
+    pub fn new_error_rejected(description: &str) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_ERROR.to_string(),
+            transaction_id: 0.0,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![fast_create_amf0_obj(vec![
+                (
+                    STATUS_LEVEL,
+                    Amf0Value::Utf8String(STATUS_LEVEL_ERROR.to_string()),
+                ),
+                (
+                    STATUS_CODE,
+                    Amf0Value::Utf8String(STATUS_CODE_CONNECT_REJECTED.to_string()),
+                ),
+                (
+                    STATUS_DESCRIPTION,
+                    Amf0Value::Utf8String(description.to_string()),
+                ),
+            ])],
+        }
+    }
     pub fn new_on_status_unpublish() -> Self {
         return RtmpMessage::Amf0Command {
             command_name: COMMAND_ON_STATUS.to_string(),
@@ -275,6 +349,32 @@ impl RtmpMessage {
             ])],
         };
     }
+    pub fn new_on_status_play_unpublish_notify(description: &str) -> Self {
+        RtmpMessage::Amf0Command {
+            command_name: COMMAND_ON_STATUS.to_string(),
+            transaction_id: 0.0,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![fast_create_amf0_obj(vec![
+                (
+                    STATUS_LEVEL,
+                    Amf0Value::Utf8String(STATUS_LEVEL_STATUS.to_string()),
+                ),
+                (
+                    STATUS_CODE,
+                    Amf0Value::Utf8String(STATUS_CODE_STREAM_UNPUBLISH_NOTIFY.to_string()),
+                ),
+                (
+                    STATUS_DESCRIPTION,
+                    Amf0Value::Utf8String(description.to_string()),
+                ),
+                (
+                    STATUS_CLIENT_ID,
+                    Amf0Value::Utf8String(RTMP_SIG_CLIENT_ID.to_string()),
+                ),
+            ])],
+        }
+    }
+
     pub fn new_on_status_pause() -> Self {
         return RtmpMessage::Amf0Command {
             command_name: COMMAND_ON_STATUS.to_string(),
@@ -352,6 +452,16 @@ impl RtmpMessage {
                 ("videoFunction", Amf0Value::Number(1.0)),
                 ("pageUrl", Amf0Value::Utf8String("".to_string())),
                 ("objectEncoding", Amf0Value::Number(0.0)),
+                // Enhanced RTMP
+                (
+                    "fourCcList",
+                    Amf0Value::StrictArray(
+                        codec::SUPPORTED_FOURCC
+                            .iter()
+                            .map(|f| Amf0Value::Utf8String(f.to_string()))
+                            .collect(),
+                    ),
+                ),
             ]),
             additional_arguments: vec![fast_create_amf0_obj(vec![
                 // FIXME: do not hardcode
@@ -363,18 +473,31 @@ impl RtmpMessage {
             ])],
         };
     }
-    pub fn new_connect_app_res(object_encoding: f64) -> Self {
+    // The fourcc_list is the negotiated codecs of Enhanced RTMP, omitted if empty
+    pub fn new_connect_app_res(object_encoding: f64, fourcc_list: &[String]) -> Self {
+        let mut command_object = vec![
+            (
+                "fmsVer",
+                Amf0Value::Utf8String(RTMP_SIG_FMS_VER.to_string()),
+            ),
+            ("capabilities", Amf0Value::Number(127.0)),
+            ("mode", Amf0Value::Number(1.0)),
+        ];
+        if !fourcc_list.is_empty() {
+            command_object.push((
+                "fourCcList",
+                Amf0Value::StrictArray(
+                    fourcc_list
+                        .iter()
+                        .map(|f| Amf0Value::Utf8String(f.clone()))
+                        .collect(),
+                ),
+            ));
+        }
         return RtmpMessage::Amf0Command {
             command_name: COMMAND_RESULT.to_string(),
             transaction_id: 1.0,
-            command_object: fast_create_amf0_obj(vec![
-                (
-                    "fmsVer",
-                    Amf0Value::Utf8String(RTMP_SIG_FMS_VER.to_string()),
-                ),
-                ("capabilities", Amf0Value::Number(127.0)),
-                ("mode", Amf0Value::Number(1.0)),
-            ]),
+            command_object: fast_create_amf0_obj(command_object),
             additional_arguments: vec![fast_create_amf0_obj(vec![
                 (
                     STATUS_LEVEL,
@@ -427,6 +550,22 @@ impl RtmpMessage {
         return false;
     }
 
+    // The code of onStatus or _error, e.g. NetStream.Publish.Start
+    pub fn status_code(&self) -> Option<&str> {
+        if let RtmpMessage::Amf0Command {
+            additional_arguments,
+            ..
+        } = self
+        {
+            if let Some(Amf0Value::Object(properties)) = additional_arguments.first() {
+                if let Some(Amf0Value::Utf8String(code)) = properties.get(STATUS_CODE) {
+                    return Some(code);
+                }
+            }
+        }
+        None
+    }
+
     pub fn len(&self) -> Option<usize> {
         match self {
             RtmpMessage::VideoData { payload, .. } => Some(payload.len()),
@@ -603,8 +742,10 @@ pub fn decode(payload: RtmpPayload) -> Result<RtmpMessage, MessageDecodeError> {
         msg_type::AGGREGATE => {}
         msg_type::AMF3_SHARED_OBJ | msg_type::AMF0_SHARED_OBJ => {}
         msg_type::AMF3_DATA | msg_type::AMF0_DATA => {
-            let mut cursor = Cursor::new(payload.raw_data);
-            let values = rml_amf0::deserialize(&mut cursor)?;
+            let values = match payload.message_type {
+                msg_type::AMF3_DATA => amf3::decode_body(&payload.raw_data)?,
+                _ => rml_amf0::deserialize(&mut Cursor::new(payload.raw_data))?,
+            };
 
             let cmd = match &values[0] {
                 Amf0Value::Utf8String(value) => value,
@@ -627,11 +768,10 @@ pub fn decode(payload: RtmpPayload) -> Result<RtmpMessage, MessageDecodeError> {
             }
         }
         msg_type::AMF3_CMD | msg_type::AMF0_CMD => {
-            let mut cursor = Cursor::new(payload.raw_data);
-            if payload.message_type == msg_type::AMF3_CMD {
-                cursor.advance(1);
-            }
-            let mut arguments = rml_amf0::deserialize(&mut cursor)?;
+            let mut arguments = match payload.message_type {
+                msg_type::AMF3_CMD => amf3::decode_body(&payload.raw_data)?,
+                _ => rml_amf0::deserialize(&mut Cursor::new(payload.raw_data))?,
+            };
 
             let command_name: String;
             let transaction_id: f64;
@@ -809,6 +949,69 @@ pub fn encode(
     }
 }
 
+// Encode the command in AMF3 for the client with objectEncoding 3, the others are the same as AMF0
+pub fn encode_amf3(
+    msg: RtmpMessage,
+    timestamp: u32,
+    csid: u32,
+) -> Result<RtmpPayload, MessageEncodeError> {
+    match msg {
+        RtmpMessage::Amf0Command {
+            command_name,
+            transaction_id,
+            command_object,
+            mut additional_arguments,
+        } => {
+            let mut values = vec![
+                Amf0Value::Utf8String(command_name),
+                Amf0Value::Number(transaction_id),
+                command_object,
+            ];
+            values.append(&mut additional_arguments);
+            Ok(RtmpPayload {
+                message_type: msg_type::AMF3_CMD,
+                csid,
+                timestamp,
+                raw_data: Bytes::from(amf3::encode_body(&values)?),
+            })
+        }
+        other => encode(other, timestamp, csid),
+    }
+}
+
+// Split the aggregate message into sub-messages, the timestamps are rebased on the aggregate one.
+// Each sub-message is a flv tag: type(1) size(3) timestamp(3) timestamp_ext(1) stream_id(3) data(size) prev_size(4)
+pub fn decode_aggregate(payload: RtmpPayload) -> Result<Vec<RtmpMessage>, MessageDecodeError> {
+    let mut data = payload.raw_data;
+    let mut base: Option<u32> = None;
+    let mut msgs = Vec::new();
+    while data.remaining() >= 11 {
+        let message_type = data.get_u8();
+        let size = data.get_uint(3) as usize;
+        let timestamp = data.get_uint(3) as u32 | (data.get_u8() as u32) << 24;
+        data.advance(3);
+        if data.remaining() < size {
+            return Err(MessageDecodeError::InvalidFormat("aggregate".to_string()));
+        }
+        let raw_data = data.split_to(size);
+        if data.remaining() >= 4 {
+            data.advance(4);
+        }
+
+        let base = *base.get_or_insert(timestamp);
+        if message_type == msg_type::AGGREGATE {
+            continue;
+        }
+        msgs.push(decode(RtmpPayload {
+            message_type,
+            csid: payload.csid,
+            timestamp: payload.timestamp.wrapping_add(timestamp.wrapping_sub(base)),
+            raw_data,
+        })?);
+    }
+    Ok(msgs)
+}
+
 fn fast_u32_encode(value: u32) -> Result<Bytes, MessageEncodeError> {
     let mut cursor = Cursor::new(Vec::new());
     cursor.write_u32::<BigEndian>(value)?;
@@ -828,7 +1031,44 @@ fn fast_create_amf0_obj(values: Vec<(&str, Amf0Value)>) -> Amf0Value {
 mod tests {
     // use amf::Amf0Value;
 
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn test_decode_aggregate() {
+        let mut raw = Vec::new();
+        for (message_type, timestamp, data) in [
+            (msg_type::VIDEO, 0x01000010_u32, &[0x17_u8, 0x01][..]),
+            (msg_type::AUDIO, 0x01000030_u32, &[0xaf_u8, 0x01, 0x02][..]),
+        ] {
+            raw.push(message_type);
+            raw.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+            raw.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+            raw.push((timestamp >> 24) as u8);
+            raw.extend_from_slice(&[0, 0, 0]);
+            raw.extend_from_slice(data);
+            raw.extend_from_slice(&(11 + data.len() as u32).to_be_bytes());
+        }
+        let msgs = decode_aggregate(RtmpPayload {
+            message_type: msg_type::AGGREGATE,
+            csid: 1,
+            timestamp: 1000,
+            raw_data: Bytes::from(raw),
+        })
+        .unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_key_frame());
+        assert_eq!(msgs[0].timestamp(), Some(1000));
+        assert_eq!(msgs[1].timestamp(), Some(1032));
+        match &msgs[1] {
+            RtmpMessage::AudioData {
+                stream_id, payload, ..
+            } => {
+                assert_eq!(*stream_id, 1);
+                assert_eq!(payload.as_ref(), &[0xaf, 0x01, 0x02]);
+            }
+            _ => panic!("expect audio"),
+        }
+    }
 
     // #[test]
     // fn test1() {
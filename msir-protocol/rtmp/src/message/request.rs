@@ -1,39 +1,83 @@
 use super::error::ReuquestError;
 use crate::connection::RtmpConnType;
-use url::Url;
+use serde_derive::Serialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+use url::{form_urlencoded, Host, Url};
+
+// Where the connection is from, to trace the abusive clients
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClientInfo {
+    // The upstream for the pull or forward client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<SocketAddr>,
+    // From the RTMP connect command object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flash_ver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swf_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tc_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub object_encoding: Option<f64>,
+    // From the HTTP request headers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+}
 
 #[derive(Debug)]
 pub struct Request {
-    // Remote IP
-    pub ip: Option<String>,
+    pub client: ClientInfo,
     // tcUrl: rtmp://a.com/live?key=1
     pub tc_url: Url,
     pub stream: Option<String>,
     pub conn_type: RtmpConnType,
     // From amf::play
     pub duration: u32,
+    // Query of the tc_url and the stream name, the latter wins, e.g. token or vhost
+    pub params: HashMap<String, String>,
+    // The resolved virtual host, None for the default one
+    pub vhost: Option<String>,
 }
 
 impl Request {
     pub fn parse_from(url: String) -> Result<Self, ReuquestError> {
-        // FIXME: whether to deal with srs-style url(with vhost)?
         let mut tc_url = Url::parse(&url)?;
 
         let app;
         let mut stream;
         let mut conn_type = RtmpConnType::Unknow;
+        let srs_params;
         {
             let app_stream: Vec<&str> = tc_url.path().splitn(3, '/').collect();
             if app_stream.len() < 2 || app_stream[1].is_empty() {
                 return Err(ReuquestError::NotfoundApp);
             }
-            app = String::from("/") + app_stream[1];
+            let (name, params) = split_srs_params(app_stream[1]);
+            app = String::from("/") + name;
+            srs_params = params;
             stream = None;
             if app_stream.len() > 2 && !app_stream[2].is_empty() {
                 let s = app_stream[2];
                 if s.len() >= 4 && &s[(s.len() - 4)..] == ".flv" {
                     stream = Some(s[0..(s.len() - 4)].to_string());
                     conn_type = RtmpConnType::FlvPlay;
+                } else if s.len() >= 5 && &s[(s.len() - 5)..] == ".m3u8" {
+                    stream = Some(s[0..(s.len() - 5)].to_string());
+                    conn_type = RtmpConnType::HlsPlay;
+                } else if s.len() >= 3 && &s[(s.len() - 3)..] == ".ts" {
+                    stream = Some(s[0..(s.len() - 3)].to_string());
+                    conn_type = RtmpConnType::TsPlay;
                 } else {
                     stream = Some(s.to_string());
                 }
@@ -41,13 +85,17 @@ impl Request {
         }
         tc_url.set_path(&app);
 
+        let mut params: HashMap<String, String> = srs_params.into_iter().collect();
+        params.extend(tc_url.query_pairs().into_owned());
         Ok(Request {
-            ip: None,
+            client: ClientInfo::default(),
             // object_encoding: rtmp_sig::RTMP_SIG_AMF0_VER,
             tc_url,
             stream,
             conn_type,
             duration: 0,
+            params,
+            vhost: None,
         })
     }
 
@@ -68,6 +116,56 @@ impl Request {
         );
     }
 
+    // The key in the stream manager, /{app}/{stream} or {vhost}/{app}/{stream}
+    pub fn stream_key(&self) -> String {
+        format!(
+            "{}{}",
+            self.vhost.as_deref().unwrap_or(""),
+            self.app_stream()
+        )
+    }
+
+    // The vhost asked by the client, by the vhost param or the domain of tc_url
+    pub fn vhost_hint(&self) -> Option<&str> {
+        if let Some(vhost) = self.param("vhost") {
+            return Some(vhost);
+        }
+        // The host of rtmp url is not parsed as IP
+        match self.tc_url.host() {
+            Some(Host::Domain(d)) if d.parse::<IpAddr>().is_err() => Some(d),
+            _ => None,
+        }
+    }
+
+    // Remote IP
+    pub fn ip(&self) -> Option<String> {
+        self.client.remote_addr.map(|a| a.ip().to_string())
+    }
+
+    // The query of the stream name, e.g. stream?token=xxx, is moved to the tc_url and params
+    pub fn set_stream(&mut self, stream: &str) {
+        let (name, query) = match stream.split_once('?') {
+            Some((name, query)) => (name, Some(query)),
+            None => (stream, None),
+        };
+        let (name, params) = split_srs_params(name);
+        self.stream = Some(name.to_string());
+        self.params.extend(params);
+        if let Some(query) = query {
+            self.params
+                .extend(form_urlencoded::parse(query.as_bytes()).into_owned());
+            let query = match self.tc_url.query() {
+                Some(q) if !q.is_empty() => format!("{}&{}", q, query),
+                _ => query.to_string(),
+            };
+            self.tc_url.set_query(Some(&query));
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(|v| v.as_str())
+    }
+
     pub fn stream(&self) -> &str {
         match &self.stream {
             Some(s) => s,
@@ -76,6 +174,17 @@ impl Request {
     }
 }
 
+// SRS-style params for the clients without query support, e.g. live...vhost...a.com
+fn split_srs_params(s: &str) -> (&str, Vec<(String, String)>) {
+    let mut parts = s.split("...");
+    let name = parts.next().unwrap_or("");
+    let mut params = Vec::new();
+    while let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+        params.push((k.to_string(), v.to_string()));
+    }
+    (name, params)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -181,6 +290,59 @@ mod tests {
             req.stream
         );
     }
+    #[test]
+    fn test_stream_query() {
+        let url = String::from("rtmp://1.1.1.1/live?vhost=a");
+        let mut req = Request::parse_from(url).unwrap();
+        req.set_stream("stream?token=abc&expire=1");
+        assert_eq!(req.stream(), "stream");
+        assert_eq!(req.app_stream(), "/live/stream");
+        assert_eq!(req.tc_url.query(), Some("vhost=a&token=abc&expire=1"));
+        assert_eq!(req.param("token"), Some("abc"));
+        assert_eq!(req.param("vhost"), Some("a"));
+        assert_eq!(req.param("none"), None);
+
+        // The stream name wins, and the values are decoded
+        let url = String::from("rtmp://1.1.1.1/live?start=1&a=b%20c");
+        let mut req = Request::parse_from(url).unwrap();
+        assert_eq!(req.params.len(), 2);
+        req.set_stream("stream?start=2");
+        assert_eq!(req.param("start"), Some("2"));
+        assert_eq!(req.param("a"), Some("b c"));
+
+        // The HTTP-FLV url
+        let url = String::from("http://1.1.1.1/live/stream.flv?token=abc");
+        let req = Request::parse_from(url).unwrap();
+        assert_eq!(req.app_stream(), "/live/stream");
+        assert_eq!(req.param("token"), Some("abc"));
+    }
+    #[test]
+    fn test_vhost() {
+        let url = String::from("rtmp://1.1.1.1/live");
+        let mut req = Request::parse_from(url).unwrap();
+        req.set_stream("stream");
+        assert_eq!(req.vhost_hint(), None);
+        assert_eq!(req.stream_key(), "/live/stream");
+        req.vhost = Some("a.com".to_string());
+        assert_eq!(req.stream_key(), "a.com/live/stream");
+
+        let url = String::from("rtmp://a.com:1935/live");
+        let req = Request::parse_from(url).unwrap();
+        assert_eq!(req.vhost_hint(), Some("a.com"));
+
+        // The param wins
+        let url = String::from("rtmp://a.com/live?vhost=b.com");
+        let req = Request::parse_from(url).unwrap();
+        assert_eq!(req.vhost_hint(), Some("b.com"));
+
+        // SRS-style
+        let url = String::from("rtmp://1.1.1.1/live...vhost...c.com");
+        let mut req = Request::parse_from(url).unwrap();
+        req.set_stream("stream...token...abc");
+        assert_eq!(req.app_stream(), "/live/stream");
+        assert_eq!(req.vhost_hint(), Some("c.com"));
+        assert_eq!(req.param("token"), Some("abc"));
+    }
     // #[test]
     // fn test11() {
     //     let url = String::from("/live/a.flv?ab=ba");
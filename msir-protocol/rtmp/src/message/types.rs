@@ -62,6 +62,7 @@ pub mod amf0_command_type {
 pub mod rtmp_sig {
     pub const RTMP_SIG_FMS_VER: &str = "FMS/3,5,3,888";
     pub const RTMP_SIG_AMF0_VER: f64 = 0.0;
+    pub const RTMP_SIG_AMF3_VER: f64 = 3.0;
     pub const RTMP_SIG_CLIENT_ID: &str = "ASAICiss";
 }
 
@@ -79,9 +80,11 @@ pub mod rtmp_status {
     pub const STATUS_CODE_CONNECT_REJECTED: &str = "NetConnection.Connect.Rejected";
     pub const STATUS_CODE_STREAM_RESET: &str = "NetStream.Play.Reset";
     pub const STATUS_CODE_STREAM_START: &str = "NetStream.Play.Start";
+    pub const STATUS_CODE_STREAM_UNPUBLISH_NOTIFY: &str = "NetStream.Play.UnpublishNotify";
     pub const STATUS_CODE_STREAM_PAUSE: &str = "NetStream.Pause.Notify";
     pub const STATUS_CODE_STREAM_UNPAUSE: &str = "NetStream.Unpause.Notify";
     pub const STATUS_CODE_PUBLISH_START: &str = "NetStream.Publish.Start";
+    pub const STATUS_CODE_PUBLISH_BAD_NAME: &str = "NetStream.Publish.BadName";
     pub const STATUS_CODE_DATA_START: &str = "NetStream.Data.Start";
     pub const STATUS_CODE_UNPUBLISH_SUCCESS: &str = "NetStream.Unpublish.Success";
 }
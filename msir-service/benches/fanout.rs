@@ -0,0 +1,133 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use futures::FutureExt;
+use httpflv::FlvTransmuxer;
+use msir_service::stream::{
+    hub::Hub,
+    queue::{self, Batch, SlowPolicy},
+};
+use rtmp::{connection::EncodedChunks, message::RtmpMessage};
+use tokio::sync::mpsc;
+
+const SUBSCRIBERS: usize = 300;
+
+// 1s of 25fps video with 2Mbps and 50fps audio, the first video frame is a keyframe
+fn frames() -> Vec<RtmpMessage> {
+    let mut msgs = Vec::new();
+    for i in 0..25_u32 {
+        let mut video = vec![if i == 0 { 0x17 } else { 0x27 }, 0x01, 0, 0, 0];
+        video.resize(10_000, 0xaa);
+        msgs.push(RtmpMessage::VideoData {
+            stream_id: 1,
+            timestamp: i * 40,
+            payload: Bytes::from(video),
+        });
+        for j in 0..2 {
+            let mut audio = vec![0xaf, 0x01];
+            audio.resize(200, 0xbb);
+            msgs.push(RtmpMessage::AudioData {
+                stream_id: 1,
+                timestamp: i * 40 + j * 20,
+                payload: Bytes::from(audio),
+            });
+        }
+    }
+    msgs
+}
+
+fn bench_fanout(c: &mut Criterion) {
+    let msgs = frames();
+    let mut group = c.benchmark_group("fanout");
+
+    // What the Hub did before: a Vec cloned for every subscriber
+    group.bench_function("clone_per_subscriber", |b| {
+        let mut subs: Vec<_> = (0..SUBSCRIBERS)
+            .map(|_| mpsc::unbounded_channel::<Vec<RtmpMessage>>())
+            .collect();
+        b.iter(|| {
+            for (tx, _) in subs.iter() {
+                tx.send(msgs.clone()).unwrap();
+            }
+            for (_, rx) in subs.iter_mut() {
+                black_box(rx.try_recv().unwrap());
+            }
+        })
+    });
+
+    group.bench_function("hub_shared_batch", |b| {
+        let (_hub_tx, hub_rx) = mpsc::unbounded_channel();
+        let (stat_tx, _stat_rx) = mpsc::unbounded_channel();
+        let mut hub = Hub::new(hub_rx, SlowPolicy::DropFrames, stat_tx);
+        let mut rxs = Vec::with_capacity(SUBSCRIBERS);
+        for i in 0..SUBSCRIBERS {
+            let (tx, rx) = queue::channel(1024);
+            hub.subscribers.insert(i.to_string(), tx);
+            rxs.push(rx);
+        }
+        b.iter(|| {
+            for msg in msgs.iter() {
+                hub.on_frame(msg.clone()).unwrap();
+            }
+            for rx in rxs.iter_mut() {
+                while let Some(Some(batch)) = rx.recv().now_or_never() {
+                    black_box(batch);
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_flv(c: &mut Criterion) {
+    let msgs = frames();
+    let mut group = c.benchmark_group("flv");
+
+    // Every player encodes the same frames
+    group.bench_function("encode_per_player", |b| {
+        let mut players: Vec<_> = (0..SUBSCRIBERS).map(|_| FlvTransmuxer::new()).collect();
+        b.iter(|| {
+            for player in players.iter_mut() {
+                black_box(player.write_tags(&msgs, 0).unwrap());
+            }
+        })
+    });
+
+    group.bench_function("encode_once", |b| {
+        let mut players: Vec<_> = (0..SUBSCRIBERS).map(|_| FlvTransmuxer::new()).collect();
+        b.iter(|| {
+            let batch = Batch::new(msgs.clone());
+            for player in players.iter_mut() {
+                let tags = batch.flv_tags(player.legacy_hevc()).unwrap();
+                black_box(player.write_encoded([tags]));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_rtmp(c: &mut Criterion) {
+    let msgs = frames();
+    let mut group = c.benchmark_group("rtmp");
+
+    // Every player encodes the same frames into chunks
+    group.bench_function("encode_per_player", |b| {
+        b.iter(|| {
+            for _ in 0..SUBSCRIBERS {
+                black_box(EncodedChunks::encode(&msgs, 60000).unwrap());
+            }
+        })
+    });
+
+    group.bench_function("encode_once", |b| {
+        b.iter(|| {
+            let batch = Batch::new(msgs.clone());
+            for _ in 0..SUBSCRIBERS {
+                black_box(batch.rtmp_chunks(60000).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_fanout, bench_flv, bench_rtmp);
+criterion_main!(benches);
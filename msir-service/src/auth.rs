@@ -0,0 +1,164 @@
+use hmac::{Hmac, Mac};
+use msir_core::utils;
+use rtmp::message::request::Request;
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("The token or expire is missing")]
+    MissingToken,
+
+    #[error("The token is expired")]
+    Expired,
+
+    #[error("The token is invalid")]
+    InvalidToken,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    pub publish: bool,
+    pub play: bool,
+    // The apps without secret are not checked
+    pub secret: Option<String>,
+    // Secret by app, override the default one
+    pub apps: HashMap<String, String>,
+}
+
+// Stateless auth by the signed url, e.g. rtmp://host/app/stream?token=xxx&expire=1700000000
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    config: Arc<AuthConfig>,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn check_publish(&self, req: &Request) -> Result<(), AuthError> {
+        match self.config.publish {
+            true => self.check(req),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_play(&self, req: &Request) -> Result<(), AuthError> {
+        match self.config.play {
+            true => self.check(req),
+            false => Ok(()),
+        }
+    }
+
+    fn check(&self, req: &Request) -> Result<(), AuthError> {
+        let app = req
+            .tc_url
+            .path_segments()
+            .and_then(|mut s| s.next())
+            .unwrap_or("");
+        let secret = match self.config.apps.get(app).or(self.config.secret.as_ref()) {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let (token, expire) = match (req.param("token"), req.param("expire")) {
+            (Some(token), Some(expire)) => (token, expire),
+            _ => return Err(AuthError::MissingToken),
+        };
+        let expire: u64 = expire.parse().map_err(|_| AuthError::InvalidToken)?;
+        if expire < utils::current_time() as u64 {
+            return Err(AuthError::Expired);
+        }
+        let token = from_hex(token).ok_or(AuthError::InvalidToken)?;
+        // Constant time comparison
+        mac(secret, app, req.stream(), expire)
+            .verify_slice(&token)
+            .map_err(|_| AuthError::InvalidToken)
+    }
+}
+
+// The token is the hex of HMAC-SHA256 over "app/stream/expire"
+pub fn sign(secret: &str, app: &str, stream: &str, expire: u64) -> String {
+    mac(secret, app, stream, expire)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn mac(secret: &str, app: &str, stream: &str, expire: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}/{}/{}", app, stream, expire).as_bytes());
+    mac
+}
+
+// None if odd length or not hex
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: &str) -> Request {
+        let mut req = Request::parse_from("rtmp://127.0.0.1/live".to_string()).unwrap();
+        req.set_stream(&format!("test?{}", query));
+        req
+    }
+
+    #[test]
+    fn test_check() {
+        let auth = Auth::new(AuthConfig {
+            publish: true,
+            play: false,
+            secret: None,
+            apps: HashMap::from([("live".to_string(), "secret".to_string())]),
+        });
+        let expire = utils::current_time() as u64 + 60;
+        let token = sign("secret", "live", "test", expire);
+
+        let req = request(&format!("token={}&expire={}", token, expire));
+        assert!(auth.check_publish(&req).is_ok());
+
+        // Signed for another stream or by another secret
+        let other = sign("secret", "live", "other", expire);
+        let req = request(&format!("token={}&expire={}", other, expire));
+        assert!(matches!(
+            auth.check_publish(&req),
+            Err(AuthError::InvalidToken)
+        ));
+        let other = sign("other", "live", "test", expire);
+        let req = request(&format!("token={}&expire={}", other, expire));
+        assert!(matches!(
+            auth.check_publish(&req),
+            Err(AuthError::InvalidToken)
+        ));
+
+        // The expire is signed too
+        let req = request(&format!("token={}&expire={}", token, expire + 1));
+        assert!(matches!(
+            auth.check_publish(&req),
+            Err(AuthError::InvalidToken)
+        ));
+        let expire = utils::current_time() as u64 - 1;
+        let token = sign("secret", "live", "test", expire);
+        let req = request(&format!("token={}&expire={}", token, expire));
+        assert!(matches!(auth.check_publish(&req), Err(AuthError::Expired)));
+
+        let req = request("expire=1");
+        assert!(matches!(
+            auth.check_publish(&req),
+            Err(AuthError::MissingToken)
+        ));
+        // Play is not checked
+        assert!(auth.check_play(&req).is_ok());
+    }
+}
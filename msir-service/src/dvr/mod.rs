@@ -0,0 +1,172 @@
+use crate::{dvr_service::DvrService, statistic::ConnToStatChanTx, stream::ConnToMgrChanTx, utils};
+use std::collections::HashMap;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, Instrument};
+
+pub mod segment;
+
+pub type ConnToDvrChanTx = mpsc::UnboundedSender<DvrEvent>;
+pub type ConnToDvrChanRx = mpsc::UnboundedReceiver<DvrEvent>;
+
+// False if the stream is already recording when start, or not recording when stop
+pub type DvrResponse = oneshot::Sender<bool>;
+
+pub enum DvrEvent {
+    Start(String, DvrResponse),
+    Stop(String, DvrResponse),
+    // A new stream is published
+    Publish(String),
+    // The recorder of stream exited
+    Remove(String, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DvrConfig {
+    // Path template, e.g. ./dvr/{app}/{stream}/{timestamp}.flv
+    pub path: String,
+    // Record every publish automatically
+    pub auto: bool,
+    // Max duration of segment in seconds, 0 means no limit
+    pub segment_duration: u32,
+    // Max size of segment in bytes, 0 means no limit
+    pub segment_size: u64,
+}
+
+pub struct DvrManager {
+    config: DvrConfig,
+    // uid and stop signal of the recorders
+    recorders: HashMap<String, (String, oneshot::Sender<()>)>,
+    dvr_rx: ConnToDvrChanRx,
+    dvr_tx: ConnToDvrChanTx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+}
+
+impl DvrManager {
+    pub fn new(
+        config: DvrConfig,
+        dvr_rx: ConnToDvrChanRx,
+        dvr_tx: ConnToDvrChanTx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        Self {
+            config,
+            recorders: HashMap::new(),
+            dvr_rx,
+            dvr_tx,
+            mgr_tx,
+            stat_tx,
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(ev) = self.dvr_rx.recv().await {
+            match ev {
+                DvrEvent::Start(stream_key, ret) => {
+                    let _ = ret.send(self.on_start(stream_key));
+                }
+                DvrEvent::Stop(stream_key, ret) => {
+                    let _ = ret.send(self.on_stop(stream_key));
+                }
+                DvrEvent::Publish(stream_key) => {
+                    if self.config.auto {
+                        // The old recorder is attached to the unpublished hub, replace it
+                        self.on_stop(stream_key.clone());
+                        self.on_start(stream_key);
+                    }
+                }
+                DvrEvent::Remove(stream_key, uid) => self.on_remove(stream_key, uid),
+            }
+        }
+    }
+
+    fn on_start(&mut self, stream_key: String) -> bool {
+        if self.recorders.contains_key(&stream_key) {
+            return false;
+        }
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let uid = utils::gen_uid();
+        self.recorders
+            .insert(stream_key.clone(), (uid.clone(), stop_tx));
+
+        debug!("Start dvr recorder {} for {}", uid, stream_key);
+        let mut dvr = DvrService::new(
+            uid.clone(),
+            self.config.clone(),
+            stop_rx,
+            self.dvr_tx.clone(),
+            self.mgr_tx.clone(),
+            self.stat_tx.clone(),
+        );
+        tokio::spawn(
+            async move {
+                if let Err(e) = dvr.run(stream_key).await {
+                    error!("Failed to record; error={}", e);
+                }
+            }
+            .instrument(tracing::info_span!("DVR-CONN", uid)),
+        );
+        true
+    }
+
+    fn on_stop(&mut self, stream_key: String) -> bool {
+        match self.recorders.remove(&stream_key) {
+            Some((uid, stop_tx)) => {
+                debug!("Stop dvr recorder {} for {}", uid, stream_key);
+                let _ = stop_tx.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn on_remove(&mut self, stream_key: String, uid: String) {
+        // A new recorder may have been started for the same stream
+        if let Some((id, _)) = self.recorders.get(&stream_key) {
+            if *id == uid {
+                self.recorders.remove(&stream_key);
+                debug!("Remove dvr recorder {} for {}", uid, stream_key);
+            }
+        }
+    }
+}
+
+// Replace {vhost}, {app}, {stream} and {timestamp} in the path template
+pub fn gen_path(template: &str, stream_key: &str, timestamp: u128) -> String {
+    let mut vecs = stream_key.splitn(3, '/');
+    let vhost = vecs.next().unwrap_or("");
+    let app = vecs.next().unwrap_or("");
+    let stream = vecs.next().unwrap_or("");
+    template
+        .replace("{vhost}", vhost)
+        .replace("{app}", app)
+        .replace("{stream}", stream)
+        .replace("{timestamp}", &timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gen_path() {
+        assert_eq!(
+            gen_path(
+                "./dvr/{app}/{stream}/{timestamp}.flv",
+                "/live/stream",
+                1686900000000
+            ),
+            "./dvr/live/stream/1686900000000.flv"
+        );
+        assert_eq!(
+            gen_path("/data/{app}-{stream}.flv", "/live/", 0),
+            "/data/live-.flv"
+        );
+        assert_eq!(
+            gen_path("/data/{vhost}/{app}/{stream}.flv", "a.com/live/stream", 0),
+            "/data/a.com/live/stream.flv"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use httpflv::FlvTransmuxer;
+use rml_amf0::Amf0Value;
+use rtmp::message::RtmpMessage;
+use std::{collections::HashMap, io::SeekFrom, path::PathBuf};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use tracing::info;
+
+use crate::error::ServiceError;
+
+// FLV header(9) and the first previous tag size(4)
+const FLV_HEADER_SIZE: u64 = 13;
+
+// Frames are written to a temporary file first, and the final file is
+// generated on close with the onMetaData which contains duration and index.
+pub struct DvrSegment {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: BufWriter<File>,
+    flv_enc: FlvTransmuxer,
+    // Timestamp of the first frame, the segment starts from 0
+    base: u32,
+    duration: u32,
+    size: u64,
+    // (timestamp, fileposition) of the keyframes
+    keyframes: Vec<(u32, u64)>,
+}
+
+impl DvrSegment {
+    pub async fn create(path: PathBuf, base: u32) -> Result<Self, ServiceError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let file = BufWriter::new(File::create(&tmp_path).await?);
+        info!("Start dvr segment {}", path.display());
+        Ok(Self {
+            path,
+            tmp_path,
+            file,
+            flv_enc: FlvTransmuxer::new(),
+            base,
+            duration: 0,
+            size: 0,
+            keyframes: Vec::new(),
+        })
+    }
+
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub async fn write(&mut self, msg: &RtmpMessage) -> Result<(), ServiceError> {
+        let msg = match msg {
+            RtmpMessage::VideoData {
+                stream_id,
+                timestamp,
+                payload,
+            } => RtmpMessage::VideoData {
+                stream_id: *stream_id,
+                timestamp: timestamp.saturating_sub(self.base),
+                payload: payload.clone(),
+            },
+            RtmpMessage::AudioData {
+                stream_id,
+                timestamp,
+                payload,
+            } => RtmpMessage::AudioData {
+                stream_id: *stream_id,
+                timestamp: timestamp.saturating_sub(self.base),
+                payload: payload.clone(),
+            },
+            _ => return Ok(()),
+        };
+        let timestamp = msg.timestamp().unwrap_or(0);
+        let data = self
+            .flv_enc
+            .write_tags(std::slice::from_ref(&msg), msg.len().unwrap_or(0))?;
+        if msg.is_key_frame() {
+            // Skip the FLV header written with the first frame
+            let position = self.size.max(FLV_HEADER_SIZE);
+            self.keyframes.push((timestamp, position));
+        }
+        self.file.write_all(&data).await?;
+        self.size += data.len() as u64;
+        self.duration = self.duration.max(timestamp);
+        Ok(())
+    }
+
+    // Generate the final file with the onMetaData fixed up
+    pub async fn close(mut self, metadata: Option<&RtmpMessage>) -> Result<(), ServiceError> {
+        self.file.flush().await?;
+        drop(self.file);
+
+        let mut object = match metadata {
+            Some(RtmpMessage::Amf0Data { values, .. }) => match values.first() {
+                Some(Amf0Value::Object(object)) => object.clone(),
+                _ => HashMap::new(),
+            },
+            _ => HashMap::new(),
+        };
+
+        // Encode once to get the size of onMetaData tag, the size of number is fixed
+        let prefix = encode_metadata(&mut object, 0, 0, &self.keyframes, 0)?;
+        let meta_size = prefix.len() as u64 - FLV_HEADER_SIZE;
+        let filesize = self.size.max(FLV_HEADER_SIZE) + meta_size;
+        let prefix = encode_metadata(
+            &mut object,
+            self.duration,
+            filesize,
+            &self.keyframes,
+            meta_size,
+        )?;
+
+        let mut file = BufWriter::new(File::create(&self.path).await?);
+        file.write_all(&prefix).await?;
+        let mut tmp = File::open(&self.tmp_path).await?;
+        tmp.seek(SeekFrom::Start(FLV_HEADER_SIZE)).await?;
+        tokio::io::copy(&mut tmp, &mut file).await?;
+        file.flush().await?;
+        fs::remove_file(&self.tmp_path).await?;
+
+        info!(
+            "Finish dvr segment {} duration {}ms size {}",
+            self.path.display(),
+            self.duration,
+            filesize
+        );
+        Ok(())
+    }
+}
+
+// Returns FLV header and the onMetaData tag
+fn encode_metadata(
+    object: &mut HashMap<String, Amf0Value>,
+    duration: u32,
+    filesize: u64,
+    keyframes: &[(u32, u64)],
+    offset: u64,
+) -> Result<Vec<u8>, ServiceError> {
+    object.insert(
+        "duration".to_string(),
+        Amf0Value::Number(duration as f64 / 1000.0),
+    );
+    object.insert("filesize".to_string(), Amf0Value::Number(filesize as f64));
+    let times = keyframes
+        .iter()
+        .map(|(t, _)| Amf0Value::Number(*t as f64 / 1000.0))
+        .collect();
+    let filepositions = keyframes
+        .iter()
+        .map(|(_, p)| Amf0Value::Number((*p + offset) as f64))
+        .collect();
+    object.insert(
+        "keyframes".to_string(),
+        Amf0Value::Object(HashMap::from([
+            ("times".to_string(), Amf0Value::StrictArray(times)),
+            (
+                "filepositions".to_string(),
+                Amf0Value::StrictArray(filepositions),
+            ),
+        ])),
+    );
+    let msg = RtmpMessage::Amf0Data {
+        command_name: "onMetaData".to_string(),
+        values: vec![Amf0Value::Object(object.clone())],
+    };
+    Ok(FlvTransmuxer::new().write_tags(&[msg], 0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::io::Cursor;
+
+    fn video(timestamp: u32, payload: &'static [u8]) -> RtmpMessage {
+        RtmpMessage::VideoData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_fixup() {
+        let dir = std::env::temp_dir().join(format!("msir-dvr-{}", crate::utils::gen_uid()));
+        let path = dir.join("live/stream.flv");
+        let mut segment = DvrSegment::create(path.clone(), 1000).await.unwrap();
+        segment
+            .write(&video(1000, &[0x17, 0x00, 0, 0, 0, 0x01]))
+            .await
+            .unwrap();
+        segment
+            .write(&video(1000, &[0x17, 0x01, 0, 0, 0, 0x65]))
+            .await
+            .unwrap();
+        segment
+            .write(&video(1040, &[0x27, 0x01, 0, 0, 0, 0x41]))
+            .await
+            .unwrap();
+        segment
+            .write(&video(2000, &[0x17, 0x01, 0, 0, 0, 0x65]))
+            .await
+            .unwrap();
+        segment.close(None).await.unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&data[..3], b"FLV");
+        // The first tag is onMetaData
+        assert_eq!(data[13], 18);
+        let size = (data[14] as usize) << 16 | (data[15] as usize) << 8 | data[16] as usize;
+        let mut values = rml_amf0::deserialize(&mut Cursor::new(&data[24..24 + size])).unwrap();
+        let mut object = match values.pop() {
+            Some(Amf0Value::Object(object)) => object,
+            _ => panic!("invalid onMetaData"),
+        };
+        assert_eq!(object["duration"], Amf0Value::Number(1.0));
+        assert_eq!(object["filesize"], Amf0Value::Number(data.len() as f64));
+
+        let mut keyframes = match object.remove("keyframes") {
+            Some(Amf0Value::Object(object)) => object,
+            _ => panic!("invalid keyframes"),
+        };
+        assert_eq!(
+            keyframes["times"],
+            Amf0Value::StrictArray(vec![Amf0Value::Number(0.0), Amf0Value::Number(1.0)])
+        );
+        let positions = match keyframes.remove("filepositions") {
+            Some(Amf0Value::StrictArray(positions)) => positions,
+            _ => panic!("invalid filepositions"),
+        };
+        for position in positions {
+            let pos = position.get_number().unwrap() as usize;
+            // Video tag of keyframe
+            assert_eq!(data[pos], 9);
+            assert_eq!(&data[pos + 11..pos + 13], &[0x17, 0x01]);
+        }
+    }
+}
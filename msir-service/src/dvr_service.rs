@@ -0,0 +1,258 @@
+use rtmp::{codec, connection::RtmpConnType, message::RtmpMessage};
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::{
+    dvr::{gen_path, segment::DvrSegment, ConnToDvrChanTx, DvrConfig, DvrEvent},
+    error::ServiceError,
+    statistic::{ConnStat, ConnToStatChanTx, StatEvent},
+    stream::{ConnToMgrChanTx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    CONN_PRINT_INTVAL,
+};
+
+pub struct DvrService {
+    uid: String,
+    config: DvrConfig,
+    stop_rx: oneshot::Receiver<()>,
+    dvr_tx: ConnToDvrChanTx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+
+    segment: Option<DvrSegment>,
+    metadata: Option<RtmpMessage>,
+    video_sh: Option<RtmpMessage>,
+    audio_sh: Option<RtmpMessage>,
+    send_bytes: u64,
+    audio_count: u64,
+    video_count: u64,
+}
+
+impl DvrService {
+    pub fn new(
+        uid: String,
+        config: DvrConfig,
+        stop_rx: oneshot::Receiver<()>,
+        dvr_tx: ConnToDvrChanTx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        Self {
+            uid,
+            config,
+            stop_rx,
+            dvr_tx,
+            mgr_tx,
+            stat_tx,
+            segment: None,
+            metadata: None,
+            video_sh: None,
+            audio_sh: None,
+            send_bytes: 0,
+            audio_count: 0,
+            video_count: 0,
+        }
+    }
+
+    pub async fn run(&mut self, stream_key: String) -> Result<(), ServiceError> {
+        info!("Identify {:?} stream:{}", RtmpConnType::Dvr, stream_key);
+
+        let ret = match self.register(&stream_key).await {
+            Ok(token) => {
+                let ret = self.recording(&stream_key, token).await;
+                self.close_segment().await;
+                self.unregister(&stream_key).await;
+                ret
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = self
+            .dvr_tx
+            .send(DvrEvent::Remove(stream_key, self.uid.clone()));
+
+        ret
+    }
+
+    async fn register(&self, stream_key: &str) -> Result<Token, ServiceError> {
+        let (reg_tx, reg_rx) = oneshot::channel();
+        let msg = StreamEvent::Register(RegisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.to_string(),
+            role: RoleType::Subscriber,
+            ret: reg_tx,
+            kick: None,
+        });
+        if self.mgr_tx.send(msg).is_err() {
+            return Err(ServiceError::RegisterFailed(
+                "send register event failed".to_string(),
+            ));
+        }
+
+        match reg_rx.await {
+            Ok(token) => {
+                if let Token::Failure(e) = token {
+                    return Err(ServiceError::RegisterFailed(e.to_string()));
+                }
+                let _ = self.stat_tx.send(StatEvent::CreateConn(
+                    self.uid.clone(),
+                    ConnStat::new(stream_key.to_string(), RtmpConnType::Dvr),
+                ));
+                Ok(token)
+            }
+            Err(_) => Err(ServiceError::RegisterFailed(
+                "recv register ret failed: ".to_string(),
+            )),
+        }
+    }
+
+    async fn unregister(&mut self, stream_key: &str) {
+        let msg = StreamEvent::Unregister(UnregisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.to_string(),
+            role: RoleType::Subscriber,
+        });
+        if let Err(e) = self.mgr_tx.send(msg) {
+            warn!("send unregister event failed: {}", e);
+        }
+        let _ = self.stat_tx.send(StatEvent::DeleteConn(
+            self.uid.clone(),
+            self.conn_stat(stream_key),
+        ));
+    }
+
+    async fn recording(&mut self, stream_key: &str, token: Token) -> Result<(), ServiceError> {
+        let mut rx = match token {
+            Token::SubscriberToken(rx) => rx,
+            _ => return Err(ServiceError::InvalidToken),
+        };
+        let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
+        loop {
+            tokio::select! {
+                msgs = rx.recv() => {
+                    match msgs {
+                        Some(msgs) => {
+                            for msg in msgs.iter() {
+                                self.on_frame(stream_key, msg.clone()).await?;
+                            }
+                        }
+                        None => return Err(ServiceError::PublishDone)
+                    }
+                }
+                _ = &mut self.stop_rx => {
+                    info!("Dvr recorder is stopped");
+                    return Ok(());
+                }
+                _ = stat_report.tick() => {
+                    let _ = self.stat_tx.send(StatEvent::UpdateConn(
+                        self.uid.clone(),
+                        self.conn_stat(stream_key),
+                    ));
+                }
+            }
+        }
+    }
+
+    async fn on_frame(&mut self, stream_key: &str, msg: RtmpMessage) -> Result<(), ServiceError> {
+        let (is_video, payload) = match &msg {
+            RtmpMessage::VideoData { payload, .. } => (true, payload),
+            RtmpMessage::AudioData { payload, .. } => (false, payload),
+            RtmpMessage::Amf0Data { .. } => {
+                if msg.is_metadata() {
+                    self.metadata = Some(msg);
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        if is_video && codec::is_video_sequence_header(payload) {
+            self.video_sh = Some(msg.clone());
+        } else if !is_video && codec::is_audio_sequence_header(payload) {
+            self.audio_sh = Some(msg.clone());
+        } else if msg.is_key_frame() || (!is_video && self.video_sh.is_none()) {
+            // Segment starts with keyframe, or any audio frame for pure audio stream
+            if self.reach_limit() {
+                self.close_segment().await;
+            }
+            if self.segment.is_none() {
+                self.open_segment(stream_key, msg.timestamp().unwrap_or(0))
+                    .await?;
+            }
+        }
+
+        if let Some(segment) = self.segment.as_mut() {
+            let size = segment.size();
+            segment.write(&msg).await?;
+            self.send_bytes += segment.size() - size;
+            match is_video {
+                true => self.video_count += 1,
+                false => self.audio_count += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn reach_limit(&self) -> bool {
+        match &self.segment {
+            Some(segment) => {
+                (self.config.segment_duration > 0
+                    && segment.duration() >= self.config.segment_duration * 1000)
+                    || (self.config.segment_size > 0 && segment.size() >= self.config.segment_size)
+            }
+            None => false,
+        }
+    }
+
+    async fn open_segment(&mut self, stream_key: &str, base: u32) -> Result<(), ServiceError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path = PathBuf::from(gen_path(&self.config.path, stream_key, now));
+        let mut segment = DvrSegment::create(path, base).await?;
+        // The sequence headers are required by every segment
+        for sh in [&self.video_sh, &self.audio_sh].into_iter().flatten() {
+            let sh = match sh {
+                RtmpMessage::VideoData {
+                    stream_id, payload, ..
+                } => RtmpMessage::VideoData {
+                    stream_id: *stream_id,
+                    timestamp: base,
+                    payload: payload.clone(),
+                },
+                RtmpMessage::AudioData {
+                    stream_id, payload, ..
+                } => RtmpMessage::AudioData {
+                    stream_id: *stream_id,
+                    timestamp: base,
+                    payload: payload.clone(),
+                },
+                _ => continue,
+            };
+            segment.write(&sh).await?;
+        }
+        self.segment = Some(segment);
+        Ok(())
+    }
+
+    async fn close_segment(&mut self) {
+        if let Some(segment) = self.segment.take() {
+            if let Err(e) = segment.close(self.metadata.as_ref()).await {
+                warn!("Close dvr segment failed: {}", e);
+            }
+        }
+    }
+
+    fn conn_stat(&self, stream_key: &str) -> ConnStat {
+        let mut conn = ConnStat::new(stream_key.to_string(), RtmpConnType::Dvr);
+        conn.recv_bytes = 0;
+        conn.send_bytes = self.send_bytes;
+        conn.audio_count = self.audio_count;
+        conn.video_count = self.video_count;
+        conn
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgePolicy {
+    // Start from the next origin for every pull
+    RoundRobin,
+    // The same stream always starts from the same origin
+    ConsistentHash,
+    // Always start from the first origin, the others are backups
+    PrimaryBackup,
+}
+
+impl EdgePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "round_robin" => Some(Self::RoundRobin),
+            "hash" => Some(Self::ConsistentHash),
+            "backup" => Some(Self::PrimaryBackup),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EdgeConfig {
+    // Origin urls, e.g. rtmp://10.0.0.1:1935
+    pub origins: Vec<String>,
+    pub policy: EdgePolicy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Origins {
+    config: Arc<EdgeConfig>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Origins {
+    pub fn new(config: EdgeConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // All the origins in the order to try, the first one is preferred and the rest are for failover
+    pub fn select(&self, stream_key: &str) -> Vec<String> {
+        let origins = &self.config.origins;
+        if origins.is_empty() {
+            return Vec::new();
+        }
+        match self.config.policy {
+            EdgePolicy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % origins.len();
+                origins[start..]
+                    .iter()
+                    .chain(origins[..start].iter())
+                    .cloned()
+                    .collect()
+            }
+            EdgePolicy::ConsistentHash => {
+                // Rendezvous hashing, only the streams of the removed origin move when origins change
+                let mut scored: Vec<(u64, &String)> = origins
+                    .iter()
+                    .map(|o| {
+                        (
+                            fnv1a(o.as_bytes(), fnv1a(stream_key.as_bytes(), FNV_OFFSET)),
+                            o,
+                        )
+                    })
+                    .collect();
+                scored.sort_by_key(|s| std::cmp::Reverse(s.0));
+                scored.into_iter().map(|(_, o)| o.clone()).collect()
+            }
+            EdgePolicy::PrimaryBackup => origins.clone(),
+        }
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Stable across processes, so all the edges choose the same origin for a stream
+fn fnv1a(data: &[u8], mut hash: u64) -> u64 {
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(policy: EdgePolicy) -> Origins {
+        Origins::new(EdgeConfig {
+            origins: vec![
+                "rtmp://10.0.0.1".to_string(),
+                "rtmp://10.0.0.2".to_string(),
+                "rtmp://10.0.0.3".to_string(),
+            ],
+            policy,
+        })
+    }
+
+    #[test]
+    fn test_select() {
+        let rr = origins(EdgePolicy::RoundRobin);
+        assert_eq!(rr.select("/live/a")[0], "rtmp://10.0.0.1");
+        assert_eq!(
+            rr.select("/live/a"),
+            vec!["rtmp://10.0.0.2", "rtmp://10.0.0.3", "rtmp://10.0.0.1"]
+        );
+
+        let hash = origins(EdgePolicy::ConsistentHash);
+        let first = hash.select("/live/a");
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, hash.select("/live/a"));
+
+        let backup = origins(EdgePolicy::PrimaryBackup);
+        assert_eq!(backup.select("/live/a"), backup.select("/live/b"));
+        assert_eq!(backup.select("/live/a")[0], "rtmp://10.0.0.1");
+    }
+}
@@ -1,7 +1,12 @@
-use crate::stream::error::StreamError;
+use crate::{hook::HookError, stream::error::StreamError};
 use futures::channel::mpsc::SendError;
 use httpflv::error::FlvMuxerError;
-use rtmp::{connection::error::ConnectionError, message::error::ReuquestError};
+use mpegts::error::TsMuxerError;
+use rtmp::{
+    connection::error::ConnectionError,
+    message::error::{MessageEncodeError, ReuquestError},
+};
+use std::io;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,6 +14,15 @@ pub enum ServiceError {
     #[error("Flv muxer error: {0}")]
     FlvError(#[from] FlvMuxerError),
 
+    #[error("Ts muxer error: {0}")]
+    TsError(#[from] TsMuxerError),
+
+    #[error("Encode message error: {0}")]
+    EncodeError(#[from] MessageEncodeError),
+
+    #[error("An IO error occurred: {0}")]
+    Io(#[from] io::Error),
+
     #[error("Channel send error: {0}")]
     ChanSendError(#[from] SendError),
 
@@ -21,6 +35,9 @@ pub enum ServiceError {
     #[error("Hub error: {0}")]
     HubError(#[from] StreamError),
 
+    #[error("Hook error: {0}")]
+    HookError(#[from] HookError),
+
     #[error("Register failed: {0}")]
     RegisterFailed(String),
 
@@ -32,4 +49,23 @@ pub enum ServiceError {
 
     #[error("No subscriber")]
     NoSubscriber,
+
+    #[error("No origin to pull from")]
+    NoOrigin,
+
+    #[error("Connect to upstream timeout")]
+    ConnectTimeout,
+
+    #[error("Kicked by the server")]
+    Kicked,
+
+    #[error("{0} is disabled by the vhost")]
+    Disabled(&'static str),
+}
+
+impl ServiceError {
+    // Rejected by the built-in auth
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, ServiceError::HookError(HookError::Unauthorized(_)))
+    }
 }
@@ -0,0 +1,118 @@
+use crate::{
+    dvr::gen_path, forward_service::ForwardService, statistic::ConnToStatChanTx,
+    stream::ConnToMgrChanTx, utils, vhost::Vhosts,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, error, Instrument};
+
+pub type ConnToForwardChanTx = mpsc::UnboundedSender<ForwardEvent>;
+pub type ConnToForwardChanRx = mpsc::UnboundedReceiver<ForwardEvent>;
+
+pub enum ForwardEvent {
+    // A new stream is published
+    Publish(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ForwardConfig {
+    // Url templates of destinations, e.g. rtmp://cdn.example.com/{app}/{stream}
+    pub destinations: Vec<String>,
+}
+
+pub struct ForwardManager {
+    // The destinations by vhost
+    vhosts: Vhosts,
+    forward_rx: ConnToForwardChanRx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+}
+
+impl ForwardManager {
+    pub fn new(
+        vhosts: Vhosts,
+        forward_rx: ConnToForwardChanRx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        Self {
+            vhosts,
+            forward_rx,
+            mgr_tx,
+            stat_tx,
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(ev) = self.forward_rx.recv().await {
+            match ev {
+                ForwardEvent::Publish(stream_key) => self.on_publish(stream_key),
+            }
+        }
+    }
+
+    // The forwarders exit by themselves when the stream is unpublished
+    fn on_publish(&mut self, stream_key: String) {
+        let config = match &self.vhosts.of_stream(&stream_key).forward {
+            Some(config) => config,
+            None => return,
+        };
+        for dest in config.destinations.iter() {
+            let (tc_url, stream) = match split_url(&gen_path(dest, &stream_key, 0)) {
+                Some(v) => v,
+                None => {
+                    error!("Invalid forward destination {}", dest);
+                    continue;
+                }
+            };
+            let uid = utils::gen_uid();
+            debug!("Start forwarder {} for {} to {}", uid, stream_key, dest);
+            let mut forwarder = ForwardService::new(
+                uid.clone(),
+                tc_url,
+                stream,
+                self.mgr_tx.clone(),
+                self.stat_tx.clone(),
+            );
+            let stream_key = stream_key.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = forwarder.run(stream_key).await {
+                        error!("Failed to forward; error={}", e);
+                    }
+                }
+                .instrument(tracing::info_span!("RTMP-FORWARD", uid)),
+            );
+        }
+    }
+}
+
+// Split rtmp://host/app/stream?param into tc_url and stream
+fn split_url(url: &str) -> Option<(String, String)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let (tc_url, stream) = rest.split_once('/').and_then(|(host, path)| {
+        let (app, stream) = path.split_once('/')?;
+        Some((format!("{}://{}/{}", scheme, host, app), stream))
+    })?;
+    match stream.is_empty() {
+        true => None,
+        false => Some((tc_url, stream.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            split_url("rtmp://127.0.0.1:1935/live/stream?token=1"),
+            Some((
+                "rtmp://127.0.0.1:1935/live".to_string(),
+                "stream?token=1".to_string()
+            ))
+        );
+        assert_eq!(split_url("rtmp://127.0.0.1/live"), None);
+        assert_eq!(split_url("rtmp://127.0.0.1/live/"), None);
+    }
+}
@@ -0,0 +1,338 @@
+use rtmp::{
+    codec,
+    connection::{client::Client as RtmpClient, RtmpConnType},
+    message::RtmpMessage,
+};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::{
+    error::ServiceError,
+    statistic::{ConnStat, ConnToStatChanTx, ForwardStat, ForwardState, StatEvent},
+    stream::{
+        ConnToMgrChanTx, HubToSubsChanRx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv,
+    },
+    CONN_PRINT_INTVAL, UPSTREAM_CONNECT_TIMEOUT,
+};
+
+const FORWARD_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const FORWARD_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+pub struct ForwardService {
+    uid: String,
+    tc_url: String,
+    stream: String,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+
+    // Resent to the destination after reconnected
+    metadata: Option<RtmpMessage>,
+    video_sh: Option<RtmpMessage>,
+    audio_sh: Option<RtmpMessage>,
+    state: ForwardState,
+    reconnects: u32,
+    // Bytes and frames of the closed connections
+    recv_bytes: u64,
+    send_bytes: u64,
+    audio_count: u64,
+    video_count: u64,
+}
+
+impl ForwardService {
+    pub fn new(
+        uid: String,
+        tc_url: String,
+        stream: String,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        Self {
+            uid,
+            tc_url,
+            stream,
+            mgr_tx,
+            stat_tx,
+            metadata: None,
+            video_sh: None,
+            audio_sh: None,
+            state: ForwardState::Connecting,
+            reconnects: 0,
+            recv_bytes: 0,
+            send_bytes: 0,
+            audio_count: 0,
+            video_count: 0,
+        }
+    }
+
+    pub async fn run(&mut self, stream_key: String) -> Result<(), ServiceError> {
+        info!(
+            "Identify {:?} stream:{} to {}/{}",
+            RtmpConnType::Forward,
+            stream_key,
+            self.tc_url,
+            self.stream
+        );
+
+        match self.register(&stream_key).await {
+            Ok(token) => {
+                let ret = self.forwarding(&stream_key, token).await;
+                self.unregister(&stream_key).await;
+                ret
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn register(&self, stream_key: &str) -> Result<Token, ServiceError> {
+        let (reg_tx, reg_rx) = oneshot::channel();
+        let msg = StreamEvent::Register(RegisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.to_string(),
+            role: RoleType::Subscriber,
+            ret: reg_tx,
+            kick: None,
+        });
+        if self.mgr_tx.send(msg).is_err() {
+            return Err(ServiceError::RegisterFailed(
+                "send register event failed".to_string(),
+            ));
+        }
+
+        match reg_rx.await {
+            Ok(token) => {
+                if let Token::Failure(e) = token {
+                    return Err(ServiceError::RegisterFailed(e.to_string()));
+                }
+                let _ = self.stat_tx.send(StatEvent::CreateConn(
+                    self.uid.clone(),
+                    self.conn_stat(stream_key, None),
+                ));
+                Ok(token)
+            }
+            Err(_) => Err(ServiceError::RegisterFailed(
+                "recv register ret failed: ".to_string(),
+            )),
+        }
+    }
+
+    async fn unregister(&mut self, stream_key: &str) {
+        let msg = StreamEvent::Unregister(UnregisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.to_string(),
+            role: RoleType::Subscriber,
+        });
+        if let Err(e) = self.mgr_tx.send(msg) {
+            warn!("send unregister event failed: {}", e);
+        }
+        let _ = self.stat_tx.send(StatEvent::DeleteConn(
+            self.uid.clone(),
+            self.conn_stat(stream_key, None),
+        ));
+    }
+
+    // Keep subscribing while reconnecting, so that the sequence headers are always up to date
+    async fn forwarding(&mut self, stream_key: &str, token: Token) -> Result<(), ServiceError> {
+        let mut rx = match token {
+            Token::SubscriberToken(rx) => rx,
+            _ => return Err(ServiceError::InvalidToken),
+        };
+        let mut backoff = FORWARD_BACKOFF_MIN;
+        loop {
+            let connect = tokio::time::timeout(
+                UPSTREAM_CONNECT_TIMEOUT,
+                connect(self.tc_url.clone(), self.stream.clone(), self.uid.clone()),
+            );
+            tokio::pin!(connect);
+            let ret = loop {
+                tokio::select! {
+                    ret = &mut connect => break ret.unwrap_or(Err(ServiceError::ConnectTimeout)),
+                    msgs = rx.recv() => match msgs {
+                        Some(msgs) => {
+                            for msg in msgs.iter() {
+                                self.cache(msg);
+                            }
+                        }
+                        None => return Err(ServiceError::PublishDone),
+                    },
+                }
+            };
+
+            match ret {
+                Ok((rtmp, sid)) => {
+                    backoff = FORWARD_BACKOFF_MIN;
+                    self.set_state(stream_key, ForwardState::Publishing, None);
+                    match self.publishing(stream_key, &mut rx, rtmp, sid).await {
+                        Err(ServiceError::PublishDone) => return Err(ServiceError::PublishDone),
+                        Err(e) => warn!("Forward to {}/{} failed: {}", self.tc_url, self.stream, e),
+                        Ok(_) => {}
+                    }
+                }
+                Err(e) => warn!("Connect to {}/{} failed: {}", self.tc_url, self.stream, e),
+            }
+
+            self.reconnects += 1;
+            self.set_state(stream_key, ForwardState::Reconnecting, None);
+            info!("Reconnect after {:?}", backoff);
+            let sleep = tokio::time::sleep(backoff);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    msgs = rx.recv() => match msgs {
+                        Some(msgs) => {
+                            for msg in msgs.iter() {
+                                self.cache(msg);
+                            }
+                        }
+                        None => return Err(ServiceError::PublishDone),
+                    },
+                }
+            }
+            backoff = (backoff * 2).min(FORWARD_BACKOFF_MAX);
+        }
+    }
+
+    async fn publishing(
+        &mut self,
+        stream_key: &str,
+        rx: &mut HubToSubsChanRx,
+        mut rtmp: RtmpClient,
+        sid: u32,
+    ) -> Result<(), ServiceError> {
+        // Start from keyframe, or any audio frame for pure audio stream
+        let mut started = false;
+        let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
+        let ret = loop {
+            tokio::select! {
+                msg = rtmp.recv_message() => {
+                    if let Err(e) = msg {
+                        break Err(ServiceError::ConnectionError(e));
+                    }
+                }
+                msgs = rx.recv() => {
+                    let msgs = match msgs {
+                        Some(msgs) => msgs,
+                        None => break Err(ServiceError::PublishDone),
+                    };
+                    let mut out = Vec::with_capacity(msgs.len() + 3);
+                    for msg in msgs.iter() {
+                        let is_header = self.cache(msg);
+                        if !started {
+                            if is_header || !self.is_start_frame(msg) {
+                                continue;
+                            }
+                            started = true;
+                            let ts = msg.timestamp().unwrap_or(0);
+                            for header in [&self.metadata, &self.video_sh, &self.audio_sh]
+                                .into_iter()
+                                .flatten()
+                            {
+                                out.push(rewrite(header, sid, ts));
+                            }
+                        }
+                        out.push(rewrite(msg, sid, msg.timestamp().unwrap_or(0)));
+                    }
+                    if !out.is_empty() {
+                        if let Err(e) = rtmp.send_messages(&out, 0, sid).await {
+                            break Err(ServiceError::ConnectionError(e));
+                        }
+                    }
+                }
+                _ = stat_report.tick() => {
+                    let _ = self.stat_tx.send(StatEvent::UpdateConn(
+                        self.uid.clone(),
+                        self.conn_stat(stream_key, Some(&mut rtmp)),
+                    ));
+                }
+            }
+        };
+        self.recv_bytes += rtmp.get_recv_bytes();
+        self.send_bytes += rtmp.get_send_bytes();
+        self.audio_count += rtmp.get_out_audio_count();
+        self.video_count += rtmp.get_out_video_count();
+        ret
+    }
+
+    // Update the cached metadata and sequence headers, return whether the msg is one of them
+    fn cache(&mut self, msg: &RtmpMessage) -> bool {
+        match msg {
+            RtmpMessage::Amf0Data { .. } if msg.is_metadata() => {
+                self.metadata = Some(msg.clone());
+                true
+            }
+            RtmpMessage::VideoData { payload, .. } if codec::is_video_sequence_header(payload) => {
+                self.video_sh = Some(msg.clone());
+                true
+            }
+            RtmpMessage::AudioData { payload, .. } if codec::is_audio_sequence_header(payload) => {
+                self.audio_sh = Some(msg.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_start_frame(&self, msg: &RtmpMessage) -> bool {
+        match msg {
+            RtmpMessage::VideoData { .. } => msg.is_key_frame(),
+            RtmpMessage::AudioData { .. } => self.video_sh.is_none(),
+            _ => false,
+        }
+    }
+
+    fn set_state(&mut self, stream_key: &str, state: ForwardState, rtmp: Option<&mut RtmpClient>) {
+        self.state = state;
+        let _ = self.stat_tx.send(StatEvent::UpdateConn(
+            self.uid.clone(),
+            self.conn_stat(stream_key, rtmp),
+        ));
+    }
+
+    fn conn_stat(&self, stream_key: &str, rtmp: Option<&mut RtmpClient>) -> ConnStat {
+        let mut conn = ConnStat::new(stream_key.to_string(), RtmpConnType::Forward);
+        conn.recv_bytes = self.recv_bytes;
+        conn.send_bytes = self.send_bytes;
+        conn.audio_count = self.audio_count;
+        conn.video_count = self.video_count;
+        if let Some(rtmp) = rtmp {
+            conn.recv_bytes += rtmp.get_recv_bytes();
+            conn.send_bytes += rtmp.get_send_bytes();
+            conn.audio_count += rtmp.get_out_audio_count();
+            conn.video_count += rtmp.get_out_video_count();
+        }
+        conn.forward = Some(ForwardStat {
+            url: format!("{}/{}", self.tc_url, self.stream),
+            state: self.state,
+            reconnects: self.reconnects,
+        });
+        conn
+    }
+}
+
+async fn connect(
+    tc_url: String,
+    stream: String,
+    uid: String,
+) -> Result<(RtmpClient, u32), ServiceError> {
+    let mut rtmp = RtmpClient::new(tc_url, stream).await?;
+    let sid = rtmp.publish(uid).await?;
+    Ok((rtmp, sid))
+}
+
+// Rewrite the stream id and timestamp for the destination
+fn rewrite(msg: &RtmpMessage, stream_id: u32, timestamp: u32) -> RtmpMessage {
+    match msg {
+        RtmpMessage::VideoData { payload, .. } => RtmpMessage::VideoData {
+            stream_id,
+            timestamp,
+            payload: payload.clone(),
+        },
+        RtmpMessage::AudioData { payload, .. } => RtmpMessage::AudioData {
+            stream_id,
+            timestamp,
+            payload: payload.clone(),
+        },
+        other => other.clone(),
+    }
+}
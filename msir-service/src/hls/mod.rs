@@ -0,0 +1,143 @@
+use crate::{
+    hls_service::HlsService, statistic::ConnToStatChanTx, stream::ConnToMgrChanTx, utils,
+    vhost::Vhosts,
+};
+use bytes::Bytes;
+use rtmp::message::request::Request;
+use std::collections::HashMap;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, Instrument};
+
+pub mod playlist;
+
+pub type HttpToHlsChanTx = mpsc::UnboundedSender<HlsEvent>;
+pub type HttpToHlsChanRx = mpsc::UnboundedReceiver<HlsEvent>;
+
+pub type QueryPlaylistResponse = oneshot::Sender<Option<String>>;
+pub type QuerySegmentResponse = oneshot::Sender<Option<Bytes>>;
+
+pub enum HlsEvent {
+    QueryPlaylist(Box<Request>, QueryPlaylistResponse),
+    QuerySegment(String, u64, QuerySegmentResponse),
+    // The packager of stream exited
+    Remove(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HlsConfig {
+    // Target duration of segment, in seconds
+    pub fragment: u32,
+    // Duration of the playlist, in seconds
+    pub window: u32,
+}
+
+pub struct HlsManager {
+    // The config of HLS by vhost
+    vhosts: Vhosts,
+    packagers: HashMap<String, HttpToHlsChanTx>,
+    hls_rx: HttpToHlsChanRx,
+    hls_tx: HttpToHlsChanTx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+}
+
+impl HlsManager {
+    pub fn new(
+        vhosts: Vhosts,
+        hls_rx: HttpToHlsChanRx,
+        hls_tx: HttpToHlsChanTx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        Self {
+            vhosts,
+            packagers: HashMap::new(),
+            hls_rx,
+            hls_tx,
+            mgr_tx,
+            stat_tx,
+        }
+    }
+
+    pub async fn run(mut self) {
+        while let Some(ev) = self.hls_rx.recv().await {
+            match ev {
+                HlsEvent::QueryPlaylist(req, ret) => self.on_query_playlist(req, ret),
+                HlsEvent::QuerySegment(stream_key, seq, ret) => {
+                    self.on_query_segment(stream_key, seq, ret)
+                }
+                HlsEvent::Remove(stream_key) => self.on_remove(stream_key),
+            }
+        }
+    }
+
+    // The vhost of request is resolved
+    fn on_query_playlist(&mut self, req: Box<Request>, ret: QueryPlaylistResponse) {
+        let stream_key = req.stream_key();
+        // Forward to the packager, start a new one if not exist or exited
+        let (req, ret) = match self.packagers.get(&stream_key) {
+            Some(tx) => match tx.send(HlsEvent::QueryPlaylist(req, ret)) {
+                Ok(_) => return,
+                Err(e) => match e.0 {
+                    HlsEvent::QueryPlaylist(req, ret) => (req, ret),
+                    _ => return,
+                },
+            },
+            None => (req, ret),
+        };
+
+        let config = match &self.vhosts.get(req.vhost.as_deref()).hls {
+            Some(config) => config.clone(),
+            None => {
+                let _ = ret.send(None);
+                return;
+            }
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.packagers.insert(stream_key.clone(), tx);
+
+        let uid = utils::gen_uid();
+        debug!("Start hls packager {} for {}", uid, stream_key);
+        let mut hls = HlsService::new(
+            uid.clone(),
+            config,
+            rx,
+            self.hls_tx.clone(),
+            self.mgr_tx.clone(),
+            self.stat_tx.clone(),
+        );
+        tokio::spawn(
+            async move {
+                if let Err(e) = hls.run(*req, ret).await {
+                    error!("Failed to transfer; error={}", e);
+                }
+            }
+            .instrument(tracing::info_span!("HLS-CONN", uid)),
+        );
+    }
+
+    fn on_query_segment(&mut self, stream_key: String, seq: u64, ret: QuerySegmentResponse) {
+        match self.packagers.get(&stream_key) {
+            Some(tx) => {
+                if let Err(e) = tx.send(HlsEvent::QuerySegment(stream_key, seq, ret)) {
+                    if let HlsEvent::QuerySegment(_, _, ret) = e.0 {
+                        let _ = ret.send(None);
+                    }
+                }
+            }
+            None => {
+                let _ = ret.send(None);
+            }
+        }
+    }
+
+    fn on_remove(&mut self, stream_key: String) {
+        // A new packager may have been started for the same stream
+        if let Some(tx) = self.packagers.get(&stream_key) {
+            if tx.is_closed() {
+                self.packagers.remove(&stream_key);
+                debug!("Remove hls packager for {}", stream_key);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use bytes::Bytes;
+use std::{collections::VecDeque, fmt::Write};
+
+#[derive(Debug)]
+pub struct Segment {
+    pub seq: u64,
+    // In ms
+    pub duration: u32,
+    pub data: Bytes,
+}
+
+#[derive(Debug)]
+pub struct Playlist {
+    segments: VecDeque<Segment>,
+    // In ms
+    window: u32,
+    next_seq: u64,
+}
+
+impl Playlist {
+    pub fn new(window: u32) -> Self {
+        Self {
+            segments: VecDeque::new(),
+            window,
+            next_seq: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn push(&mut self, duration: u32, data: Bytes) {
+        self.segments.push_back(Segment {
+            seq: self.next_seq,
+            duration,
+            data,
+        });
+        self.next_seq += 1;
+
+        // Slide the window, but keep the newest segment at least
+        let mut total: u32 = self.segments.iter().map(|s| s.duration).sum();
+        while self.segments.len() > 1 {
+            let front = self.segments.front().unwrap().duration;
+            if total - front < self.window {
+                break;
+            }
+            total -= front;
+            self.segments.pop_front();
+        }
+    }
+
+    pub fn segment(&self, seq: u64) -> Option<Bytes> {
+        self.segments
+            .iter()
+            .find(|s| s.seq == seq)
+            .map(|s| s.data.clone())
+    }
+
+    // The uri of segment is relative to the playlist, e.g. /live/stream.m3u8 => /live/stream/0.ts
+    // The query is appended to the uri, e.g. ?vhost=a.com
+    pub fn m3u8(&self, stream: &str, query: &str) -> String {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|s| s.duration.div_ceil(1000))
+            .max()
+            .unwrap_or(0);
+        let mut m3u8 = String::with_capacity(128 + self.segments.len() * 32);
+        m3u8.push_str("#EXTM3U\n");
+        m3u8.push_str("#EXT-X-VERSION:3\n");
+        let _ = writeln!(
+            m3u8,
+            "#EXT-X-MEDIA-SEQUENCE:{}",
+            self.segments.front().map(|s| s.seq).unwrap_or(0)
+        );
+        let _ = writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target_duration);
+        for s in self.segments.iter() {
+            let _ = writeln!(m3u8, "#EXTINF:{:.3},", s.duration as f64 / 1000.0);
+            let _ = writeln!(m3u8, "{}/{}.ts{}", stream, s.seq, query);
+        }
+        m3u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(playlist: &Playlist) -> Vec<u64> {
+        playlist.segments.iter().map(|s| s.seq).collect()
+    }
+
+    #[test]
+    fn test_window() {
+        let mut playlist = Playlist::new(10000);
+        for _ in 0..3 {
+            playlist.push(4000, Bytes::new());
+        }
+        assert_eq!(seqs(&playlist), [0, 1, 2]);
+        // The rest still covers the window
+        playlist.push(4000, Bytes::new());
+        assert_eq!(seqs(&playlist), [1, 2, 3]);
+        assert!(playlist.segment(0).is_none());
+
+        // The newest segment is kept even if longer than the window
+        playlist.push(30000, Bytes::from_static(b"ts"));
+        assert_eq!(seqs(&playlist), [4]);
+        assert_eq!(playlist.segment(4), Some(Bytes::from_static(b"ts")));
+    }
+
+    #[test]
+    fn test_m3u8() {
+        let mut playlist = Playlist::new(5000);
+        assert_eq!(
+            playlist.m3u8("test", ""),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-TARGETDURATION:0\n"
+        );
+        playlist.push(8000, Bytes::new());
+        playlist.push(3001, Bytes::new());
+        playlist.push(2500, Bytes::new());
+        assert_eq!(
+            playlist.m3u8("test", "?vhost=a.com"),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:3\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-TARGETDURATION:4\n\
+             #EXTINF:3.001,\n\
+             test/1.ts?vhost=a.com\n\
+             #EXTINF:2.500,\n\
+             test/2.ts?vhost=a.com\n"
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use bytes::Bytes;
+use mpegts::TsMuxer;
+use rtmp::{codec, message::request::Request, message::RtmpMessage};
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tracing::{info, trace, warn};
+use url::form_urlencoded;
+
+use crate::{
+    error::ServiceError,
+    hls::{
+        playlist::Playlist, HlsConfig, HlsEvent, HttpToHlsChanRx, HttpToHlsChanTx,
+        QueryPlaylistResponse,
+    },
+    statistic::{ConnStat, ConnToStatChanTx, StatEvent},
+    stream::{ConnToMgrChanTx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    CONN_PRINT_INTVAL, HLS_IDLE_TIMEOUT,
+};
+
+pub struct HlsService {
+    uid: String,
+    config: HlsConfig,
+    query_rx: HttpToHlsChanRx,
+    hls_tx: HttpToHlsChanTx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+
+    ts_enc: TsMuxer,
+    playlist: Playlist,
+    segment: Vec<u8>,
+    segment_start: Option<u32>,
+    segment_end: u32,
+    // With the query of segment uri
+    waiters: Vec<(QueryPlaylistResponse, String)>,
+    last_access: Instant,
+    send_bytes: u64,
+}
+
+impl HlsService {
+    pub fn new(
+        uid: String,
+        config: HlsConfig,
+        query_rx: HttpToHlsChanRx,
+        hls_tx: HttpToHlsChanTx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+    ) -> Self {
+        let playlist = Playlist::new(config.window * 1000);
+        Self {
+            uid,
+            config,
+            query_rx,
+            hls_tx,
+            mgr_tx,
+            stat_tx,
+            ts_enc: TsMuxer::new(),
+            playlist,
+            segment: Vec::new(),
+            segment_start: None,
+            segment_end: 0,
+            waiters: Vec::new(),
+            last_access: Instant::now(),
+            send_bytes: 0,
+        }
+    }
+
+    pub async fn run(
+        &mut self,
+        req: Request,
+        waiter: QueryPlaylistResponse,
+    ) -> Result<(), ServiceError> {
+        info!(
+            "Identify {:?} app:{} stream:{} param:{}",
+            req.conn_type,
+            req.tc_url.path(),
+            req.stream(),
+            req.tc_url.query().unwrap_or(""),
+        );
+
+        // Response the first playlist request after the first segment is ready
+        self.waiters.push((waiter, segment_query(&req)));
+
+        let ret = match self.register(&req).await {
+            Ok(token) => {
+                let ret = self.playing(&req, token).await;
+                self.unregister(&req).await;
+                ret
+            }
+            Err(e) => Err(e),
+        };
+
+        // Close the query channel first, so that manager can start a new packager
+        self.query_rx.close();
+        let _ = self.hls_tx.send(HlsEvent::Remove(req.stream_key()));
+
+        ret
+    }
+
+    async fn register(&self, req: &Request) -> Result<Token, ServiceError> {
+        let stream_key = req.stream_key();
+        let (reg_tx, reg_rx) = oneshot::channel();
+        let msg = StreamEvent::Register(RegisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.clone(),
+            role: RoleType::Subscriber,
+            ret: reg_tx,
+            kick: None,
+        });
+        if self.mgr_tx.send(msg).is_err() {
+            return Err(ServiceError::RegisterFailed(
+                "send register event failed".to_string(),
+            ));
+        }
+
+        match reg_rx.await {
+            Ok(token) => {
+                if let Token::Failure(e) = token {
+                    return Err(ServiceError::RegisterFailed(e.to_string()));
+                }
+                let _ = self.stat_tx.send(StatEvent::CreateConn(
+                    self.uid.clone(),
+                    ConnStat::new(stream_key, req.conn_type.clone()),
+                ));
+                Ok(token)
+            }
+            Err(_) => Err(ServiceError::RegisterFailed(
+                "recv register ret failed: ".to_string(),
+            )),
+        }
+    }
+
+    async fn unregister(&mut self, req: &Request) {
+        let stream_key = req.stream_key();
+        let msg = StreamEvent::Unregister(UnregisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.clone(),
+            role: RoleType::Subscriber,
+        });
+        if let Err(e) = self.mgr_tx.send(msg) {
+            warn!("send unregister event failed: {}", e);
+        }
+        let _ = self.stat_tx.send(StatEvent::DeleteConn(self.uid.clone(), {
+            let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
+            conn.recv_bytes = 0;
+            conn.send_bytes = self.send_bytes;
+            conn.audio_count = self.ts_enc.get_audio_count();
+            conn.video_count = self.ts_enc.get_video_count();
+            conn
+        }));
+    }
+
+    async fn playing(&mut self, req: &Request, token: Token) -> Result<(), ServiceError> {
+        let mut rx = match token {
+            Token::SubscriberToken(rx) => rx,
+            _ => return Err(ServiceError::InvalidToken),
+        };
+        let stream_key = req.stream_key();
+        let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
+        loop {
+            tokio::select! {
+                msgs = rx.recv() => {
+                    match msgs {
+                        Some(msgs) => {
+                            for msg in msgs.iter() {
+                                self.on_frame(msg, req.stream())?;
+                            }
+                        }
+                        None => return Err(ServiceError::PublishDone)
+                    }
+                }
+                ev = self.query_rx.recv() => {
+                    match ev {
+                        Some(ev) => self.on_query(ev, req.stream()),
+                        None => return Ok(()),
+                    }
+                }
+                _ = stat_report.tick() => {
+                    if self.last_access.elapsed() > HLS_IDLE_TIMEOUT {
+                        info!("No hls request in {:?}, stop packager", HLS_IDLE_TIMEOUT);
+                        return Ok(());
+                    }
+                    let _ = self.stat_tx.send(StatEvent::UpdateConn(self.uid.clone(), {
+                        let mut conn = ConnStat::new(stream_key.clone(), req.conn_type.clone());
+                        conn.recv_bytes = 0;
+                        conn.send_bytes = self.send_bytes;
+                        conn.audio_count = self.ts_enc.get_audio_count();
+                        conn.video_count = self.ts_enc.get_video_count();
+                        conn
+                    }));
+                }
+            }
+        }
+    }
+
+    fn on_frame(&mut self, msg: &RtmpMessage, stream: &str) -> Result<(), ServiceError> {
+        let ts = match msg.timestamp() {
+            Some(ts) => ts,
+            None => return Ok(()),
+        };
+        if self.is_segment_boundary(msg) {
+            if let Some(start) = self.segment_start {
+                // Timestamp may jump back when republish
+                if ts < start || ts - start >= self.config.fragment * 1000 {
+                    let end = if ts < start { self.segment_end } else { ts };
+                    self.reap_segment(end - start, stream);
+                }
+            }
+            if self.segment_start.is_none() {
+                self.segment_start = Some(ts);
+                self.ts_enc.insert_pat_pmt();
+            }
+        }
+        let data = self.ts_enc.write_tags(std::slice::from_ref(msg))?;
+        // Drop the frames before the first keyframe
+        if self.segment_start.is_some() {
+            self.segment.extend_from_slice(&data);
+            self.segment_end = ts;
+        }
+        Ok(())
+    }
+
+    // Cut segment at keyframe, or at any audio frame for pure audio stream
+    fn is_segment_boundary(&self, msg: &RtmpMessage) -> bool {
+        match msg {
+            RtmpMessage::VideoData { .. } => msg.is_key_frame(),
+            RtmpMessage::AudioData { payload, .. } => {
+                !self.ts_enc.has_video() && !codec::is_audio_sequence_header(payload)
+            }
+            _ => false,
+        }
+    }
+
+    fn reap_segment(&mut self, duration: u32, stream: &str) {
+        let data = Bytes::from(std::mem::take(&mut self.segment));
+        trace!(
+            "Reap hls segment duration {}ms size {}",
+            duration,
+            data.len()
+        );
+        self.playlist.push(duration, data);
+        self.segment_start = None;
+
+        if !self.waiters.is_empty() {
+            for (waiter, query) in self.waiters.drain(..) {
+                let _ = waiter.send(Some(self.playlist.m3u8(stream, &query)));
+            }
+        }
+    }
+
+    fn on_query(&mut self, ev: HlsEvent, stream: &str) {
+        self.last_access = Instant::now();
+        match ev {
+            HlsEvent::QueryPlaylist(req, ret) => {
+                let query = segment_query(&req);
+                if self.playlist.is_empty() {
+                    self.waiters.push((ret, query));
+                } else {
+                    let _ = ret.send(Some(self.playlist.m3u8(stream, &query)));
+                }
+            }
+            HlsEvent::QuerySegment(_, seq, ret) => {
+                let segment = self.playlist.segment(seq);
+                if let Some(data) = &segment {
+                    self.send_bytes += data.len() as u64;
+                }
+                let _ = ret.send(segment);
+            }
+            HlsEvent::Remove(_) => {}
+        }
+    }
+}
+
+// The segment uri carries the vhost to find the packager, and the token of the
+// playlist request to pass the auth
+fn segment_query(req: &Request) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    if let Some(vhost) = &req.vhost {
+        query.append_pair("vhost", vhost);
+    }
+    for key in ["token", "expire"] {
+        if let Some(value) = req.param(key) {
+            query.append_pair(key, value);
+        }
+    }
+    match query.finish() {
+        query if query.is_empty() => query,
+        query => format!("?{}", query),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn service() -> HlsService {
+        let (_, query_rx) = mpsc::unbounded_channel();
+        let (hls_tx, _) = mpsc::unbounded_channel();
+        let (mgr_tx, _) = mpsc::unbounded_channel();
+        let (stat_tx, _) = mpsc::unbounded_channel();
+        let config = HlsConfig {
+            fragment: 2,
+            window: 60,
+        };
+        HlsService::new("uid".to_string(), config, query_rx, hls_tx, mgr_tx, stat_tx)
+    }
+
+    fn video(timestamp: u32, payload: &[u8]) -> RtmpMessage {
+        RtmpMessage::VideoData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    fn audio(timestamp: u32, payload: &[u8]) -> RtmpMessage {
+        RtmpMessage::AudioData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    fn avc_sequence_header() -> RtmpMessage {
+        video(
+            0,
+            &[
+                0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x05, 0x67,
+                0x64, 0x00, 0x1f, 0xac, 0x01, 0x00, 0x04, 0x68, 0xee, 0x3c, 0x80,
+            ],
+        )
+    }
+
+    fn keyframe(timestamp: u32) -> RtmpMessage {
+        video(
+            timestamp,
+            &[
+                0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x65, 0x88,
+            ],
+        )
+    }
+
+    fn interframe(timestamp: u32) -> RtmpMessage {
+        video(
+            timestamp,
+            &[
+                0x27, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x41, 0x9a,
+            ],
+        )
+    }
+
+    fn durations(hls: &HlsService) -> Vec<String> {
+        hls.playlist
+            .m3u8("test", "")
+            .lines()
+            .filter_map(|l| l.strip_prefix("#EXTINF:"))
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_segment_boundary() {
+        let mut hls = service();
+        let aac_sh = audio(0, &[0xaf, 0x00, 0x12, 0x10]);
+        let aac = audio(0, &[0xaf, 0x01, 0x21]);
+        // Any audio frame for pure audio stream, except the sequence header
+        assert!(!hls.is_segment_boundary(&aac_sh));
+        assert!(hls.is_segment_boundary(&aac));
+
+        hls.on_frame(&avc_sequence_header(), "test").unwrap();
+        assert!(!hls.is_segment_boundary(&aac));
+        assert!(!hls.is_segment_boundary(&avc_sequence_header()));
+        assert!(!hls.is_segment_boundary(&interframe(0)));
+        assert!(hls.is_segment_boundary(&keyframe(0)));
+    }
+
+    #[test]
+    fn test_segment_cutting() {
+        let mut hls = service();
+        hls.on_frame(&avc_sequence_header(), "test").unwrap();
+        // Dropped before the first keyframe
+        hls.on_frame(&interframe(500), "test").unwrap();
+        assert!(hls.segment.is_empty());
+
+        hls.on_frame(&keyframe(1000), "test").unwrap();
+        assert_eq!(hls.segment_start, Some(1000));
+        hls.on_frame(&interframe(1500), "test").unwrap();
+        // Shorter than the fragment
+        hls.on_frame(&keyframe(2000), "test").unwrap();
+        assert!(hls.playlist.is_empty());
+        hls.on_frame(&keyframe(3000), "test").unwrap();
+        assert_eq!(durations(&hls), ["2.000,"]);
+        assert_eq!(hls.segment_start, Some(3000));
+
+        // Republished, the timestamp jumps back
+        hls.on_frame(&interframe(3500), "test").unwrap();
+        hls.on_frame(&keyframe(100), "test").unwrap();
+        assert_eq!(durations(&hls), ["2.000,", "0.500,"]);
+        assert_eq!(hls.segment_start, Some(100));
+    }
+
+    #[test]
+    fn test_segment_query() {
+        let mut req = Request::parse_from(
+            "http://127.0.0.1/live/test.m3u8?token=ab&expire=1&t=1".to_string(),
+        )
+        .unwrap();
+        assert_eq!(segment_query(&req), "?token=ab&expire=1");
+        req.vhost = Some("a.com".to_string());
+        assert_eq!(segment_query(&req), "?vhost=a.com&token=ab&expire=1");
+        req.params.clear();
+        assert_eq!(segment_query(&req), "?vhost=a.com");
+        req.vhost = None;
+        assert_eq!(segment_query(&req), "");
+
+        // Escaped, not to break the uri or the playlist
+        req.params
+            .insert("token".to_string(), "a&b #%+\n#EXT".to_string());
+        assert_eq!(segment_query(&req), "?token=a%26b+%23%25%2B%0A%23EXT");
+    }
+}
@@ -0,0 +1,306 @@
+use crate::auth::{Auth, AuthError};
+use hyper::{client::HttpConnector, Body, Client, Method, Request as HttpRequest, StatusCode};
+use rtmp::message::request::Request;
+use serde_derive::Serialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use thiserror::Error;
+use tracing::{debug, warn, Instrument};
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("Hook {0} is rejected with status {1}")]
+    Rejected(String, StatusCode),
+
+    #[error("Hook {0} is timeout")]
+    Timeout(String),
+
+    #[error("Hook request error: {0}")]
+    HttpError(#[from] hyper::Error),
+
+    #[error("Hook build request error: {0}")]
+    InvalidRequest(#[from] hyper::http::Error),
+
+    #[error("Hook encode body error: {0}")]
+    EncodeError(#[from] serde_json::Error),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(#[from] AuthError),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HookConfig {
+    pub on_publish: Option<String>,
+    pub on_unpublish: Option<String>,
+    pub on_play: Option<String>,
+    pub on_stop: Option<String>,
+    pub timeout: Duration,
+    // Retry times when the request failed or timeout, non-2xx response is not retried
+    pub retries: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct HookBody<'a> {
+    action: &'a str,
+    client_id: &'a str,
+    ip: &'a str,
+    vhost: &'a str,
+    app: &'a str,
+    stream: &'a str,
+    param: &'a str,
+    params: &'a HashMap<String, String>,
+    conn_type: &'a str,
+}
+
+#[derive(Clone, Default)]
+pub struct Hooks {
+    config: Arc<HookConfig>,
+    client: Client<HttpConnector>,
+    // Checked before calling the hooks
+    auth: Auth,
+}
+
+impl Hooks {
+    pub fn new(config: HookConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            client: Client::new(),
+            auth: Auth::default(),
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub async fn on_publish(&self, uid: &str, req: &Request) -> Result<(), HookError> {
+        self.auth.check_publish(req)?;
+        match &self.config.on_publish {
+            Some(url) => self.call(url, "on_publish", uid, req).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn on_play(&self, uid: &str, req: &Request) -> Result<(), HookError> {
+        self.auth.check_play(req)?;
+        match &self.config.on_play {
+            Some(url) => self.call(url, "on_play", uid, req).await,
+            None => Ok(()),
+        }
+    }
+
+    // Without the hook, e.g. for each hls request
+    pub fn check_play_auth(&self, req: &Request) -> Result<(), AuthError> {
+        self.auth.check_play(req)
+    }
+
+    // Notification only, do not block the caller
+    pub fn on_unpublish(&self, uid: &str, req: &Request) {
+        if let Some(url) = &self.config.on_unpublish {
+            self.notify(url, "on_unpublish", uid, req);
+        }
+    }
+
+    pub fn on_stop(&self, uid: &str, req: &Request) {
+        if let Some(url) = &self.config.on_stop {
+            self.notify(url, "on_stop", uid, req);
+        }
+    }
+
+    fn notify(&self, url: &str, action: &'static str, uid: &str, req: &Request) {
+        let body = match encode_body(action, uid, req) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Hook {} encode body failed: {}", action, e);
+                return;
+            }
+        };
+        let hooks = self.clone();
+        let url = url.to_string();
+        tokio::spawn(
+            async move {
+                if let Err(e) = hooks.post(&url, action, body).await {
+                    warn!("Hook {} failed: {}", action, e);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    async fn call(
+        &self,
+        url: &str,
+        action: &str,
+        uid: &str,
+        req: &Request,
+    ) -> Result<(), HookError> {
+        let body = encode_body(action, uid, req)?;
+        self.post(url, action, body).await
+    }
+
+    async fn post(&self, url: &str, action: &str, body: String) -> Result<(), HookError> {
+        let mut retries = self.config.retries;
+        loop {
+            let request = HttpRequest::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.clone()))?;
+            let err = match tokio::time::timeout(self.config.timeout, self.client.request(request))
+                .await
+            {
+                Ok(Ok(resp)) => {
+                    debug!("Hook {} {} response {}", action, url, resp.status());
+                    return match resp.status().is_success() {
+                        true => Ok(()),
+                        false => Err(HookError::Rejected(action.to_string(), resp.status())),
+                    };
+                }
+                Ok(Err(e)) => HookError::HttpError(e),
+                Err(_) => HookError::Timeout(action.to_string()),
+            };
+            if retries == 0 {
+                return Err(err);
+            }
+            retries -= 1;
+            warn!("Hook {} {} failed: {}, retry", action, url, err);
+        }
+    }
+}
+
+fn encode_body(action: &str, uid: &str, req: &Request) -> Result<String, HookError> {
+    let app = req
+        .tc_url
+        .path_segments()
+        .and_then(|mut s| s.next())
+        .unwrap_or("");
+    Ok(serde_json::to_string(&HookBody {
+        action,
+        client_id: uid,
+        ip: &req.ip().unwrap_or_default(),
+        vhost: req.vhost.as_deref().unwrap_or(""),
+        app,
+        stream: req.stream(),
+        param: req.tc_url.query().unwrap_or(""),
+        params: &req.params,
+        conn_type: req.conn_type.as_str(),
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+    use rtmp::connection::RtmpConnType;
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::sync::mpsc;
+
+    // Response by the path, e.g. /403, and delay the first N requests of /slow/N
+    async fn stub() -> (String, Arc<AtomicUsize>, mpsc::UnboundedReceiver<String>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let counter = count.clone();
+        let make_svc = make_service_fn(move |_| {
+            let (count, tx) = (counter.clone(), tx.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: HttpRequest<Body>| {
+                    let (count, tx) = (count.clone(), tx.clone());
+                    async move {
+                        let n = count.fetch_add(1, Ordering::SeqCst);
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let _ = tx.send(String::from_utf8_lossy(&body).to_string());
+                        let status = match path.strip_prefix("/slow/") {
+                            Some(delayed) => {
+                                if n < delayed.parse().unwrap() {
+                                    tokio::time::sleep(Duration::from_secs(1)).await;
+                                }
+                                StatusCode::OK
+                            }
+                            None => path[1..].parse().unwrap(),
+                        };
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, count, rx)
+    }
+
+    fn hooks(on_play: String, retries: u32) -> Hooks {
+        Hooks::new(HookConfig {
+            on_play: Some(on_play),
+            timeout: Duration::from_millis(200),
+            retries,
+            ..Default::default()
+        })
+    }
+
+    fn request() -> Request {
+        let mut req =
+            Request::parse_from("http://127.0.0.1/live/test.flv?token=abc".to_string()).unwrap();
+        req.client.remote_addr = Some("10.0.0.1:5000".parse().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn test_body() {
+        let (url, _, mut rx) = stub().await;
+        hooks(format!("{}/200", url), 0)
+            .on_play("uid", &request())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+        assert_eq!(body["action"], "on_play");
+        assert_eq!(body["client_id"], "uid");
+        assert_eq!(body["ip"], "10.0.0.1");
+        assert_eq!(body["app"], "live");
+        assert_eq!(body["stream"], "test");
+        assert_eq!(body["params"]["token"], "abc");
+        assert_eq!(body["conn_type"], RtmpConnType::FlvPlay.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_rejected() {
+        let (url, count, _rx) = stub().await;
+        let ret = hooks(format!("{}/403", url), 2)
+            .on_play("uid", &request())
+            .await;
+        assert!(matches!(
+            ret,
+            Err(HookError::Rejected(_, StatusCode::FORBIDDEN))
+        ));
+        // Not retried
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_timeout() {
+        let (url, count, _rx) = stub().await;
+        hooks(format!("{}/slow/2", url), 2)
+            .on_play("uid", &request())
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let (url, _, _rx) = stub().await;
+        let ret = hooks(format!("{}/slow/2", url), 1)
+            .on_play("uid", &request())
+            .await;
+        assert!(matches!(ret, Err(HookError::Timeout(_))));
+    }
+}
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use futures::channel::mpsc::UnboundedSender;
 use httpflv::FlvTransmuxer;
 use hyper::{http::HeaderValue, Body, Request as HttpRequest};
@@ -9,17 +10,20 @@ use tracing::{info, trace, warn};
 use crate::{
     error::ServiceError,
     statistic::{ConnStat, ConnToStatChanTx, StatEvent},
-    stream::{ConnToMgrChanTx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    stream::{ConnToMgrChanTx, KickRx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    utils,
+    vhost::Vhosts,
     CONN_PRINT_INTVAL, PERF_MERGE_SEND_MSG,
 };
 
-type FlvRespChanTx = UnboundedSender<io::Result<Vec<u8>>>;
+type FlvRespChanTx = UnboundedSender<io::Result<Bytes>>;
 
 pub struct HttpFlvService {
     uid: String,
     response: FlvRespChanTx,
     mgr_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
 
     flv_enc: FlvTransmuxer,
 }
@@ -30,18 +34,24 @@ impl HttpFlvService {
         response: FlvRespChanTx,
         mgr_tx: ConnToMgrChanTx,
         stat_tx: ConnToStatChanTx,
+        vhosts: Vhosts,
     ) -> Self {
+        // Most of the HTTP-FLV players only know HEVC with codec id 12
+        let mut flv_enc = FlvTransmuxer::new();
+        flv_enc.set_legacy_hevc(true);
         Self {
             uid,
             response,
             mgr_tx,
             stat_tx,
-            flv_enc: FlvTransmuxer::new(),
+            vhosts,
+            flv_enc,
         }
     }
 
     pub async fn run(&mut self, req: HttpRequest<Body>) -> Result<(), ServiceError> {
-        let req = Request::parse_from(format!(
+        let client = utils::http_client_info(&req);
+        let mut req = Request::parse_from(format!(
             "http://{}{}",
             req.headers()
                 .get("Host")
@@ -50,6 +60,11 @@ impl HttpFlvService {
                 .unwrap_or("0.0.0.0"),
             req.uri()
         ))?;
+        req.client = client;
+        let vhost = self.vhosts.resolve(&mut req);
+        if !vhost.flv {
+            return Err(ServiceError::Disabled("HTTP-FLV"));
+        }
 
         info!(
             "Identify {:?} app:{} stream:{} param:{}",
@@ -59,9 +74,19 @@ impl HttpFlvService {
             req.tc_url.query().unwrap_or(""),
         );
 
-        let token = self.register(&req).await?;
+        let (token, kick) = match self.register(&req).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                // The HTTP server responses 403 by the error
+                if e.is_unauthorized() {
+                    let err = io::Error::new(io::ErrorKind::PermissionDenied, e.to_string());
+                    let _ = self.response.unbounded_send(Err(err));
+                }
+                return Err(e);
+            }
+        };
 
-        let ret = self.playing(&req, token).await;
+        let ret = self.playing(&req, token, kick).await;
 
         self.unregister(&req).await;
 
@@ -69,14 +94,21 @@ impl HttpFlvService {
         Ok(())
     }
 
-    async fn register(&self, req: &Request) -> Result<Token, ServiceError> {
-        let stream_key = req.app_stream();
+    async fn register(&self, req: &Request) -> Result<(Token, KickRx), ServiceError> {
+        self.vhosts
+            .get(req.vhost.as_deref())
+            .hooks
+            .on_play(&self.uid, req)
+            .await?;
+        let stream_key = req.stream_key();
         let (reg_tx, reg_rx) = oneshot::channel();
+        let (kick_tx, kick_rx) = oneshot::channel();
         let msg = StreamEvent::Register(RegisterEv {
             uid: self.uid.clone(),
             stream_key: stream_key.clone(),
             role: RoleType::Subscriber,
             ret: reg_tx,
+            kick: Some(kick_tx),
         });
         if let Err(_) = self.mgr_tx.send(msg) {
             return Err(ServiceError::RegisterFailed(
@@ -89,11 +121,12 @@ impl HttpFlvService {
                 if let Token::Failure(e) = token {
                     return Err(ServiceError::RegisterFailed(e.to_string()));
                 }
-                let _ = self.stat_tx.send(StatEvent::CreateConn(
-                    self.uid.clone(),
-                    ConnStat::new(stream_key, req.conn_type.clone()),
-                ));
-                Ok(token)
+                let _ = self.stat_tx.send(StatEvent::CreateConn(self.uid.clone(), {
+                    let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
+                    conn.client = Some(req.client.clone());
+                    conn
+                }));
+                Ok((token, kick_rx))
             }
             Err(_) => Err(ServiceError::RegisterFailed(
                 "recv register ret failed: ".to_string(),
@@ -102,7 +135,7 @@ impl HttpFlvService {
     }
 
     async fn unregister(&mut self, req: &Request) {
-        let stream_key = req.app_stream();
+        let stream_key = req.stream_key();
         let msg = StreamEvent::Unregister(UnregisterEv {
             uid: self.uid.clone(),
             stream_key: stream_key.clone(),
@@ -111,6 +144,10 @@ impl HttpFlvService {
         if let Err(e) = self.mgr_tx.send(msg) {
             warn!("send unregister event failed: {}", e);
         }
+        self.vhosts
+            .get(req.vhost.as_deref())
+            .hooks
+            .on_stop(&self.uid, req);
         let _ = self.stat_tx.send(StatEvent::DeleteConn(self.uid.clone(), {
             let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
             conn.recv_bytes = 0;
@@ -121,36 +158,53 @@ impl HttpFlvService {
         }));
     }
 
-    async fn playing(&mut self, req: &Request, token: Token) -> Result<(), ServiceError> {
+    async fn playing(
+        &mut self,
+        req: &Request,
+        token: Token,
+        mut kick: KickRx,
+    ) -> Result<(), ServiceError> {
         let mut rx = match token {
             Token::SubscriberToken(rx) => rx,
             _ => return Err(ServiceError::InvalidToken),
         };
-        let mut merge_msgs = Vec::with_capacity(128);
+        let mut merge_batches = Vec::with_capacity(16);
         let mut merge_size = 0;
         let mut start_ts = 0;
-        let stream_key = req.app_stream();
+        let stream_key = req.stream_key();
         let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
         loop {
             tokio::select! {
+                _ = &mut kick => {
+                    info!("Kicked");
+                    return Ok(());
+                }
                 msgs = rx.recv() => {
                     match msgs {
                         Some(msgs) => {
                             let mut cur_ts = 0;
                             let mut has_key_frame = false;
-                            for msg in msgs {
+                            for msg in msgs.iter() {
                                 if !has_key_frame {
                                     has_key_frame = msg.is_key_frame();
                                 }
                                 cur_ts = msg.timestamp().unwrap_or(0);
                                 merge_size += msg.len().unwrap_or(0);
-                                merge_msgs.push(msg);
                             }
+                            merge_batches.push(msgs);
                             // Merge-send msgs to player in PERF_MERGE_SEND_MSG for improve performance
                             if cur_ts >= (start_ts + PERF_MERGE_SEND_MSG) || cur_ts == 0 || cur_ts < start_ts || has_key_frame {
-                                trace!("Merged send msgs len {} total_size {}", merge_msgs.len(), merge_size);
-                                self.response.start_send(Ok(self.flv_enc.write_tags(&merge_msgs, merge_size)?))?;
-                                merge_msgs.clear();
+                                trace!("Merged send batches len {} total_size {}", merge_batches.len(), merge_size);
+                                // The FLV tags are encoded once by the Hub batch and shared by the players
+                                let legacy_hevc = self.flv_enc.legacy_hevc();
+                                let tags = merge_batches
+                                    .iter()
+                                    .map(|b| b.flv_tags(legacy_hevc))
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                for chunk in self.flv_enc.write_encoded(tags) {
+                                    self.response.start_send(Ok(chunk))?;
+                                }
+                                merge_batches.clear();
                                 start_ts = cur_ts;
                                 merge_size = 0;
                             }
@@ -0,0 +1,328 @@
+use futures::channel::mpsc::UnboundedSender;
+use hyper::{http::HeaderValue, Body, Request as HttpRequest};
+use mpegts::TsMuxer;
+use rtmp::message::request::Request;
+use std::io;
+use tokio::sync::oneshot;
+use tracing::{info, trace, warn};
+
+use crate::{
+    error::ServiceError,
+    statistic::{ConnStat, ConnToStatChanTx, StatEvent},
+    stream::{ConnToMgrChanTx, KickRx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    utils,
+    vhost::Vhosts,
+    CONN_PRINT_INTVAL, PERF_MERGE_SEND_MSG,
+};
+
+type TsRespChanTx = UnboundedSender<io::Result<Vec<u8>>>;
+
+pub struct HttpTsService {
+    uid: String,
+    response: TsRespChanTx,
+    mgr_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
+
+    ts_enc: TsMuxer,
+}
+
+impl HttpTsService {
+    pub fn new(
+        uid: String,
+        response: TsRespChanTx,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+        vhosts: Vhosts,
+    ) -> Self {
+        Self {
+            uid,
+            response,
+            mgr_tx,
+            stat_tx,
+            vhosts,
+            ts_enc: TsMuxer::new(),
+        }
+    }
+
+    pub async fn run(&mut self, req: HttpRequest<Body>) -> Result<(), ServiceError> {
+        let client = utils::http_client_info(&req);
+        let mut req = Request::parse_from(format!(
+            "http://{}{}",
+            req.headers()
+                .get("Host")
+                .unwrap_or(&HeaderValue::from_static("0.0.0.0"))
+                .to_str()
+                .unwrap_or("0.0.0.0"),
+            req.uri()
+        ))?;
+        req.client = client;
+        self.vhosts.resolve(&mut req);
+
+        info!(
+            "Identify {:?} app:{} stream:{} param:{}",
+            req.conn_type,
+            req.tc_url.path(),
+            req.stream(),
+            req.tc_url.query().unwrap_or(""),
+        );
+
+        let (token, kick) = match self.register(&req).await {
+            Ok(ret) => ret,
+            Err(e) => {
+                // The HTTP server responses 403 by the error
+                if e.is_unauthorized() {
+                    let err = io::Error::new(io::ErrorKind::PermissionDenied, e.to_string());
+                    let _ = self.response.unbounded_send(Err(err));
+                }
+                return Err(e);
+            }
+        };
+
+        let ret = self.playing(&req, token, kick).await;
+
+        self.unregister(&req).await;
+
+        ret?;
+        Ok(())
+    }
+
+    async fn register(&self, req: &Request) -> Result<(Token, KickRx), ServiceError> {
+        self.vhosts
+            .get(req.vhost.as_deref())
+            .hooks
+            .on_play(&self.uid, req)
+            .await?;
+        let stream_key = req.stream_key();
+        let (reg_tx, reg_rx) = oneshot::channel();
+        let (kick_tx, kick_rx) = oneshot::channel();
+        let msg = StreamEvent::Register(RegisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.clone(),
+            role: RoleType::Subscriber,
+            ret: reg_tx,
+            kick: Some(kick_tx),
+        });
+        if self.mgr_tx.send(msg).is_err() {
+            return Err(ServiceError::RegisterFailed(
+                "send register event failed".to_string(),
+            ));
+        }
+
+        match reg_rx.await {
+            Ok(token) => {
+                if let Token::Failure(e) = token {
+                    return Err(ServiceError::RegisterFailed(e.to_string()));
+                }
+                let _ = self.stat_tx.send(StatEvent::CreateConn(self.uid.clone(), {
+                    let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
+                    conn.client = Some(req.client.clone());
+                    conn
+                }));
+                Ok((token, kick_rx))
+            }
+            Err(_) => Err(ServiceError::RegisterFailed(
+                "recv register ret failed: ".to_string(),
+            )),
+        }
+    }
+
+    async fn unregister(&mut self, req: &Request) {
+        let stream_key = req.stream_key();
+        let msg = StreamEvent::Unregister(UnregisterEv {
+            uid: self.uid.clone(),
+            stream_key: stream_key.clone(),
+            role: RoleType::Subscriber,
+        });
+        if let Err(e) = self.mgr_tx.send(msg) {
+            warn!("send unregister event failed: {}", e);
+        }
+        self.vhosts
+            .get(req.vhost.as_deref())
+            .hooks
+            .on_stop(&self.uid, req);
+        let _ = self.stat_tx.send(StatEvent::DeleteConn(self.uid.clone(), {
+            let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
+            conn.recv_bytes = 0;
+            conn.send_bytes = self.ts_enc.get_send_bytes();
+            conn.audio_count = self.ts_enc.get_audio_count();
+            conn.video_count = self.ts_enc.get_video_count();
+            conn
+        }));
+    }
+
+    async fn playing(
+        &mut self,
+        req: &Request,
+        token: Token,
+        mut kick: KickRx,
+    ) -> Result<(), ServiceError> {
+        let mut rx = match token {
+            Token::SubscriberToken(rx) => rx,
+            _ => return Err(ServiceError::InvalidToken),
+        };
+        let mut merge_msgs = Vec::with_capacity(128);
+        let mut merge_size = 0;
+        let mut start_ts = 0;
+        let stream_key = req.stream_key();
+        let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
+        loop {
+            tokio::select! {
+                _ = &mut kick => {
+                    info!("Kicked");
+                    return Ok(());
+                }
+                msgs = rx.recv() => {
+                    match msgs {
+                        Some(msgs) => {
+                            let mut cur_ts = 0;
+                            let mut has_key_frame = false;
+                            for msg in msgs.iter() {
+                                if !has_key_frame {
+                                    has_key_frame = msg.is_key_frame();
+                                }
+                                cur_ts = msg.timestamp().unwrap_or(0);
+                                merge_size += msg.len().unwrap_or(0);
+                                merge_msgs.push(msg.clone());
+                            }
+                            // Merge-send msgs to player in PERF_MERGE_SEND_MSG for improve performance
+                            if cur_ts >= (start_ts + PERF_MERGE_SEND_MSG) || cur_ts == 0 || cur_ts < start_ts || has_key_frame {
+                                trace!("Merged send msgs len {} total_size {}", merge_msgs.len(), merge_size);
+                                self.response.start_send(Ok(self.ts_enc.write_tags(&merge_msgs)?))?;
+                                merge_msgs.clear();
+                                start_ts = cur_ts;
+                                merge_size = 0;
+                            }
+                        }
+                        None => return Err(ServiceError::PublishDone)
+                    }
+                }
+                _ = stat_report.tick() => {
+                    let _ = self.stat_tx.send(StatEvent::UpdateConn(self.uid.clone(), {
+                        let mut conn = ConnStat::new(stream_key.clone(), req.conn_type.clone());
+                        conn.recv_bytes = 0;
+                        conn.send_bytes = self.ts_enc.get_send_bytes();
+                        conn.audio_count = self.ts_enc.get_audio_count();
+                        conn.video_count = self.ts_enc.get_video_count();
+                        conn
+                    }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{Auth, AuthConfig},
+        hook::{HookConfig, Hooks},
+        stream::queue::{self, Batch},
+        vhost::Vhost,
+    };
+    use bytes::Bytes;
+    use futures::{channel::mpsc::unbounded, StreamExt};
+    use rtmp::message::RtmpMessage;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    fn get(uri: &str) -> HttpRequest<Body> {
+        HttpRequest::get(uri)
+            .header("Host", "127.0.0.1")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_play() {
+        let (tx, mut rx) = unbounded();
+        let (mgr_tx, mut mgr_rx) = mpsc::unbounded_channel();
+        let (stat_tx, mut stat_rx) = mpsc::unbounded_channel();
+        let mut ts_service =
+            HttpTsService::new("uid".to_string(), tx, mgr_tx, stat_tx, Vhosts::default());
+        let service = tokio::spawn(async move { ts_service.run(get("/live/test.ts")).await });
+
+        let (mut sub_tx, sub_rx) = queue::channel(16);
+        // Kicked if dropped
+        let _kick = match mgr_rx.recv().await {
+            Some(StreamEvent::Register(ev)) => {
+                assert_eq!(ev.stream_key, "/live/test");
+                assert!(matches!(ev.role, RoleType::Subscriber));
+                let _ = ev.ret.send(Token::SubscriberToken(sub_rx));
+                ev.kick.unwrap()
+            }
+            _ => panic!("expect the register event"),
+        };
+        assert!(matches!(
+            stat_rx.recv().await,
+            Some(StatEvent::CreateConn(..))
+        ));
+
+        // AAC-LC, 44100Hz, stereo
+        let msgs = vec![
+            RtmpMessage::AudioData {
+                stream_id: 1,
+                timestamp: 0,
+                payload: Bytes::from_static(&[0xaf, 0x00, 0x12, 0x10]),
+            },
+            RtmpMessage::AudioData {
+                stream_id: 1,
+                timestamp: 0,
+                payload: Bytes::from_static(&[0xaf, 0x01, 0x21, 0x00]),
+            },
+        ];
+        sub_tx.try_send(Batch::new(msgs)).unwrap();
+        let data = rx.next().await.unwrap().unwrap();
+        assert!(!data.is_empty());
+        assert_eq!(data[0], 0x47);
+
+        // Unregistered when the publisher is done
+        sub_tx.close();
+        assert!(matches!(
+            service.await.unwrap(),
+            Err(ServiceError::PublishDone)
+        ));
+        match mgr_rx.recv().await {
+            Some(StreamEvent::Unregister(ev)) => {
+                assert_eq!(ev.uid, "uid");
+                assert_eq!(ev.stream_key, "/live/test");
+                assert!(matches!(ev.role, RoleType::Subscriber));
+            }
+            _ => panic!("expect the unregister event"),
+        }
+        while let Some(ev) = stat_rx.recv().await {
+            if let StatEvent::DeleteConn(uid, conn) = ev {
+                assert_eq!(uid, "uid");
+                assert!(conn.send_bytes > 0);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let auth = Auth::new(AuthConfig {
+            play: true,
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        });
+        let vhost = Vhost {
+            hooks: Hooks::new(HookConfig::default()).with_auth(auth),
+            ..Default::default()
+        };
+        let vhosts = Vhosts::new(vhost, HashMap::new());
+        let (tx, mut rx) = unbounded();
+        let (mgr_tx, mut mgr_rx) = mpsc::unbounded_channel();
+        let (stat_tx, _stat_rx) = mpsc::unbounded_channel();
+        let mut ts_service = HttpTsService::new("uid".to_string(), tx, mgr_tx, stat_tx, vhosts);
+
+        let ret = ts_service.run(get("/live/test.ts")).await;
+        assert!(ret.unwrap_err().is_unauthorized());
+        // The HTTP server responses 403 by the error
+        let err = rx.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // Never registered
+        assert!(mgr_rx.try_recv().is_err());
+    }
+}
@@ -1,15 +1,26 @@
 use std::time::Duration;
 
+pub mod auth;
+pub mod dvr;
+pub mod dvr_service;
+pub mod edge;
 pub mod error;
+pub mod forward;
+pub mod forward_service;
+pub mod hls;
+pub mod hls_service;
+pub mod hook;
 pub mod httpflv_service;
+pub mod httpts_service;
 pub mod rtmp_pull;
 pub mod rtmp_service;
 pub mod statistic;
 pub mod stream;
 pub mod utils;
+pub mod vhost;
 
 const CONN_PRINT_INTVAL: Duration = Duration::from_secs(5);
 const PERF_MERGE_SEND_MSG: u32 = 350;
 const PERF_MERGE_SEND_CHAN: u32 = 170;
-
-const STATIC_PULL_ADDRESS: &str = "rtmp://127.0.0.1";
+const HLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
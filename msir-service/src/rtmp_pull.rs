@@ -2,13 +2,13 @@ use rtmp::{
     connection::{client::Client as RtmpClient, RtmpConnType, RtmpCtrlAction},
     message::RtmpMessage,
 };
-use tracing::{debug, warn};
+use tracing::{info, warn};
 
 use crate::{
     error::ServiceError,
     statistic::{ConnStat, ConnToStatChanTx, StatEvent},
-    stream::{hub::Hub, ConnToMgrChanTx, RoleType, StreamEvent, UnregisterEv},
-    CONN_PRINT_INTVAL,
+    stream::{hub::Hub, ConnToMgrChanTx, KickRx, RoleType, StreamEvent, UnregisterEv},
+    CONN_PRINT_INTVAL, UPSTREAM_CONNECT_TIMEOUT,
 };
 
 pub struct RtmpPull {
@@ -16,15 +16,26 @@ pub struct RtmpPull {
     hub: Hub,
     mgr_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    // Whether any media is received from the origin
+    started: bool,
+    kick: KickRx,
 }
 
 impl RtmpPull {
-    pub fn new(uid: String, hub: Hub, mgr_tx: ConnToMgrChanTx, stat_tx: ConnToStatChanTx) -> Self {
+    pub fn new(
+        uid: String,
+        hub: Hub,
+        mgr_tx: ConnToMgrChanTx,
+        stat_tx: ConnToStatChanTx,
+        kick: KickRx,
+    ) -> Self {
         Self {
             uid,
             hub,
             mgr_tx,
             stat_tx,
+            started: false,
+            kick,
         }
     }
 
@@ -53,14 +64,28 @@ impl RtmpPull {
         }
     }
 
-    pub async fn pulling(&mut self, tc_url: String, stream: String) -> Result<(), ServiceError> {
-        let mut rtmp = RtmpClient::new(tc_url, stream).await?;
-        let sid = rtmp.connect(self.uid.clone()).await? as u32;
-        rtmp.play(sid).await?;
-        let stream_key = rtmp.req.app_stream();
+    pub async fn pulling(
+        &mut self,
+        tc_url: String,
+        stream: String,
+        stream_key: &str,
+    ) -> Result<(), ServiceError> {
+        let uid = self.uid.clone();
+        let mut rtmp = match tokio::time::timeout(UPSTREAM_CONNECT_TIMEOUT, async move {
+            let mut rtmp = RtmpClient::new(tc_url, stream).await?;
+            let sid = rtmp.connect(uid).await? as u32;
+            rtmp.play(sid).await?;
+            Ok::<_, ServiceError>(rtmp)
+        })
+        .await
+        {
+            Ok(ret) => ret?,
+            Err(_) => return Err(ServiceError::ConnectTimeout),
+        };
         let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
         loop {
             tokio::select! {
+                _ = &mut self.kick => return Err(ServiceError::Kicked),
                 msg = rtmp.recv_message() => {
                     match msg {
                         Ok(msg) => {
@@ -70,8 +95,10 @@ impl RtmpPull {
                                         self.hub.on_metadata(msg)?;
                                     }
                                 }
-                                RtmpMessage::VideoData {..} => self.hub.on_frame(msg)?,
-                                RtmpMessage::AudioData {..} => self.hub.on_frame(msg)?,
+                                RtmpMessage::VideoData {..} | RtmpMessage::AudioData {..} => {
+                                    self.started = true;
+                                    self.hub.on_frame(msg)?;
+                                }
                                 _ => {} // debug!("Ignore {}", other)
                             }
                         }
@@ -90,11 +117,14 @@ impl RtmpPull {
                 }
                 _ = stat_report.tick() => {
                     let _ = self.stat_tx.send(StatEvent::UpdateConn(self.uid.clone(), {
-                        let mut conn = ConnStat::new(stream_key.clone(), RtmpConnType::Pull);
+                        let mut conn = ConnStat::new(stream_key.to_string(), RtmpConnType::Pull);
                         conn.recv_bytes = rtmp.get_recv_bytes();
                         conn.send_bytes = rtmp.get_send_bytes();
                         conn.audio_count = rtmp.get_audio_count();
                         conn.video_count = rtmp.get_video_count();
+                        conn.codec = Some(self.hub.codec_info().clone());
+                        conn.realtime = Some(self.hub.realtime_stat());
+                        conn.client = Some(rtmp.req.client.clone());
                         conn
                     }));
                 }
@@ -103,10 +133,92 @@ impl RtmpPull {
     }
 }
 
+// Try the origins in order, fail over to the next one if nothing is pulled from the current one
 pub async fn start_pull_task(
     rtmp: &mut RtmpPull,
-    tc_url: String,
-    stream: String,
+    origins: Vec<String>,
+    stream_key: &str,
 ) -> Result<(), ServiceError> {
-    rtmp.pulling(tc_url, stream).await
+    let vecs: Vec<&str> = stream_key.splitn(3, '/').collect();
+    let (vhost, app, stream) = (vecs[0], vecs[1], vecs[2]);
+    let mut ret = Err(ServiceError::NoOrigin);
+    for origin in origins {
+        let mut tc_url = format!("{}/{}", origin.trim_end_matches('/'), app);
+        // The origin serves the same vhost
+        if !vhost.is_empty() {
+            tc_url = format!("{}?vhost={}", tc_url, vhost);
+        }
+        info!("Pull {} from origin {}", stream_key, origin);
+        ret = rtmp.pulling(tc_url, stream.to_string(), stream_key).await;
+        match &ret {
+            Err(ServiceError::NoSubscriber) | Err(ServiceError::Kicked) => break,
+            Err(e) if !rtmp.started => warn!("Pull from origin {} failed: {}", origin, e),
+            _ => break,
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::queue::SlowPolicy;
+    use bytes::Bytes;
+    use msir_core::transport::Transport;
+    use rtmp::connection::{server::Server, EncodedChunks};
+    use std::time::Duration;
+    use tokio::{
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+    };
+
+    // Serve one play request, reject it or send one video frame and close
+    async fn origin(reject: bool) -> (String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut server = Server::new(Transport::new(socket)).await.unwrap();
+            let req = server.identify_client().await.unwrap();
+            assert_eq!(req.stream(), "test");
+            if reject {
+                server.reject_play("stream not found").await.unwrap();
+                return;
+            }
+            server.start_play().await.unwrap();
+            let msgs = vec![RtmpMessage::VideoData {
+                stream_id: 1,
+                timestamp: 0,
+                payload: Bytes::from(vec![0x17; 100]),
+            }];
+            let chunks = EncodedChunks::encode(&msgs, server.get_out_chunk_size()).unwrap();
+            server.send_chunks(&[&chunks]).await.unwrap();
+            // Drain the client's pending messages, or closing resets the connection
+            while let Ok(Ok(_)) =
+                tokio::time::timeout(Duration::from_millis(200), server.recv_message()).await
+            {
+            }
+        });
+        (format!("rtmp://{}", addr), handle)
+    }
+
+    #[tokio::test]
+    async fn test_pull_failover_on_play_rejected() {
+        let (first, first_handle) = origin(true).await;
+        let (second, second_handle) = origin(false).await;
+
+        let (_hub_tx, hub_rx) = mpsc::unbounded_channel();
+        let (stat_tx, _stat_rx) = mpsc::unbounded_channel();
+        let (mgr_tx, _mgr_rx) = mpsc::unbounded_channel();
+        let (_kick_tx, kick_rx) = oneshot::channel();
+        let hub = Hub::new(hub_rx, SlowPolicy::DropFrames, stat_tx.clone());
+        let mut rtmp = RtmpPull::new("uid".to_string(), hub, mgr_tx, stat_tx, kick_rx);
+
+        // Ends once the second origin closes the connection
+        let ret = start_pull_task(&mut rtmp, vec![first, second], "/live/test").await;
+        assert!(ret.is_err());
+        assert!(rtmp.started);
+        first_handle.await.unwrap();
+        second_handle.await.unwrap();
+    }
 }
@@ -1,65 +1,79 @@
 use crate::{
     error::ServiceError,
     statistic::{ConnStat, ConnToStatChanTx, StatEvent},
-    stream::{ConnToMgrChanTx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
-    utils, CONN_PRINT_INTVAL, PERF_MERGE_SEND_MSG,
+    stream::{ConnToMgrChanTx, KickRx, RegisterEv, RoleType, StreamEvent, Token, UnregisterEv},
+    utils,
+    vhost::Vhosts,
+    CONN_PRINT_INTVAL, PERF_MERGE_SEND_MSG,
 };
 use msir_core::transport::Transport;
 use rtmp::connection::RtmpConnType;
 use rtmp::connection::{server::Server as RtmpServer, RtmpCtrlAction};
 use rtmp::message::request::Request;
 use rtmp::message::RtmpMessage;
+use std::net::SocketAddr;
 use tokio::sync::oneshot;
 use tracing::{debug, error, info, trace, warn};
 
 pub struct RtmpService {
     uid: String,
     rtmp: RtmpServer,
+    // None if unknown
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
     mgr_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
 }
 
 impl RtmpService {
     pub async fn new(
         io: Transport,
         uid: Option<String>,
+        remote_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
         mgr_tx: ConnToMgrChanTx,
         stat_tx: ConnToStatChanTx,
+        vhosts: Vhosts,
     ) -> Result<Self, ServiceError> {
         let rtmp = RtmpServer::new(io).await?;
         let uid = uid.unwrap_or_else(|| utils::gen_uid());
         Ok(Self {
             uid,
             rtmp,
+            remote_addr,
+            local_addr,
             mgr_tx,
             stat_tx,
+            vhosts,
         })
     }
     pub async fn run(&mut self) -> Result<(), ServiceError> {
         loop {
             // connect with client and identify conn type
-            let req = self.rtmp.identify_client().await?;
-            // start publish/play
-            match req.conn_type {
-                RtmpConnType::Play => {
-                    self.rtmp.start_play().await?;
+            let mut req = self.rtmp.identify_client().await?;
+            req.client.remote_addr = self.remote_addr;
+            req.client.local_addr = self.local_addr;
+            self.vhosts.resolve(&mut req);
+            // Register before start publish/play, so that the client can be rejected
+            let (token, kick) = match self.register(&req).await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    let _ = match req.conn_type.is_publish() {
+                        true => self.rtmp.reject_publish(&e.to_string()).await,
+                        false => self.rtmp.reject_play(&e.to_string()).await,
+                    };
+                    return Err(e);
                 }
-                RtmpConnType::FmlePublish => {
-                    self.rtmp.start_fmle_publish().await?;
-                }
-                RtmpConnType::FlashPublish => {
-                    self.rtmp.start_flash_publish().await?;
-                }
-                RtmpConnType::HaivisionPublish => {
-                    self.rtmp.start_haivision_publish().await?;
-                }
-                _ => {}
-            }
-            let token = self.register(&req).await?;
+            };
             debug!("Register to hub");
+            if let Err(e) = self.start(&req).await {
+                self.unregister(&req).await;
+                return Err(e);
+            }
             let ret = match req.conn_type.is_publish() {
-                true => self.publishing(&req, token).await,
-                false => self.playing(&req, token).await,
+                true => self.publishing(&req, token, kick).await,
+                false => self.playing(&req, token, kick).await,
             };
             self.unregister(&req).await;
             debug!("Unegister to hub");
@@ -73,19 +87,46 @@ impl RtmpService {
         }
     }
 
+    // Start publish/play
+    async fn start(&mut self, req: &Request) -> Result<(), ServiceError> {
+        match req.conn_type {
+            RtmpConnType::Play => {
+                self.rtmp.start_play().await?;
+            }
+            RtmpConnType::FmlePublish => {
+                self.rtmp.start_fmle_publish().await?;
+            }
+            RtmpConnType::FlashPublish => {
+                self.rtmp.start_flash_publish().await?;
+            }
+            RtmpConnType::HaivisionPublish => {
+                self.rtmp.start_haivision_publish().await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     // TODO: register, unregister, stats can be reused by rtmp and httpflv
-    async fn register(&self, req: &Request) -> Result<Token, ServiceError> {
-        let stream_key = req.app_stream();
+    async fn register(&self, req: &Request) -> Result<(Token, KickRx), ServiceError> {
+        let stream_key = req.stream_key();
         let role = match req.conn_type.is_publish() {
             true => RoleType::Publisher,
             false => RoleType::Subscriber,
         };
+        let hooks = &self.vhosts.get(req.vhost.as_deref()).hooks;
+        match role {
+            RoleType::Publisher => hooks.on_publish(&self.uid, req).await?,
+            RoleType::Subscriber => hooks.on_play(&self.uid, req).await?,
+        }
         let (reg_tx, reg_rx) = oneshot::channel();
+        let (kick_tx, kick_rx) = oneshot::channel();
         let msg = StreamEvent::Register(RegisterEv {
             uid: self.uid.clone(),
             stream_key: stream_key.clone(),
             role,
             ret: reg_tx,
+            kick: Some(kick_tx),
         });
         if let Err(_) = self.mgr_tx.send(msg) {
             return Err(ServiceError::RegisterFailed(
@@ -98,11 +139,12 @@ impl RtmpService {
                 if let Token::Failure(e) = token {
                     return Err(ServiceError::RegisterFailed(e.to_string()));
                 }
-                let _ = self.stat_tx.send(StatEvent::CreateConn(
-                    self.uid.clone(),
-                    ConnStat::new(stream_key, req.conn_type.clone()),
-                ));
-                Ok(token)
+                let _ = self.stat_tx.send(StatEvent::CreateConn(self.uid.clone(), {
+                    let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
+                    conn.client = Some(req.client.clone());
+                    conn
+                }));
+                Ok((token, kick_rx))
             }
             Err(_) => Err(ServiceError::RegisterFailed(
                 "recv register ret failed: ".to_string(),
@@ -111,7 +153,7 @@ impl RtmpService {
     }
 
     async fn unregister(&mut self, req: &Request) {
-        let stream_key = req.app_stream();
+        let stream_key = req.stream_key();
         let role = match req.conn_type.is_publish() {
             true => RoleType::Publisher,
             false => RoleType::Subscriber,
@@ -124,6 +166,11 @@ impl RtmpService {
         if let Err(e) = self.mgr_tx.send(msg) {
             warn!("send unregister event failed: {}", e);
         }
+        let hooks = &self.vhosts.get(req.vhost.as_deref()).hooks;
+        match req.conn_type.is_publish() {
+            true => hooks.on_unpublish(&self.uid, req),
+            false => hooks.on_stop(&self.uid, req),
+        }
         let _ = self.stat_tx.send(StatEvent::DeleteConn(self.uid.clone(), {
             let mut conn = ConnStat::new(stream_key, req.conn_type.clone());
             conn.recv_bytes = self.rtmp.get_recv_bytes();
@@ -138,19 +185,25 @@ impl RtmpService {
         &mut self,
         req: &Request,
         token: Token,
+        mut kick: KickRx,
     ) -> Result<Option<RtmpCtrlAction>, ServiceError> {
         let mut rx = match token {
             Token::SubscriberToken(rx) => rx,
             _ => return Err(ServiceError::InvalidToken),
         };
-        let mut merge_msgs = Vec::with_capacity(128);
+        let mut merge_batches = Vec::with_capacity(16);
         let mut pause = false;
         let mut merge_size = 0;
         let mut start_ts = 0;
-        let stream_key = req.app_stream();
+        let stream_key = req.stream_key();
         let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
         loop {
             tokio::select! {
+                _ = &mut kick => {
+                    info!("Kicked");
+                    self.rtmp.kick_play("Kicked by the server").await?;
+                    return Ok(None);
+                }
                 msg = self.rtmp.recv_message() => {
                     match msg {
                         Ok(msg) => {
@@ -181,19 +234,26 @@ impl RtmpService {
                             }
                             let mut cur_ts = 0;
                             let mut has_key_frame = false;
-                            for msg in msgs {
+                            for msg in msgs.iter() {
                                 if !has_key_frame {
                                     has_key_frame = msg.is_key_frame();
                                 }
                                 cur_ts = msg.timestamp().unwrap_or(0);
                                 merge_size += msg.len().unwrap_or(0);
-                                merge_msgs.push(msg);
                             }
+                            merge_batches.push(msgs);
                             // Merge-send msgs to player in PERF_MERGE_SEND_MSG for improve performance
                             if cur_ts >= (start_ts + PERF_MERGE_SEND_MSG) || cur_ts == 0 || cur_ts < start_ts || has_key_frame {
-                                trace!("Merged send msgs len {} total_size {}", merge_msgs.len(), merge_size);
-                                self.rtmp.send_messages(&merge_msgs, 0, 0).await?;
-                                merge_msgs.clear();
+                                trace!("Merged send batches len {} total_size {}", merge_batches.len(), merge_size);
+                                // The chunks are encoded once by the Hub batch and shared by the players
+                                let chunk_size = self.rtmp.get_out_chunk_size();
+                                let chunks = merge_batches
+                                    .iter()
+                                    .map(|b| b.rtmp_chunks(chunk_size))
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                let chunks: Vec<_> = chunks.iter().map(|c| c.as_ref()).collect();
+                                self.rtmp.send_chunks(&chunks).await?;
+                                merge_batches.clear();
                                 start_ts = cur_ts;
                                 merge_size = 0;
                             }
@@ -219,15 +279,20 @@ impl RtmpService {
         &mut self,
         req: &Request,
         token: Token,
+        mut kick: KickRx,
     ) -> Result<Option<RtmpCtrlAction>, ServiceError> {
         let mut hub = match token {
             Token::PublisherToken(hub) => hub,
             _ => return Err(ServiceError::InvalidToken),
         };
-        let stream_key = req.app_stream();
+        let stream_key = req.stream_key();
         let mut stat_report = tokio::time::interval(CONN_PRINT_INTVAL);
         loop {
             tokio::select! {
+                _ = &mut kick => {
+                    info!("Kicked");
+                    return Ok(None);
+                }
                 msg = self.rtmp.recv_message() => {
                     match msg {
                         Ok(msg) => {
@@ -262,6 +327,8 @@ impl RtmpService {
                         conn.send_bytes = self.rtmp.get_send_bytes();
                         conn.audio_count = self.rtmp.get_audio_count();
                         conn.video_count = self.rtmp.get_video_count();
+                        conn.codec = Some(hub.codec_info().clone());
+                        conn.realtime = Some(hub.realtime_stat());
                         conn
                     }));
                 }
@@ -1,6 +1,7 @@
+use crate::stream::queue::SlowPolicy;
 use msir_core::utils;
 use prometheus::{CounterVec, Encoder, Gauge, GaugeVec, Opts, Registry};
-use rtmp::connection::RtmpConnType;
+use rtmp::{codec_info::CodecInfo, connection::RtmpConnType, message::request::ClientInfo};
 use serde_derive::Serialize;
 use std::{
     collections::HashMap,
@@ -23,6 +24,8 @@ pub enum StatEvent {
     CreateConn(String, ConnStat),
     DeleteConn(String, ConnStat),
     UpdateConn(String, ConnStat),
+    // Reported by the Hub when the queue of a subscriber is full
+    SlowConsumer(String, SlowPolicy, SlowStat),
 
     QueryMetrics(QueryMetricsResponse),
     QueryConn(String, QueryConnsResponse),
@@ -60,6 +63,7 @@ impl Statistic {
                             StatEvent::CreateConn(uid, cs) => self.on_create_conn(uid, cs),
                             StatEvent::DeleteConn(uid, cs) => self.on_delete_conn(uid, cs),
                             StatEvent::UpdateConn(uid, cs) => self.on_update_conn(uid, cs),
+                            StatEvent::SlowConsumer(uid, policy, ss) => self.on_slow_consumer(uid, policy, ss),
 
                             StatEvent::QueryMetrics(tx) => self.on_query_metrics(tx),
                             StatEvent::QueryConn(filter, tx) => self.on_query_conns(filter, tx),
@@ -109,6 +113,13 @@ impl Statistic {
             conn.send_bytes = stat.send_bytes;
             conn.conn_type = stat.conn_type;
             conn.stream_key = stat.stream_key;
+            conn.forward = stat.forward;
+            conn.codec = stat.codec;
+            conn.realtime = stat.realtime;
+            // The pull client knows it after connected to the upstream
+            if stat.client.is_some() {
+                conn.client = stat.client;
+            }
 
             self.metrics
                 .recv_bytes_counter
@@ -124,6 +135,11 @@ impl Statistic {
                     s.audio = stat.audio_count;
                     s.video = stat.video_count;
                     s.recv_bytes = stat.recv_bytes;
+                    s.codec = conn.codec.clone();
+                    s.realtime = conn.realtime;
+                    if let Some(realtime) = &conn.realtime {
+                        self.metrics.set_stream(&conn.stream_key, realtime);
+                    }
                     s.can_print(&conn.stream_key, Instant::now());
                 } else {
                     s.send_bytes += delta_send_bytes;
@@ -132,6 +148,22 @@ impl Statistic {
         }
     }
 
+    fn on_slow_consumer(&mut self, uid: String, policy: SlowPolicy, stat: SlowStat) {
+        let last = match self.conns.get_mut(&uid) {
+            Some(conn) => conn.slow.replace(stat).unwrap_or_default(),
+            None => return,
+        };
+        let labels = &[policy.as_str()];
+        self.metrics
+            .slow_consumer_counter
+            .with_label_values(labels)
+            .inc_by(stat.lags.saturating_sub(last.lags) as f64);
+        self.metrics
+            .dropped_msgs_counter
+            .with_label_values(labels)
+            .inc_by(stat.dropped.saturating_sub(last.dropped) as f64);
+    }
+
     fn on_delete_conn(&mut self, uid: String, stat: ConnStat) {
         let conn = self.conns.remove(&uid);
 
@@ -155,6 +187,7 @@ impl Statistic {
         match stat.conn_type.is_publish() {
             true => {
                 self.streams.remove(&stat.stream_key);
+                self.metrics.remove_stream(&stat.stream_key);
                 info!("StreamStats {} removed", stat.stream_key);
             }
             false => {
@@ -223,6 +256,10 @@ pub struct StreamStat {
     pub publish_type: RtmpConnType,
     pub start_time: u32,
     pub clients: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<CodecInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub realtime: Option<RealtimeStat>,
     // Last record for log print
     #[serde(skip_serializing)]
     last_logged: Option<Instant>,
@@ -244,6 +281,8 @@ impl StreamStat {
             clients: 0,
             publish_type,
             start_time,
+            codec: None,
+            realtime: None,
             last_logged: None,
             last_video: 0,
             last_recv_bytes: 0,
@@ -284,10 +323,24 @@ pub struct ConnStat {
     pub conn_time: u32,
     pub stream_key: String,
     pub conn_type: RtmpConnType,
+    // Addresses and the client metadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientInfo>,
     pub recv_bytes: u64,
     pub send_bytes: u64,
     pub audio_count: u64,
     pub video_count: u64,
+    // Only for the forwarder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward: Option<ForwardStat>,
+    // Only for the publisher
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<CodecInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub realtime: Option<RealtimeStat>,
+    // Only for the subscriber which ever lagged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow: Option<SlowStat>,
 }
 
 impl ConnStat {
@@ -296,14 +349,57 @@ impl ConnStat {
             stream_key,
             conn_type,
             conn_time: utils::current_time(),
+            client: None,
             recv_bytes: 0,
             send_bytes: 0,
             audio_count: 0,
             video_count: 0,
+            forward: None,
+            codec: None,
+            realtime: None,
+            slow: None,
         }
     }
 }
 
+// Rolling metrics of the publisher, computed by the Hub
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct RealtimeStat {
+    pub video_kbps: f64,
+    pub audio_kbps: f64,
+    pub fps: f64,
+    // Measured keyframe interval
+    pub gop_ms: u32,
+    // Mean difference between the arrival interval and the timestamp interval
+    pub jitter_ms: u64,
+}
+
+// Cumulative, the queue of the subscriber was full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SlowStat {
+    // Times the subscriber fell behind
+    pub lags: u32,
+    // Messages not delivered to the subscriber
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ForwardState {
+    #[serde(rename(serialize = "connecting"))]
+    Connecting,
+    #[serde(rename(serialize = "publishing"))]
+    Publishing,
+    #[serde(rename(serialize = "reconnecting"))]
+    Reconnecting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardStat {
+    pub url: String,
+    pub state: ForwardState,
+    pub reconnects: u32,
+}
+
 #[cfg(target_os = "linux")]
 #[derive(Debug, Clone, Serialize)]
 pub struct SummariesStat {
@@ -369,7 +465,7 @@ impl SummariesStat {
             streams: 0,
             conns: 0,
             mem_mbytes: 0,
-            cpu_percent: 0.0
+            cpu_percent: 0.0,
         }
     }
 
@@ -383,6 +479,15 @@ struct Metrics {
     send_bytes_counter: CounterVec,
     cpu_percent_gauge: Gauge,
     mem_mbytes_gauge: Gauge,
+    // Labelled by stream
+    stream_video_kbps_gauge: GaugeVec,
+    stream_audio_kbps_gauge: GaugeVec,
+    stream_fps_gauge: GaugeVec,
+    stream_gop_seconds_gauge: GaugeVec,
+    stream_jitter_ms_gauge: GaugeVec,
+    // Labelled by the slow consumer policy
+    slow_consumer_counter: CounterVec,
+    dropped_msgs_counter: CounterVec,
 }
 
 impl Metrics {
@@ -417,11 +522,58 @@ impl Metrics {
                 .const_label("misr_ip", local_ip.as_str()),
         )
         .unwrap();
+        let stream_gauge = |name: &str, help: &str| {
+            GaugeVec::new(
+                Opts::new(name, help).const_label("misr_ip", local_ip.as_str()),
+                &["stream"],
+            )
+            .unwrap()
+        };
+        let stream_video_kbps_gauge = stream_gauge(
+            "msir_stream_video_kbps_gauge",
+            "stream video kbps gauge help",
+        );
+        let stream_audio_kbps_gauge = stream_gauge(
+            "msir_stream_audio_kbps_gauge",
+            "stream audio kbps gauge help",
+        );
+        let stream_fps_gauge = stream_gauge("msir_stream_fps_gauge", "stream fps gauge help");
+        let stream_gop_seconds_gauge = stream_gauge(
+            "msir_stream_gop_seconds_gauge",
+            "stream gop seconds gauge help",
+        );
+        let stream_jitter_ms_gauge =
+            stream_gauge("msir_stream_jitter_ms_gauge", "stream jitter ms gauge help");
+        let slow_consumer_counter = CounterVec::new(
+            Opts::new("msir_slow_consumer_counter", "slow consumer counter help")
+                .const_label("misr_ip", local_ip.as_str()),
+            &["policy"],
+        )
+        .unwrap();
+        let dropped_msgs_counter = CounterVec::new(
+            Opts::new("msir_dropped_msgs_counter", "dropped msgs counter help")
+                .const_label("misr_ip", local_ip.as_str()),
+            &["policy"],
+        )
+        .unwrap();
         reg.register(Box::new(conn_gauge.clone())).unwrap();
         reg.register(Box::new(recv_bytes_counter.clone())).unwrap();
         reg.register(Box::new(send_bytes_counter.clone())).unwrap();
         reg.register(Box::new(cpu_percent_gauge.clone())).unwrap();
         reg.register(Box::new(mem_mbytes_gauge.clone())).unwrap();
+        reg.register(Box::new(stream_video_kbps_gauge.clone()))
+            .unwrap();
+        reg.register(Box::new(stream_audio_kbps_gauge.clone()))
+            .unwrap();
+        reg.register(Box::new(stream_fps_gauge.clone())).unwrap();
+        reg.register(Box::new(stream_gop_seconds_gauge.clone()))
+            .unwrap();
+        reg.register(Box::new(stream_jitter_ms_gauge.clone()))
+            .unwrap();
+        reg.register(Box::new(slow_consumer_counter.clone()))
+            .unwrap();
+        reg.register(Box::new(dropped_msgs_counter.clone()))
+            .unwrap();
         Self {
             reg,
             conn_gauge,
@@ -429,6 +581,45 @@ impl Metrics {
             send_bytes_counter,
             cpu_percent_gauge,
             mem_mbytes_gauge,
+            stream_video_kbps_gauge,
+            stream_audio_kbps_gauge,
+            stream_fps_gauge,
+            stream_gop_seconds_gauge,
+            stream_jitter_ms_gauge,
+            slow_consumer_counter,
+            dropped_msgs_counter,
+        }
+    }
+
+    fn set_stream(&mut self, stream_key: &str, realtime: &RealtimeStat) {
+        let labels = &[stream_key];
+        self.stream_video_kbps_gauge
+            .with_label_values(labels)
+            .set(realtime.video_kbps);
+        self.stream_audio_kbps_gauge
+            .with_label_values(labels)
+            .set(realtime.audio_kbps);
+        self.stream_fps_gauge
+            .with_label_values(labels)
+            .set(realtime.fps);
+        self.stream_gop_seconds_gauge
+            .with_label_values(labels)
+            .set(realtime.gop_ms as f64 / 1000.0);
+        self.stream_jitter_ms_gauge
+            .with_label_values(labels)
+            .set(realtime.jitter_ms as f64);
+    }
+
+    fn remove_stream(&mut self, stream_key: &str) {
+        let labels = &[stream_key];
+        for gauge in [
+            &self.stream_video_kbps_gauge,
+            &self.stream_audio_kbps_gauge,
+            &self.stream_fps_gauge,
+            &self.stream_gop_seconds_gauge,
+            &self.stream_jitter_ms_gauge,
+        ] {
+            let _ = gauge.remove_label_values(labels);
         }
     }
 
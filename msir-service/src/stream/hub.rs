@@ -1,8 +1,22 @@
-use crate::PERF_MERGE_SEND_CHAN;
+use crate::{
+    statistic::{ConnToStatChanTx, RealtimeStat, StatEvent},
+    PERF_MERGE_SEND_CHAN,
+};
 
-use super::{error::StreamError, gop::GopCache, HubToSubsChanTx, MgrToHubChanRx};
-use rtmp::{codec, message::RtmpMessage};
-use std::collections::HashMap;
+use super::{
+    error::StreamError,
+    gop::GopCache,
+    metrics::StreamMetrics,
+    queue::{Batch, SlowPolicy},
+    HubToSubsChanTx, MgrToHubChanRx,
+};
+use rtmp::{
+    codec,
+    codec_info::{AudioInfo, CodecInfo, VideoInfo},
+    message::RtmpMessage,
+};
+use std::{collections::HashMap, sync::Arc, time::Instant};
+use tokio::sync::mpsc::error::TrySendError;
 use tracing::{debug, info, trace, warn};
 
 pub enum HubEvent {
@@ -17,6 +31,17 @@ struct MetaCache {
     audio_sh: Option<RtmpMessage>,
 }
 
+impl MetaCache {
+    // Metadata and sequence headers to start decoding
+    fn headers(&self) -> Vec<RtmpMessage> {
+        [&self.metadata, &self.audio_sh, &self.video_sh]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Hub {
     meta: MetaCache,
@@ -25,10 +50,20 @@ pub struct Hub {
     pub subscribers: HashMap<String, HubToSubsChanTx>,
     merge_msgs: Vec<RtmpMessage>,
     start_ts: u32,
+    // Parsed from the sequence headers
+    codec: CodecInfo,
+    metrics: StreamMetrics,
+    // Headers and gop cache for the new subscriber, rebuilt after changed
+    live: Option<Arc<Batch>>,
+    // For the subscriber whose queue is full
+    policy: SlowPolicy,
+    // The new subscriber starts from the next key frame if disabled
+    gop_cache: bool,
+    stat_tx: ConnToStatChanTx,
 }
 
 impl Hub {
-    pub fn new(rx: MgrToHubChanRx) -> Self {
+    pub fn new(rx: MgrToHubChanRx, policy: SlowPolicy, stat_tx: ConnToStatChanTx) -> Self {
         Self {
             gop: GopCache::new(),
             meta: MetaCache::default(),
@@ -36,49 +71,42 @@ impl Hub {
             subscribers: HashMap::new(),
             merge_msgs: Vec::with_capacity(64),
             start_ts: 0,
+            codec: CodecInfo::default(),
+            metrics: StreamMetrics::new(),
+            live: None,
+            policy,
+            gop_cache: true,
+            stat_tx,
         }
     }
 
+    pub fn with_gop_cache(mut self, enabled: bool) -> Self {
+        self.gop_cache = enabled;
+        self
+    }
+
     pub async fn process_hub_ev(&mut self) -> Result<usize, StreamError> {
         match self.event_rx.recv().await {
             Some(ev) => {
                 match ev {
-                    HubEvent::SubscriberJoin(uid, tx) => {
-                        let mut sent_meta = 0;
-                        let mut sent_sh = 0;
-                        let mut sent_frame = 0;
-                        let mut msgs = Vec::with_capacity(self.gop.caches.len() + 3);
-                        // send metadata
-                        if let Some(meta) = &self.meta.metadata {
-                            sent_meta += 1;
-                            msgs.push(meta.clone());
-                        }
-                        // send audio sequence heade
-                        if let Some(meta) = &self.meta.audio_sh {
-                            sent_sh += 1;
-                            msgs.push(meta.clone());
-                        }
-                        // send video sequence heade
-                        if let Some(meta) = &self.meta.video_sh {
-                            sent_sh += 1;
-                            msgs.push(meta.clone());
-                        }
-                        // send gopcache
-                        for msg in self.gop.caches.iter() {
-                            msgs.push(msg.clone());
+                    HubEvent::SubscriberJoin(uid, mut tx) => {
+                        if !self.gop_cache && self.meta.video_sh.is_some() {
+                            // Resumed with the headers at the next key frame, as the lagging one
+                            debug!("Subscriber {} waits for the next key frame", uid);
+                            tx.lagging = true;
+                        } else {
+                            let msgs = live_batch(&mut self.live, &self.meta, &self.gop);
+                            debug!(
+                                "Send to {} {} msgs with gop {}, duration {}ms",
+                                uid,
+                                msgs.len(),
+                                self.gop.caches.len(),
+                                self.gop.duration()
+                            );
+                            if let Err(_) = tx.try_send(msgs) {
+                                warn!("Hub send frame to subscriber failed");
+                            }
                         }
-                        if let Err(_) = tx.send(msgs) {
-                            warn!("Hub send frame to subscriber failed");
-                        }
-                        sent_frame += self.gop.caches.len();
-                        debug!(
-                            "Send to {} metadata {} seq header {} av {}, duration {}ms",
-                            uid,
-                            sent_meta,
-                            sent_sh,
-                            sent_frame,
-                            self.gop.duration()
-                        );
                         self.subscribers.insert(uid, tx)
                     }
                     HubEvent::SubscriberLeave(uid) => self.subscribers.remove(&uid),
@@ -89,14 +117,19 @@ impl Hub {
         }
     }
 
+    pub fn codec_info(&self) -> &CodecInfo {
+        &self.codec
+    }
+
+    pub fn realtime_stat(&mut self) -> RealtimeStat {
+        self.metrics.snapshot(Instant::now())
+    }
+
     pub fn on_metadata(&mut self, msg: RtmpMessage) -> Result<(), StreamError> {
         debug!("Recv metadata {}", msg);
-        for (_, subscriber) in self.subscribers.iter() {
-            if let Err(_) = subscriber.send(vec![msg.clone()]) {
-                warn!("Hub send metadata to subscriber failed");
-            }
-        }
-        self.meta.metadata = Some(msg);
+        self.meta.metadata = Some(msg.clone());
+        self.live = None;
+        self.fan_out(vec![msg], false);
         Ok(())
     }
 
@@ -104,38 +137,213 @@ impl Hub {
         let cur_ts = msg.timestamp().unwrap_or(0);
         self.merge_msgs.push(msg.clone());
         let has_key_frame = msg.is_key_frame();
+        if let RtmpMessage::VideoData { payload, .. } | RtmpMessage::AudioData { payload, .. } =
+            &msg
+        {
+            let is_video = matches!(msg, RtmpMessage::VideoData { .. });
+            self.metrics
+                .on_frame(Instant::now(), is_video, cur_ts, payload.len());
+            if has_key_frame {
+                self.metrics.on_keyframe(cur_ts);
+            }
+        }
         // Merge-send msgs to channel in PERF_MERGE_SEND_CHAN for improve performance in mutli-thread mode
-        if cur_ts >= (self.start_ts + PERF_MERGE_SEND_CHAN)
+        let flush = cur_ts >= (self.start_ts + PERF_MERGE_SEND_CHAN)
             || cur_ts == 0
             || cur_ts < self.start_ts
-            || has_key_frame
-        {
-            for (_, subscriber) in self.subscribers.iter() {
-                if let Err(_) = subscriber.send(self.merge_msgs.clone()) {
-                    warn!("Hub send frames to subscriber failed");
-                }
-            }
-            self.merge_msgs.clear();
+            || has_key_frame;
+
+        // Cache before sending, so that the lagging subscriber can restart from the live edge
+        self.cache(msg);
+
+        if flush {
+            let msgs = std::mem::replace(&mut self.merge_msgs, Vec::with_capacity(64));
+            self.fan_out(msgs, has_key_frame);
             self.start_ts = cur_ts;
         }
+        Ok(())
+    }
 
+    fn cache(&mut self, msg: RtmpMessage) {
+        self.live = None;
         match &msg {
             RtmpMessage::AudioData { payload, .. } => {
                 if codec::is_audio_sequence_header(payload) {
+                    self.codec.audio = AudioInfo::parse(payload);
+                    info!("Audio codec {:?}", self.codec.audio);
                     self.meta.audio_sh = Some(msg);
-                    return Ok(());
+                    return;
                 }
             }
             RtmpMessage::VideoData { payload, .. } => {
                 if codec::is_video_sequence_header(payload) {
+                    self.codec.video = VideoInfo::parse(payload);
+                    info!("Video codec {:?}", self.codec.video);
                     self.meta.video_sh = Some(msg);
-                    return Ok(());
+                    return;
                 }
             }
             _ => {}
         }
 
-        self.gop.cache(msg);
-        Ok(())
+        if self.gop_cache {
+            self.gop.cache(msg);
+        }
+    }
+
+    // The key frame is the last one of msgs if has
+    fn fan_out(&mut self, msgs: Vec<RtmpMessage>, has_key_frame: bool) {
+        // Shared by all the subscribers
+        let batch = Batch::new(msgs);
+        // Without the gop cache, the key frame is the only point to restart decoding
+        let drop_frames = self.policy == SlowPolicy::DropFrames || !self.gop_cache;
+        let mut closed = Vec::new();
+        for (uid, subscriber) in self.subscribers.iter_mut() {
+            let lagging = subscriber.lagging;
+            if lagging && subscriber.has_room() {
+                // Restart with the sequence headers so that the decoder can recover
+                let resume = match self.policy {
+                    _ if drop_frames && has_key_frame => {
+                        let mut msgs = self.meta.headers();
+                        msgs.extend(batch.last().cloned());
+                        Some(Batch::new(msgs))
+                    }
+                    // Pure audio
+                    _ if drop_frames && self.meta.video_sh.is_none() => {
+                        let mut msgs = self.meta.headers();
+                        msgs.extend(batch.iter().cloned());
+                        Some(Batch::new(msgs))
+                    }
+                    SlowPolicy::SkipToLive if self.gop_cache => {
+                        Some(live_batch(&mut self.live, &self.meta, &self.gop))
+                    }
+                    _ => None,
+                };
+                if let Some(resume) = resume {
+                    let dropped = match drop_frames && has_key_frame {
+                        true => batch.len() - 1,
+                        false => 0,
+                    };
+                    if subscriber.try_send(resume).is_ok() {
+                        subscriber.lagging = false;
+                        // The new subscriber is not slow
+                        if subscriber.slow.lags == 0 {
+                            continue;
+                        }
+                        subscriber.slow.dropped += dropped as u64;
+                        info!("Subscriber {} resumed, {:?}", uid, subscriber.slow);
+                        let _ = self.stat_tx.send(StatEvent::SlowConsumer(
+                            uid.clone(),
+                            self.policy,
+                            subscriber.slow,
+                        ));
+                        continue;
+                    }
+                }
+            }
+            if lagging {
+                if subscriber.slow.lags > 0 {
+                    subscriber.slow.dropped += batch.len() as u64;
+                }
+                continue;
+            }
+            match subscriber.try_send(batch.clone()) {
+                Ok(_) => {}
+                Err(TrySendError::Closed(_)) => warn!("Hub send frames to subscriber failed"),
+                Err(TrySendError::Full(_)) => {
+                    warn!("Subscriber {} is too slow, policy {:?}", uid, self.policy);
+                    subscriber.lagging = true;
+                    subscriber.slow.lags += 1;
+                    subscriber.slow.dropped += batch.len() as u64;
+                    match self.policy {
+                        SlowPolicy::SkipToLive => subscriber.skip_queued(),
+                        SlowPolicy::Disconnect => closed.push(uid.clone()),
+                        SlowPolicy::DropFrames => {}
+                    }
+                    let _ = self.stat_tx.send(StatEvent::SlowConsumer(
+                        uid.clone(),
+                        self.policy,
+                        subscriber.slow,
+                    ));
+                }
+            }
+        }
+        for uid in closed {
+            if let Some(subscriber) = self.subscribers.remove(&uid) {
+                subscriber.close();
+            }
+        }
+    }
+}
+
+// Built once and shared by the subscribers joined before the next frame
+fn live_batch(live: &mut Option<Arc<Batch>>, meta: &MetaCache, gop: &GopCache) -> Arc<Batch> {
+    live.get_or_insert_with(|| {
+        let mut msgs = meta.headers();
+        msgs.extend(gop.caches.iter().cloned());
+        Batch::new(msgs)
+    })
+    .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::queue;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn video(timestamp: u32, payload: &'static [u8]) -> RtmpMessage {
+        RtmpMessage::VideoData {
+            stream_id: 1,
+            timestamp,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    const AVC_SH: &[u8] = &[0x17, 0x00, 0, 0, 0, 0x01];
+    const KEYFRAME: &[u8] = &[0x17, 0x01, 0, 0, 0, 0x65];
+    const INTERFRAME: &[u8] = &[0x27, 0x01, 0, 0, 0, 0x41];
+
+    #[tokio::test]
+    async fn test_join_without_gop_cache() {
+        let (hub_tx, hub_rx) = mpsc::unbounded_channel();
+        let (stat_tx, mut stat_rx) = mpsc::unbounded_channel();
+        // Not by the slow policy
+        let mut hub = Hub::new(hub_rx, SlowPolicy::SkipToLive, stat_tx).with_gop_cache(false);
+        hub.on_frame(video(0, AVC_SH)).unwrap();
+        hub.on_frame(video(0, KEYFRAME)).unwrap();
+        hub.on_frame(video(40, INTERFRAME)).unwrap();
+
+        let (tx, mut rx) = queue::channel(16);
+        assert!(hub_tx
+            .send(HubEvent::SubscriberJoin("uid".to_string(), tx))
+            .is_ok());
+        assert_eq!(hub.process_hub_ev().await.unwrap(), 1);
+
+        // The frames without reference are not sent
+        for ts in [80, 1000, 2000] {
+            hub.on_frame(video(ts, INTERFRAME)).unwrap();
+        }
+        let timeout = Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, rx.recv()).await.is_err());
+
+        // Start from the next key frame with the sequence header
+        hub.on_frame(video(3000, KEYFRAME)).unwrap();
+        let msgs = rx.recv().await.unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert!(
+            matches!(&msgs[0], RtmpMessage::VideoData { payload, .. } if payload[..] == *AVC_SH)
+        );
+        assert!(msgs[1].is_key_frame());
+        assert_eq!(msgs[1].timestamp(), Some(3000));
+
+        hub.on_frame(video(3040, INTERFRAME)).unwrap();
+        hub.on_frame(video(4000, KEYFRAME)).unwrap();
+        let msgs = rx.recv().await.unwrap();
+        assert_eq!(msgs[0].timestamp(), Some(3040));
+        // Not reported as the slow consumer
+        assert!(stat_rx.try_recv().is_err());
     }
 }
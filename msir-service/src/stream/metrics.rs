@@ -0,0 +1,154 @@
+use crate::statistic::RealtimeStat;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const METRICS_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Sample {
+    at: Instant,
+    bytes: usize,
+    is_video: bool,
+    // |arrival interval - timestamp interval| in ms, to the previous frame of the same type
+    jitter: Option<u64>,
+}
+
+// Rolling metrics of the frames in the last METRICS_WINDOW
+#[derive(Debug)]
+pub struct StreamMetrics {
+    samples: VecDeque<Sample>,
+    started: Option<Instant>,
+    last_video: Option<(Instant, u32)>,
+    last_audio: Option<(Instant, u32)>,
+    last_keyframe_ts: Option<u32>,
+    // Measured keyframe interval by timestamp
+    gop_ms: u32,
+}
+
+impl StreamMetrics {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(512),
+            started: None,
+            last_video: None,
+            last_audio: None,
+            last_keyframe_ts: None,
+            gop_ms: 0,
+        }
+    }
+
+    pub fn on_frame(&mut self, now: Instant, is_video: bool, timestamp: u32, bytes: usize) {
+        self.started.get_or_insert(now);
+        let last = match is_video {
+            true => self.last_video.replace((now, timestamp)),
+            false => self.last_audio.replace((now, timestamp)),
+        };
+        let jitter = last.map(|(at, ts)| {
+            let arrival = now.duration_since(at).as_millis() as i64;
+            (arrival - timestamp.wrapping_sub(ts) as i32 as i64).unsigned_abs()
+        });
+        self.samples.push_back(Sample {
+            at: now,
+            bytes,
+            is_video,
+            jitter,
+        });
+        self.expire(now);
+    }
+
+    pub fn on_keyframe(&mut self, timestamp: u32) {
+        if let Some(last) = self.last_keyframe_ts.replace(timestamp) {
+            self.gop_ms = timestamp.saturating_sub(last);
+        }
+    }
+
+    // The rates decay to 0 if the publisher stops sending
+    pub fn snapshot(&mut self, now: Instant) -> RealtimeStat {
+        self.expire(now);
+        let secs = match self.started {
+            Some(started) => now
+                .duration_since(started)
+                .min(METRICS_WINDOW)
+                .as_secs_f64(),
+            None => 0.0,
+        };
+        let (mut video_bytes, mut audio_bytes, mut video_frames) = (0, 0, 0);
+        let (mut jitter_sum, mut jitter_count) = (0, 0);
+        for sample in self.samples.iter() {
+            match sample.is_video {
+                true => {
+                    video_bytes += sample.bytes;
+                    video_frames += 1;
+                }
+                false => audio_bytes += sample.bytes,
+            }
+            if let Some(jitter) = sample.jitter {
+                jitter_sum += jitter;
+                jitter_count += 1;
+            }
+        }
+        let per_sec = |v: f64| match secs > 0.0 {
+            true => (v / secs * 100.0).round() / 100.0,
+            false => 0.0,
+        };
+        RealtimeStat {
+            video_kbps: per_sec(video_bytes as f64 * 8.0 / 1000.0),
+            audio_kbps: per_sec(audio_bytes as f64 * 8.0 / 1000.0),
+            fps: per_sec(video_frames as f64),
+            gop_ms: self.gop_ms,
+            jitter_ms: match jitter_count {
+                0 => 0,
+                n => jitter_sum / n,
+            },
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(sample) = self.samples.front() {
+            if now.duration_since(sample.at) <= METRICS_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl Default for StreamMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_metrics() {
+        let start = Instant::now();
+        let mut metrics = StreamMetrics::new();
+        // 25fps video of 5000 bytes and 50fps audio of 200 bytes in 4s, keyframe every 2s
+        for i in 0..100_u32 {
+            let at = start + Duration::from_millis(i as u64 * 40);
+            if i % 50 == 0 {
+                metrics.on_keyframe(i * 40);
+            }
+            metrics.on_frame(at, true, i * 40, 5000);
+            metrics.on_frame(at, false, i * 40, 200);
+            metrics.on_frame(at + Duration::from_millis(20), false, i * 40 + 20, 200);
+        }
+        let stat = metrics.snapshot(start + Duration::from_secs(4));
+        assert_eq!(stat.fps, 25.0);
+        assert_eq!(stat.video_kbps, 1000.0);
+        assert_eq!(stat.audio_kbps, 80.0);
+        assert_eq!(stat.gop_ms, 2000);
+        assert_eq!(stat.jitter_ms, 0);
+
+        // The rates collapse after the publisher stops
+        let stat = metrics.snapshot(start + Duration::from_secs(10));
+        assert_eq!(stat.fps, 0.0);
+        assert_eq!(stat.video_kbps, 0.0);
+    }
+}
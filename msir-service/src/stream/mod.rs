@@ -1,14 +1,18 @@
 use crate::{
+    dvr::{ConnToDvrChanTx, DvrEvent},
+    edge::Origins,
+    forward::{ConnToForwardChanTx, ForwardEvent},
     rtmp_pull::{start_pull_task, RtmpPull},
     statistic::ConnToStatChanTx,
-    utils, STATIC_PULL_ADDRESS,
+    utils,
+    vhost::Vhosts,
 };
 
 use self::{
     error::StreamError,
     hub::{Hub, HubEvent},
+    queue::{QueueConfig, SubscriberRx, SubscriberTx},
 };
-use rtmp::message::RtmpMessage;
 use std::collections::HashMap;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, warn, Instrument};
@@ -16,9 +20,12 @@ use tracing::{debug, error, info, trace, warn, Instrument};
 pub mod error;
 pub mod gop;
 pub mod hub;
+pub mod metrics;
+pub mod queue;
 
-type HubToSubsChanTx = mpsc::UnboundedSender<Vec<RtmpMessage>>;
-type HubToSubsChanRx = mpsc::UnboundedReceiver<Vec<RtmpMessage>>;
+// Bounded, the Hub handles the slow subscriber by the policy of app
+type HubToSubsChanTx = SubscriberTx;
+pub type HubToSubsChanRx = SubscriberRx;
 
 type MgrToHubChanTx = mpsc::UnboundedSender<HubEvent>;
 type MgrToHubChanRx = mpsc::UnboundedReceiver<HubEvent>;
@@ -26,6 +33,10 @@ type MgrToHubChanRx = mpsc::UnboundedReceiver<HubEvent>;
 pub type ConnToMgrChanTx = mpsc::UnboundedSender<StreamEvent>;
 pub type ConnToMgrChanRx = mpsc::UnboundedReceiver<StreamEvent>;
 
+// Close the connection gracefully, e.g. kicked by the API
+pub type KickTx = oneshot::Sender<()>;
+pub type KickRx = oneshot::Receiver<()>;
+
 #[derive(Debug)]
 pub enum RoleType {
     Subscriber,
@@ -45,6 +56,8 @@ pub struct RegisterEv {
     pub role: RoleType,
     pub stream_key: String,
     pub ret: oneshot::Sender<Token>,
+    // None if the connection can not be kicked
+    pub kick: Option<KickTx>,
 }
 
 pub struct UnregisterEv {
@@ -56,26 +69,51 @@ pub struct UnregisterEv {
 pub enum StreamEvent {
     Register(RegisterEv),
     Unregister(UnregisterEv),
+    // Kick the client by uid, response whether it is found
+    KickClient(String, oneshot::Sender<bool>),
+    // Kick the publisher and all the subscribers of the stream, response the number of kicked
+    KickStream(String, oneshot::Sender<usize>),
 }
 
 pub struct Manager {
     pool: HashMap<String, MgrToHubChanTx>,
+    // uid => (stream_key, kick_tx)
+    clients: HashMap<String, (String, KickTx)>,
     conn_rx: ConnToMgrChanRx,
     conn_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    dvr_tx: Option<ConnToDvrChanTx>,
+    forward_tx: Option<ConnToForwardChanTx>,
+    // Pull from origins when no publisher, None if not an edge
+    edge: Option<Origins>,
+    queue: QueueConfig,
+    // The gop cache by vhost
+    vhosts: Vhosts,
 }
 
 impl Manager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         conn_rx: ConnToMgrChanRx,
         conn_tx: ConnToMgrChanTx,
         stat_tx: ConnToStatChanTx,
+        dvr_tx: Option<ConnToDvrChanTx>,
+        forward_tx: Option<ConnToForwardChanTx>,
+        edge: Option<Origins>,
+        queue: QueueConfig,
+        vhosts: Vhosts,
     ) -> Self {
         Self {
             conn_rx,
             conn_tx,
             stat_tx,
+            dvr_tx,
+            forward_tx,
+            edge,
+            queue,
+            vhosts,
             pool: HashMap::new(),
+            clients: HashMap::new(),
         }
     }
 
@@ -84,12 +122,19 @@ impl Manager {
             match ev {
                 StreamEvent::Register(ev) => self.register(ev).await,
                 StreamEvent::Unregister(ev) => self.unregister(ev).await,
+                StreamEvent::KickClient(uid, ret) => {
+                    let _ = ret.send(self.kick_client(&uid));
+                }
+                StreamEvent::KickStream(stream_key, ret) => {
+                    let _ = ret.send(self.kick_stream(&stream_key));
+                }
             }
         }
         Ok(())
     }
 
     async fn register(&mut self, ev: RegisterEv) {
+        let (uid, stream_key) = (ev.uid.clone(), ev.stream_key.clone());
         let hub_ev_tx = self.pool.get(&ev.stream_key);
         debug!(
             "Recv register {} {:?} {} exist {}",
@@ -104,38 +149,54 @@ impl Manager {
                     Token::Failure(StreamError::DuplicatePublish)
                 } else {
                     let (tx, rx) = mpsc::unbounded_channel();
+                    if let Some(dvr_tx) = &self.dvr_tx {
+                        let _ = dvr_tx.send(DvrEvent::Publish(ev.stream_key.clone()));
+                    }
+                    if let Some(forward_tx) = &self.forward_tx {
+                        let _ = forward_tx.send(ForwardEvent::Publish(ev.stream_key.clone()));
+                    }
+                    let policy = self.queue.policy(app_of(&ev.stream_key));
+                    let gop_cache = self.vhosts.of_stream(&ev.stream_key).gop_cache;
                     self.pool.insert(ev.stream_key, tx);
-                    Token::PublisherToken(Hub::new(rx))
+                    Token::PublisherToken(
+                        Hub::new(rx, policy, self.stat_tx.clone()).with_gop_cache(gop_cache),
+                    )
                 }
             }
             RoleType::Subscriber => {
                 if let Some(hub_ev_tx) = hub_ev_tx {
-                    let (tx, rx) = mpsc::unbounded_channel();
+                    let (tx, rx) = queue::channel(self.queue.size);
                     if let Err(_) = hub_ev_tx.send(HubEvent::SubscriberJoin(ev.uid, tx)) {
                         Token::Failure(StreamError::DisconnectHub)
                     } else {
                         Token::SubscriberToken(rx)
                     }
-                } else {
-                    // Token::Failure(StreamError::NoPublish)
+                } else if let Some(edge) = &self.edge {
+                    let origins = edge.select(&ev.stream_key);
                     let (hub_tx, hub_rx) = mpsc::unbounded_channel();
                     self.pool.insert(ev.stream_key.clone(), hub_tx.clone());
                     // New rtmp client
                     let uid = utils::gen_uid();
+                    let (kick_tx, kick_rx) = oneshot::channel();
+                    self.clients
+                        .insert(uid.clone(), (ev.stream_key.clone(), kick_tx));
                     let mut rtmp = RtmpPull::new(
                         uid.clone(),
-                        Hub::new(hub_rx),
+                        Hub::new(
+                            hub_rx,
+                            self.queue.policy(app_of(&ev.stream_key)),
+                            self.stat_tx.clone(),
+                        )
+                        .with_gop_cache(self.vhosts.of_stream(&ev.stream_key).gop_cache),
                         self.conn_tx.clone(),
                         self.stat_tx.clone(),
+                        kick_rx,
                     );
                     rtmp.on_create_conn(ev.stream_key.clone());
-                    let vecs: Vec<&str> = ev.stream_key.split('/').collect();
-                    let tc_url = format!("{}/{}", STATIC_PULL_ADDRESS, vecs[1]);
-                    let stream = vecs[2].to_string();
                     let stream_key = ev.stream_key;
                     tokio::spawn(
                         async move {
-                            if let Err(e) = start_pull_task(&mut rtmp, tc_url, stream).await {
+                            if let Err(e) = start_pull_task(&mut rtmp, origins, &stream_key).await {
                                 error!("Failed to transfer; error={}", e);
                             }
                             rtmp.on_delete_conn(stream_key.clone());
@@ -144,15 +205,20 @@ impl Manager {
                         .instrument(tracing::info_span!("RTMP-PULL", uid)),
                     );
 
-                    let (sub_tx, sub_rx) = mpsc::unbounded_channel();
+                    let (sub_tx, sub_rx) = queue::channel(self.queue.size);
                     if let Err(_) = hub_tx.send(HubEvent::SubscriberJoin(ev.uid, sub_tx)) {
                         Token::Failure(StreamError::DisconnectHub)
                     } else {
                         Token::SubscriberToken(sub_rx)
                     }
+                } else {
+                    Token::Failure(StreamError::NoPublish)
                 }
             }
         };
+        if let (false, Some(kick)) = (matches!(token, Token::Failure(_)), ev.kick) {
+            self.clients.insert(uid, (stream_key, kick));
+        }
         if let Err(_) = ev.ret.send(token) {
             error!("Response token falied");
         }
@@ -161,6 +227,7 @@ impl Manager {
     async fn unregister(&mut self, ev: UnregisterEv) {
         let hub_ev_tx = self.pool.get(&ev.stream_key);
         debug!("Recv unregister {} {:?} {}", ev.uid, ev.role, ev.stream_key);
+        self.clients.remove(&ev.uid);
 
         if let Some(hub_ev_tx) = hub_ev_tx {
             match ev.role {
@@ -175,4 +242,36 @@ impl Manager {
             }
         }
     }
+
+    fn kick_client(&mut self, uid: &str) -> bool {
+        match self.clients.remove(uid) {
+            Some((stream_key, kick)) => {
+                info!("Kick client {} of {}", uid, stream_key);
+                let _ = kick.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn kick_stream(&mut self, stream_key: &str) -> usize {
+        let uids: Vec<String> = self
+            .clients
+            .iter()
+            .filter(|(_, (key, _))| key == stream_key)
+            .map(|(uid, _)| uid.clone())
+            .collect();
+        info!("Kick stream {} with {} clients", stream_key, uids.len());
+        for uid in uids.iter() {
+            if let Some((_, kick)) = self.clients.remove(uid) {
+                let _ = kick.send(());
+            }
+        }
+        uids.len()
+    }
+}
+
+// The stream key is {vhost}/{app}/{stream}
+fn app_of(stream_key: &str) -> &str {
+    stream_key.split('/').nth(1).unwrap_or("")
 }
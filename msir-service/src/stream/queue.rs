@@ -0,0 +1,240 @@
+use crate::statistic::SlowStat;
+use httpflv::{error::FlvMuxerError, FlvTags};
+use rtmp::{
+    connection::{error::ConnectionError, EncodedChunks},
+    message::RtmpMessage,
+};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+// What the Hub does when the queue of a subscriber is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowPolicy {
+    // Drop the frames until the next keyframe
+    DropFrames,
+    // Discard the queued frames and restart from the gop cache
+    SkipToLive,
+    // Close the subscriber
+    Disconnect,
+}
+
+impl SlowPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "drop" => Some(Self::DropFrames),
+            "skip" => Some(Self::SkipToLive),
+            "disconnect" => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::DropFrames => "drop",
+            Self::SkipToLive => "skip",
+            Self::Disconnect => "disconnect",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    // Max number of the merged batches queued for a subscriber
+    pub size: usize,
+    pub policy: SlowPolicy,
+    // Policy by app, override the default one
+    pub apps: HashMap<String, SlowPolicy>,
+}
+
+impl QueueConfig {
+    pub fn policy(&self, app: &str) -> SlowPolicy {
+        self.apps.get(app).copied().unwrap_or(self.policy)
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            policy: SlowPolicy::DropFrames,
+            apps: HashMap::new(),
+        }
+    }
+}
+
+// Merged messages built once by the Hub and shared by all the subscribers
+#[derive(Debug, Default)]
+pub struct Batch {
+    msgs: Vec<RtmpMessage>,
+    // Encoded by the first HTTP-FLV player, indexed by legacy_hevc
+    flv: [OnceLock<FlvTags>; 2],
+    // Encoded by the first RTMP player of every out chunk size
+    rtmp: Mutex<Vec<(usize, Arc<EncodedChunks>)>>,
+}
+
+impl Batch {
+    pub fn new(msgs: Vec<RtmpMessage>) -> Arc<Self> {
+        Arc::new(Self {
+            msgs,
+            flv: Default::default(),
+            rtmp: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn flv_tags(&self, legacy_hevc: bool) -> Result<&FlvTags, FlvMuxerError> {
+        let cell = &self.flv[legacy_hevc as usize];
+        if let Some(tags) = cell.get() {
+            return Ok(tags);
+        }
+        // Maybe encoded twice by the racing players, only one is kept
+        let tags = httpflv::encode_tags(&self.msgs, legacy_hevc)?;
+        Ok(cell.get_or_init(|| tags))
+    }
+
+    pub fn rtmp_chunks(&self, chunk_size: usize) -> Result<Arc<EncodedChunks>, ConnectionError> {
+        let find = |cache: &Vec<(usize, Arc<EncodedChunks>)>| {
+            cache
+                .iter()
+                .find(|(size, _)| *size == chunk_size)
+                .map(|(_, chunks)| chunks.clone())
+        };
+        if let Some(chunks) = find(&self.rtmp.lock().unwrap()) {
+            return Ok(chunks);
+        }
+        // Encode without the lock, maybe twice by the racing players
+        let chunks = Arc::new(EncodedChunks::encode(&self.msgs, chunk_size)?);
+        let mut cache = self.rtmp.lock().unwrap();
+        match find(&cache) {
+            Some(chunks) => Ok(chunks),
+            None => {
+                cache.push((chunk_size, chunks.clone()));
+                Ok(chunks)
+            }
+        }
+    }
+}
+
+impl Deref for Batch {
+    type Target = [RtmpMessage];
+
+    fn deref(&self) -> &Self::Target {
+        &self.msgs
+    }
+}
+
+// Every batch is tagged by a sequence, the ones before skip_to are discarded by the subscriber
+type Tagged = (u64, Arc<Batch>);
+
+pub fn channel(size: usize) -> (SubscriberTx, SubscriberRx) {
+    let (tx, rx) = mpsc::channel(size.max(1));
+    let skip_to = Arc::new(AtomicU64::new(0));
+    (
+        SubscriberTx {
+            tx,
+            skip_to: skip_to.clone(),
+            seq: 0,
+            lagging: false,
+            slow: SlowStat::default(),
+        },
+        SubscriberRx { rx, skip_to },
+    )
+}
+
+#[derive(Debug)]
+pub struct SubscriberTx {
+    tx: mpsc::Sender<Tagged>,
+    skip_to: Arc<AtomicU64>,
+    seq: u64,
+    // Whether the frames are dropped since the queue was full
+    pub lagging: bool,
+    pub slow: SlowStat,
+}
+
+impl SubscriberTx {
+    pub fn try_send(&mut self, msgs: Arc<Batch>) -> Result<(), TrySendError<Arc<Batch>>> {
+        match self.tx.try_send((self.seq, msgs)) {
+            Ok(_) => {
+                self.seq += 1;
+                Ok(())
+            }
+            Err(TrySendError::Full((_, msgs))) => Err(TrySendError::Full(msgs)),
+            Err(TrySendError::Closed((_, msgs))) => Err(TrySendError::Closed(msgs)),
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.tx.capacity() > 0
+    }
+
+    // The subscriber discards all the queued batches
+    pub fn skip_queued(&self) {
+        self.skip_to.store(self.seq, Ordering::Release);
+    }
+
+    // The subscriber discards all the queued batches and gets closed
+    pub fn close(self) {
+        self.skip_to.store(u64::MAX, Ordering::Release);
+    }
+}
+
+#[derive(Debug)]
+pub struct SubscriberRx {
+    rx: mpsc::Receiver<Tagged>,
+    skip_to: Arc<AtomicU64>,
+}
+
+impl SubscriberRx {
+    // Cancel safe, None if the publisher is gone or the subscriber is disconnected
+    pub async fn recv(&mut self) -> Option<Arc<Batch>> {
+        loop {
+            let (seq, msgs) = self.rx.recv().await?;
+            if seq >= self.skip_to.load(Ordering::Acquire) {
+                return Some(msgs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn batch(ts: u32) -> Arc<Batch> {
+        Batch::new(vec![RtmpMessage::Acknowledgement {
+            sequence_number: ts,
+        }])
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_queue() {
+        let (mut tx, mut rx) = channel(2);
+        assert!(tx.try_send(batch(1)).is_ok());
+        assert!(tx.try_send(batch(2)).is_ok());
+        assert!(!tx.has_room());
+        assert!(matches!(tx.try_send(batch(3)), Err(TrySendError::Full(_))));
+
+        // The queued batches are discarded after skipped
+        tx.skip_queued();
+        let timeout = Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, rx.recv()).await.is_err());
+        assert!(tx.has_room());
+        assert!(tx.try_send(batch(4)).is_ok());
+        let msgs = rx.recv().await.unwrap();
+        assert!(matches!(
+            msgs[0],
+            RtmpMessage::Acknowledgement { sequence_number: 4 }
+        ));
+
+        assert!(tx.try_send(batch(5)).is_ok());
+        tx.close();
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -1,4 +1,6 @@
+use hyper::{header, Body, Request};
 use rand::Rng;
+use rtmp::message::request::ClientInfo;
 
 pub fn gen_uid() -> String {
     rand::thread_rng()
@@ -7,3 +9,21 @@ pub fn gen_uid() -> String {
         .map(char::from)
         .collect::<String>()
 }
+
+// The addresses are inserted by the HTTP server
+pub fn http_client_info(req: &Request<Body>) -> ClientInfo {
+    let mut client = req
+        .extensions()
+        .get::<ClientInfo>()
+        .cloned()
+        .unwrap_or_default();
+    let get = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    client.user_agent = get(header::USER_AGENT);
+    client.referer = get(header::REFERER);
+    client
+}
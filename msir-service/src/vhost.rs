@@ -0,0 +1,105 @@
+use crate::{forward::ForwardConfig, hls::HlsConfig, hook::Hooks};
+use rtmp::message::request::Request;
+use std::{collections::HashMap, sync::Arc};
+
+// The settings of a tenant, the default vhost is from the global config
+#[derive(Clone)]
+pub struct Vhost {
+    pub gop_cache: bool,
+    pub flv: bool,
+    // None if disabled
+    pub hls: Option<HlsConfig>,
+    pub forward: Option<ForwardConfig>,
+    // With the auth
+    pub hooks: Hooks,
+}
+
+impl Default for Vhost {
+    fn default() -> Self {
+        Self {
+            gop_cache: true,
+            flv: false,
+            hls: None,
+            forward: None,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Vhosts {
+    default: Arc<Vhost>,
+    vhosts: Arc<HashMap<String, Vhost>>,
+}
+
+impl Vhosts {
+    pub fn new(default: Vhost, vhosts: HashMap<String, Vhost>) -> Self {
+        Self {
+            default: Arc::new(default),
+            vhosts: Arc::new(vhosts),
+        }
+    }
+
+    // Set the vhost of request, the unknown one is served by the default vhost
+    pub fn resolve(&self, req: &mut Request) -> &Vhost {
+        req.vhost = req
+            .vhost_hint()
+            .filter(|v| self.vhosts.contains_key(*v))
+            .map(|v| v.to_string());
+        self.get(req.vhost.as_deref())
+    }
+
+    pub fn get(&self, vhost: Option<&str>) -> &Vhost {
+        vhost
+            .and_then(|v| self.vhosts.get(v))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn of_stream(&self, stream_key: &str) -> &Vhost {
+        self.get(vhost_of(stream_key))
+    }
+
+    // Whether the feature is enabled by any vhost
+    pub fn any(&self, f: impl Fn(&Vhost) -> bool) -> bool {
+        f(&self.default) || self.vhosts.values().any(f)
+    }
+}
+
+// The stream key is {vhost}/{app}/{stream}, the vhost is empty for the default one
+pub fn vhost_of(stream_key: &str) -> Option<&str> {
+    stream_key.split('/').next().filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let vhost = Vhost {
+            gop_cache: false,
+            ..Default::default()
+        };
+        let vhosts = Vhosts::new(
+            Vhost::default(),
+            HashMap::from([("a.com".to_string(), vhost)]),
+        );
+
+        let mut req = Request::parse_from("rtmp://a.com/live/stream".to_string()).unwrap();
+        assert!(!vhosts.resolve(&mut req).gop_cache);
+        assert_eq!(req.stream_key(), "a.com/live/stream");
+        assert!(!vhosts.of_stream(&req.stream_key()).gop_cache);
+
+        // Not configured
+        let mut req = Request::parse_from("rtmp://b.com/live/stream".to_string()).unwrap();
+        assert!(vhosts.resolve(&mut req).gop_cache);
+        assert_eq!(req.stream_key(), "/live/stream");
+        assert!(vhosts.of_stream(&req.stream_key()).gop_cache);
+
+        let mut req =
+            Request::parse_from("rtmp://127.0.0.1/live/stream?vhost=a.com".to_string()).unwrap();
+        vhosts.resolve(&mut req);
+        assert_eq!(req.vhost.as_deref(), Some("a.com"));
+        assert!(vhosts.any(|v| !v.gop_cache));
+    }
+}
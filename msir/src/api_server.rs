@@ -1,16 +1,17 @@
 use crate::config::ApiConfig;
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use msir_service::{
+    dvr::{ConnToDvrChanTx, DvrEvent},
     statistic::{ConnStat, ConnToStatChanTx, StatEvent, StreamStat, SummariesStat},
-    stream::ConnToMgrChanTx,
+    stream::{ConnToMgrChanTx, StreamEvent},
 };
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::sync::oneshot;
 use tracing::info;
@@ -37,11 +38,30 @@ enum ApiRespData {
 
     #[serde(rename(serialize = "streams"))]
     Streams(HashMap<String, StreamStat>),
+
+    #[serde(rename(serialize = "dvr"))]
+    Dvr(String),
+
+    #[serde(rename(serialize = "kicked"))]
+    Kicked(usize),
+}
+
+// The stream of a vhost, e.g. /dvr/live/stream?vhost=a.com
+#[derive(Debug, Deserialize)]
+struct VhostQuery {
+    vhost: Option<String>,
+}
+
+impl VhostQuery {
+    fn stream_key(&self, app: &str, stream: &str) -> String {
+        format!("{}/{}/{}", self.vhost.as_deref().unwrap_or(""), app, stream)
+    }
 }
 
 pub async fn api_server_start(
     stream_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    dvr_tx: Option<ConnToDvrChanTx>,
     config: &ApiConfig,
 ) -> Result<()> {
     if !config.enabled {
@@ -55,9 +75,14 @@ pub async fn api_server_start(
                 .route("/", get(api_root))
                 .route("/summaries", get(api_summaries))
                 .route("/clients", get(api_clients))
-                .route("/client/:cid", get(api_client_byid))
+                .route("/client/:cid", get(api_client_byid).delete(api_kick_client))
+                .route("/forwards", get(api_forwards))
                 .route("/streams", get(api_streams))
-                .route("/stream/:sid", get(api_stream_byid))
+                .route("/stream/:sid", get(api_stream_byid).delete(api_kick_stream))
+                .route(
+                    "/dvr/:app/:stream",
+                    post(api_dvr_start).delete(api_dvr_stop).with_state(dvr_tx),
+                )
                 .with_state((stream_tx, stat_tx.clone())),
         )
         .route("/metrics", get(metrics_handle))
@@ -86,7 +111,11 @@ async fn api_root() -> impl IntoResponse {
     );
     urls.insert(
         "/client/:cid".to_string(),
-        "the specified client info of instance".to_string(),
+        "the specified client info(GET) or kick it(DELETE)".to_string(),
+    );
+    urls.insert(
+        "/forwards".to_string(),
+        "all of the forwarders info of instance".to_string(),
     );
     urls.insert(
         "/streams".to_string(),
@@ -94,7 +123,11 @@ async fn api_root() -> impl IntoResponse {
     );
     urls.insert(
         "/stream/:sid".to_string(),
-        "the specified stream info of instance".to_string(),
+        "the specified stream info(GET) or kick it with all the players(DELETE)".to_string(),
+    );
+    urls.insert(
+        "/dvr/:app/:stream".to_string(),
+        "start(POST) or stop(DELETE) recording the specified stream".to_string(),
     );
     Json(ApiResp {
         code: 0,
@@ -156,6 +189,34 @@ async fn api_clients(
     }
 }
 
+async fn api_forwards(
+    State((_, stat_tx)): State<(ConnToMgrChanTx, ConnToStatChanTx)>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+    let query = StatEvent::QueryConn(String::new(), tx);
+
+    if stat_tx.send(query).is_err() {
+        return Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        });
+    }
+
+    match rx.await {
+        Ok(mut res) => {
+            res.retain(|_, c| c.forward.is_some());
+            Json(ApiResp {
+                code: 0,
+                data: ApiRespData::Clients(res),
+            })
+        }
+        Err(_) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        }),
+    }
+}
+
 async fn api_client_byid(
     Path(cid): Path<String>,
     State((_, stat_tx)): State<(ConnToMgrChanTx, ConnToStatChanTx)>,
@@ -184,6 +245,70 @@ async fn api_client_byid(
     }
 }
 
+async fn api_kick_client(
+    Path(cid): Path<String>,
+    State((stream_tx, _)): State<(ConnToMgrChanTx, ConnToStatChanTx)>,
+) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+    if stream_tx.send(StreamEvent::KickClient(cid, tx)).is_err() {
+        return Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        });
+    }
+
+    match rx.await {
+        Ok(true) => Json(ApiResp {
+            code: 0,
+            data: ApiRespData::Kicked(1),
+        }),
+        Ok(false) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("client not found".to_string()),
+        }),
+        Err(_) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        }),
+    }
+}
+
+// The sid is the stream key, e.g. live%2Fstream for /live/stream, a.com%2Flive%2Fstream for vhost
+async fn api_kick_stream(
+    Path(sid): Path<String>,
+    State((stream_tx, _)): State<(ConnToMgrChanTx, ConnToStatChanTx)>,
+) -> impl IntoResponse {
+    let stream_key = match sid.matches('/').count() {
+        1 if !sid.starts_with('/') => format!("/{}", sid),
+        _ => sid,
+    };
+    let (tx, rx) = oneshot::channel();
+    if stream_tx
+        .send(StreamEvent::KickStream(stream_key, tx))
+        .is_err()
+    {
+        return Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        });
+    }
+
+    match rx.await {
+        Ok(0) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("stream not found".to_string()),
+        }),
+        Ok(n) => Json(ApiResp {
+            code: 0,
+            data: ApiRespData::Kicked(n),
+        }),
+        Err(_) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        }),
+    }
+}
+
 async fn api_streams(
     State((_, stat_tx)): State<(ConnToMgrChanTx, ConnToStatChanTx)>,
 ) -> impl IntoResponse {
@@ -239,6 +364,69 @@ async fn api_stream_byid(
     }
 }
 
+async fn api_dvr_start(
+    Path((app, stream)): Path<(String, String)>,
+    Query(query): Query<VhostQuery>,
+    State(dvr_tx): State<Option<ConnToDvrChanTx>>,
+) -> impl IntoResponse {
+    api_dvr_request(query.stream_key(&app, &stream), dvr_tx, true).await
+}
+
+async fn api_dvr_stop(
+    Path((app, stream)): Path<(String, String)>,
+    Query(query): Query<VhostQuery>,
+    State(dvr_tx): State<Option<ConnToDvrChanTx>>,
+) -> impl IntoResponse {
+    api_dvr_request(query.stream_key(&app, &stream), dvr_tx, false).await
+}
+
+async fn api_dvr_request(
+    stream_key: String,
+    dvr_tx: Option<ConnToDvrChanTx>,
+    start: bool,
+) -> Json<ApiResp> {
+    let dvr_tx = match dvr_tx {
+        Some(tx) => tx,
+        None => {
+            return Json(ApiResp {
+                code: -1,
+                data: ApiRespData::Error("dvr is disabled".to_string()),
+            })
+        }
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let query = match start {
+        true => DvrEvent::Start(stream_key.clone(), tx),
+        false => DvrEvent::Stop(stream_key.clone(), tx),
+    };
+
+    if dvr_tx.send(query).is_err() {
+        return Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        });
+    }
+
+    match rx.await {
+        Ok(true) => Json(ApiResp {
+            code: 0,
+            data: ApiRespData::Dvr(stream_key),
+        }),
+        Ok(false) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error(match start {
+                true => "stream is already recording".to_string(),
+                false => "stream is not recording".to_string(),
+            }),
+        }),
+        Err(_) => Json(ApiResp {
+            code: -1,
+            data: ApiRespData::Error("internal error".to_string()),
+        }),
+    }
+}
+
 async fn metrics_handle(State(stat_tx): State<ConnToStatChanTx>) -> impl IntoResponse {
     let (tx, rx) = oneshot::channel();
     let query = StatEvent::QueryMetrics(tx);
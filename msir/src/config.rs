@@ -1,6 +1,6 @@
 use anyhow::Result;
 use serde_derive::Deserialize;
-use std::fs;
+use std::{collections::HashMap, fs};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
@@ -9,6 +9,11 @@ pub struct Config {
     pub rtmp: Option<RtmpConfig>,
     pub http: Option<HttpConfig>,
     pub api: Option<ApiConfig>,
+    pub dvr: Option<DvrConfig>,
+    pub hook: Option<HookConfig>,
+    pub auth: Option<AuthConfig>,
+    // By the name of vhost, e.g. [vhost."a.example.com"]
+    pub vhost: Option<HashMap<String, VhostConfig>>,
 }
 
 impl Config {
@@ -19,6 +24,10 @@ impl Config {
             rtmp: Some(RtmpConfig::default()),
             http: Some(HttpConfig::default()),
             api: Some(ApiConfig::default()),
+            dvr: Some(DvrConfig::default()),
+            hook: Some(HookConfig::default()),
+            auth: None,
+            vhost: None,
         }
     }
 
@@ -58,6 +67,23 @@ impl Config {
                 Some(a.clone())
             }
         };
+        self.dvr = match &mut self.dvr {
+            None => Some(DvrConfig::default()),
+            Some(d) => {
+                d.fill_default();
+                Some(d.clone())
+            }
+        };
+        self.hook = match &mut self.hook {
+            None => Some(HookConfig::default()),
+            Some(h) => {
+                h.fill_default();
+                Some(h.clone())
+            }
+        };
+        for vhost in self.vhost.iter_mut().flat_map(|v| v.values_mut()) {
+            vhost.fill_default();
+        }
     }
 }
 
@@ -96,28 +122,103 @@ impl LogConfig {
 #[derive(Debug, Deserialize, Clone)]
 pub struct RtmpConfig {
     pub listen: String,
+    // Expect the PROXY protocol v1/v2 header from the load balancer
+    pub proxy_protocol: Option<bool>,
     performance: Option<String>,
+    pub edge: Option<RtmpEdge>,
+    pub forward: Option<RtmpForward>,
+    pub queue: Option<RtmpQueue>,
+    pub tls: Option<RtmpTls>,
+    pub gop_cache: Option<bool>,
 }
 
 impl RtmpConfig {
     fn default() -> Self {
         Self {
             listen: "0.0.0.0:1935".to_string(),
+            proxy_protocol: None,
             performance: Some("middle".to_string()),
+            edge: None,
+            forward: None,
+            queue: None,
+            tls: None,
+            gop_cache: Some(true),
         }
     }
     fn fill_default(&mut self) {
         if let None = self.performance {
             self.performance = Some("middle".to_string())
         }
+        if let Some(edge) = self.edge.as_mut() {
+            edge.fill_default();
+        }
+        if let Some(tls) = self.tls.as_mut() {
+            tls.fill_default();
+        }
+        if self.gop_cache.is_none() {
+            self.gop_cache = Some(true)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RtmpEdge {
+    pub enabled: bool,
+    pub origins: Vec<String>,
+    // round_robin, hash or backup
+    pub policy: Option<String>,
+}
+
+impl RtmpEdge {
+    fn fill_default(&mut self) {
+        if self.policy.is_none() {
+            self.policy = Some("round_robin".to_string())
+        }
     }
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct RtmpForward {
+    pub enabled: bool,
+    // e.g. rtmp://cdn.example.com/{app}/{stream}
+    pub destinations: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RtmpTls {
+    pub enabled: bool,
+    pub listen: Option<String>,
+    pub proxy_protocol: Option<bool>,
+    // PEM files of the certificate chain and the private key
+    pub cert: String,
+    pub key: String,
+}
+
+impl RtmpTls {
+    fn fill_default(&mut self) {
+        if self.listen.is_none() {
+            self.listen = Some("0.0.0.0:443".to_string())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RtmpQueue {
+    // Max number of merged batches queued for a subscriber
+    pub size: Option<usize>,
+    // drop, skip or disconnect
+    pub policy: Option<String>,
+    // Policy by app
+    pub apps: Option<HashMap<String, String>>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct HttpConfig {
     pub enabled: bool,
     pub listen: Option<String>,
+    pub proxy_protocol: Option<bool>,
     pub flv: Option<HttpFlv>,
+    pub ts: Option<HttpTs>,
     pub hls: Option<HttpHls>,
 }
 
@@ -126,7 +227,9 @@ impl HttpConfig {
         Self {
             enabled: false,
             listen: Some("0.0.0.0:8080".to_string()),
+            proxy_protocol: None,
             flv: None,
+            ts: None,
             hls: None,
         }
     }
@@ -134,6 +237,9 @@ impl HttpConfig {
         if let None = self.listen {
             self.listen = Some("0.0.0.0:8080".to_string())
         }
+        if let Some(hls) = self.hls.as_mut() {
+            hls.fill_default();
+        }
     }
 }
 
@@ -142,9 +248,28 @@ pub struct HttpFlv {
     pub enabled: bool,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpTs {
+    pub enabled: bool,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct HttpHls {
     pub enabled: bool,
+    // In seconds
+    pub fragment: Option<u32>,
+    pub window: Option<u32>,
+}
+
+impl HttpHls {
+    fn fill_default(&mut self) {
+        if self.fragment.is_none() {
+            self.fragment = Some(10)
+        }
+        if self.window.is_none() {
+            self.window = Some(60)
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -167,6 +292,111 @@ impl ApiConfig {
     }
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct DvrConfig {
+    pub enabled: bool,
+    pub path: Option<String>,
+    // Record every publish automatically
+    pub auto: Option<bool>,
+    // In seconds, 0 means no limit
+    pub segment_duration: Option<u32>,
+    // In bytes, 0 means no limit
+    pub segment_size: Option<u64>,
+}
+
+impl DvrConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Some("./dvr/{app}/{stream}/{timestamp}.flv".to_string()),
+            auto: Some(true),
+            segment_duration: Some(0),
+            segment_size: Some(0),
+        }
+    }
+    fn fill_default(&mut self) {
+        if self.path.is_none() {
+            self.path = Some("./dvr/{app}/{stream}/{timestamp}.flv".to_string())
+        }
+        if self.auto.is_none() {
+            self.auto = Some(true)
+        }
+        if self.segment_duration.is_none() {
+            self.segment_duration = Some(0)
+        }
+        if self.segment_size.is_none() {
+            self.segment_size = Some(0)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    // Check the token of publish or play
+    pub publish: Option<bool>,
+    pub play: Option<bool>,
+    pub secret: Option<String>,
+    // Secret by app
+    pub apps: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HookConfig {
+    pub enabled: bool,
+    pub on_publish: Option<String>,
+    pub on_unpublish: Option<String>,
+    pub on_play: Option<String>,
+    pub on_stop: Option<String>,
+    // In milliseconds
+    pub timeout: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+impl HookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            on_publish: None,
+            on_unpublish: None,
+            on_play: None,
+            on_stop: None,
+            timeout: Some(3000),
+            retries: Some(1),
+        }
+    }
+    fn fill_default(&mut self) {
+        if self.timeout.is_none() {
+            self.timeout = Some(3000)
+        }
+        if self.retries.is_none() {
+            self.retries = Some(1)
+        }
+    }
+}
+
+// Overrides the global config for a tenant, the unset ones are inherited
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct VhostConfig {
+    pub gop_cache: Option<bool>,
+    pub flv: Option<HttpFlv>,
+    pub hls: Option<HttpHls>,
+    pub forward: Option<RtmpForward>,
+    pub auth: Option<AuthConfig>,
+    pub hook: Option<HookConfig>,
+}
+
+impl VhostConfig {
+    fn fill_default(&mut self) {
+        if let Some(hls) = self.hls.as_mut() {
+            hls.fill_default();
+        }
+        if let Some(hook) = self.hook.as_mut() {
+            hook.fill_default();
+        }
+    }
+}
+
 pub fn load(path: &str) -> Result<Config> {
     let content = fs::read_to_string(path)?;
     let mut config: Config = toml::from_str(&content[..])?;
@@ -1,66 +1,207 @@
 use anyhow::Result;
-use futures::channel::mpsc::unbounded;
+use futures::{channel::mpsc::unbounded, StreamExt};
 use hyper::{
+    body::Bytes,
+    header::HeaderValue,
+    server::accept,
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server, StatusCode,
 };
+use msir_core::proxy_protocol;
 use msir_service::httpflv_service::HttpFlvService;
-use msir_service::{statistic::ConnToStatChanTx, stream::ConnToMgrChanTx, utils};
-use std::{convert::Infallible, io};
+use msir_service::httpts_service::HttpTsService;
+use msir_service::{
+    hls::{HlsEvent, HlsManager, HttpToHlsChanTx},
+    statistic::ConnToStatChanTx,
+    stream::ConnToMgrChanTx,
+    utils,
+    vhost::Vhosts,
+};
+use rtmp::message::request::{ClientInfo, Request as RtmpRequest};
+use std::{
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
 
-use tracing::{error, info, Instrument};
+use tracing::{error, info, warn, Instrument};
 
 use crate::config::HttpConfig;
 
 // type FlvRespChanRx = UnboundedReceiver<io::Result<Vec<u8>>>;
 
+// The first playlist request waits until the first segment is ready
+const HLS_PLAYLIST_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 pub async fn http_server_start(
     stream_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
     config: &HttpConfig,
 ) -> Result<()> {
     if !config.enabled {
         return Ok(());
     }
-    let addr = config.listen.clone().unwrap().parse()?;
-    let flv = config.flv.clone().map(|f| f.enabled).unwrap_or(false);
-    let hls = config.hls.clone().map(|h| h.enabled).unwrap_or(false);
+    let addr: SocketAddr = config.listen.clone().unwrap().parse()?;
+    let proxy = config.proxy_protocol.unwrap_or(false);
+    // Enabled by the global config or any vhost, then checked by the vhost of request
+    let flv = vhosts.any(|v| v.flv);
+    let ts = config.ts.clone().map(|t| t.enabled).unwrap_or(false);
+    let hls = match vhosts.any(|v| v.hls.is_some()) {
+        true => {
+            let (hls_tx, hls_rx) = mpsc::unbounded_channel();
+            let hls_mgr = HlsManager::new(
+                vhosts.clone(),
+                hls_rx,
+                hls_tx.clone(),
+                stream_tx.clone(),
+                stat_tx.clone(),
+            );
+            tokio::spawn(hls_mgr.run().instrument(tracing::info_span!("HLS-MGR")));
+            Some(hls_tx)
+        }
+        false => None,
+    };
 
-    let make_service = make_service_fn(move |_socket| {
+    let make_service = make_service_fn(move |conn: &ClientStream| {
+        let client = conn.client.clone();
         let stream_tx_c = stream_tx.clone();
         let stat_tx_c = stat_tx.clone();
+        let hls_c = hls.clone();
+        let vhosts_c = vhosts.clone();
         async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                // Used by the services as the client info
+                req.extensions_mut().insert(client.clone());
                 http_service(
                     req,
                     utils::gen_uid(),
                     stream_tx_c.clone(),
                     stat_tx_c.clone(),
+                    vhosts_c.clone(),
                     flv,
-                    hls,
+                    ts,
+                    hls_c.clone(),
                 )
             }))
         }
     });
 
-    info!("Listening on: {}", addr);
+    let listener = TcpListener::bind(addr).await?;
 
-    Server::bind(&addr).serve(make_service).await?;
+    info!("Listening on: {} proxy_protocol:{}", addr, proxy);
+
+    // The PROXY protocol header is read before the HTTP parsing, without blocking the accept loop
+    let (conn_tx, conn_rx) = unbounded();
+    tokio::spawn(async move {
+        loop {
+            let (mut io, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Transient, e.g. too many open files, keep listening
+                    error!("Accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            let conn_tx = conn_tx.clone();
+            tokio::spawn(async move {
+                let local = io.local_addr().unwrap_or(addr);
+                let (remote, local) = match proxy {
+                    true => match proxy_protocol::resolve_addrs(&mut io, peer, local).await {
+                        Ok(h) => (h.src, h.dst),
+                        Err(e) => return warn!("Drop connection from {}: {}", peer, e),
+                    },
+                    false => (peer, local),
+                };
+                let client = ClientInfo {
+                    remote_addr: Some(remote),
+                    local_addr: Some(local),
+                    ..Default::default()
+                };
+                let _ = conn_tx.unbounded_send(Ok::<_, io::Error>(ClientStream { io, client }));
+            });
+        }
+    });
+
+    Server::builder(accept::from_stream(conn_rx))
+        .serve(make_service)
+        .await?;
 
     Ok(())
 }
 
+// The accepted stream with the real client address
+struct ClientStream {
+    io: TcpStream,
+    client: ClientInfo,
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn http_service(
     req: Request<Body>,
     uid: String,
     stream: ConnToMgrChanTx,
     stat: ConnToStatChanTx,
+    vhosts: Vhosts,
     flv_en: bool,
-    hls_en: bool,
+    ts_en: bool,
+    hls: Option<HttpToHlsChanTx>,
 ) -> Result<Response<Body>> {
-    if flv_en {
-        if req.uri().path().ends_with(".flv") {
-            if let Ok(resp) = httpflv_service(req, uid, stream, stat).await {
+    let path = req.uri().path();
+    if flv_en && path.ends_with(".flv") {
+        if let Ok(resp) = httpflv_service(req, uid, stream, stat, vhosts).await {
+            return Ok(resp);
+        }
+    } else if ts_en && path.ends_with(".ts") && path.matches('/').count() == 2 {
+        // The uri of hls segment is /app/stream/seq.ts
+        if let Ok(resp) = httpts_service(req, uid, stream, stat, vhosts).await {
+            return Ok(resp);
+        }
+    } else if let Some(hls) = hls {
+        if path.ends_with(".m3u8") {
+            if let Ok(resp) = hls_playlist_service(req, hls, vhosts).await {
+                return Ok(resp);
+            }
+        } else if path.ends_with(".ts") {
+            if let Ok(resp) = hls_segment_service(req, hls, vhosts).await {
                 return Ok(resp);
             }
         }
@@ -78,21 +219,257 @@ async fn httpflv_service(
     uid: String,
     stream: ConnToMgrChanTx,
     stat: ConnToStatChanTx,
+    vhosts: Vhosts,
 ) -> Result<Response<Body>> {
-    let (tx, rx) = unbounded::<io::Result<Vec<u8>>>();
+    let (tx, rx) = unbounded::<io::Result<Bytes>>();
 
-    let mut flv_service = HttpFlvService::new(uid.clone(), tx, stream, stat);
+    let ip = req
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|c| c.remote_addr)
+        .map(|a| a.ip().to_string())
+        .unwrap_or_default();
+    let mut flv_service = HttpFlvService::new(uid.clone(), tx, stream, stat, vhosts);
     tokio::spawn(
         async move {
             if let Err(e) = flv_service.run(req).await {
                 error!("Failed to transfer; error={}", e);
             }
         }
-        .instrument(tracing::info_span!("FLV-CONN", uid)),
+        .instrument(tracing::info_span!("FLV-CONN", uid, ip)),
     );
 
+    // The stream ends without data if rejected by hook or register failed
+    let mut rx = rx.peekable();
+    match Pin::new(&mut rx).peek().await {
+        None => return Err(anyhow::anyhow!("play is rejected")),
+        Some(Err(e)) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(forbidden()),
+        _ => {}
+    }
+
     let mut resp = Response::new(Body::wrap_stream(rx));
     resp.headers_mut()
         .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
     Ok(resp)
 }
+
+async fn httpts_service(
+    req: Request<Body>,
+    uid: String,
+    stream: ConnToMgrChanTx,
+    stat: ConnToStatChanTx,
+    vhosts: Vhosts,
+) -> Result<Response<Body>> {
+    let (tx, rx) = unbounded::<io::Result<Vec<u8>>>();
+
+    let ip = req
+        .extensions()
+        .get::<ClientInfo>()
+        .and_then(|c| c.remote_addr)
+        .map(|a| a.ip().to_string())
+        .unwrap_or_default();
+    let mut ts_service = HttpTsService::new(uid.clone(), tx, stream, stat, vhosts);
+    tokio::spawn(
+        async move {
+            if let Err(e) = ts_service.run(req).await {
+                error!("Failed to transfer; error={}", e);
+            }
+        }
+        .instrument(tracing::info_span!("TS-CONN", uid, ip)),
+    );
+
+    // The stream ends without data if rejected by hook or register failed
+    let mut rx = rx.peekable();
+    match Pin::new(&mut rx).peek().await {
+        None => return Err(anyhow::anyhow!("play is rejected")),
+        Some(Err(e)) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(forbidden()),
+        _ => {}
+    }
+
+    let mut resp = Response::new(Body::wrap_stream(rx));
+    resp.headers_mut()
+        .insert("Content-Type", "video/mp2t".parse().unwrap());
+    resp.headers_mut()
+        .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
+    Ok(resp)
+}
+
+// Rejected by the auth
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body("403".into())
+        .unwrap()
+}
+
+async fn hls_playlist_service(
+    req: Request<Body>,
+    hls: HttpToHlsChanTx,
+    vhosts: Vhosts,
+) -> Result<Response<Body>> {
+    let mut req = parse_request(&req)?;
+    // Only the signed url per request, the hooks are not called for hls
+    if vhosts
+        .resolve(&mut req)
+        .hooks
+        .check_play_auth(&req)
+        .is_err()
+    {
+        return Ok(forbidden());
+    }
+
+    let (tx, rx) = oneshot::channel();
+    hls.send(HlsEvent::QueryPlaylist(Box::new(req), tx))
+        .map_err(|_| anyhow::anyhow!("hls manager exited"))?;
+    match tokio::time::timeout(HLS_PLAYLIST_TIMEOUT, rx).await?? {
+        Some(m3u8) => {
+            let mut resp = Response::new(Body::from(m3u8));
+            resp.headers_mut().insert(
+                "Content-Type",
+                "application/vnd.apple.mpegurl".parse().unwrap(),
+            );
+            resp.headers_mut()
+                .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
+            Ok(resp)
+        }
+        None => Err(anyhow::anyhow!("playlist not found")),
+    }
+}
+
+// The uri of segment is /app/stream/seq.ts
+async fn hls_segment_service(
+    req: Request<Body>,
+    hls: HttpToHlsChanTx,
+    vhosts: Vhosts,
+) -> Result<Response<Body>> {
+    let path = req.uri().path();
+    let (app_stream, seq) = path
+        .strip_suffix(".ts")
+        .and_then(|p| p.rsplit_once('/'))
+        .ok_or(anyhow::anyhow!("invalid segment uri"))?;
+    let seq: u64 = seq.parse()?;
+    // By the host or the vhost param of segment uri
+    let mut rtmp_req = parse_request(&req)?;
+    // The token is signed over the stream, not the segment
+    if let Some((_, stream)) = app_stream.rsplit_once('/') {
+        rtmp_req.set_stream(stream);
+    }
+    if vhosts
+        .resolve(&mut rtmp_req)
+        .hooks
+        .check_play_auth(&rtmp_req)
+        .is_err()
+    {
+        return Ok(forbidden());
+    }
+    let stream_key = format!("{}{}", rtmp_req.vhost.unwrap_or_default(), app_stream);
+
+    let (tx, rx) = oneshot::channel();
+    hls.send(HlsEvent::QuerySegment(stream_key, seq, tx))
+        .map_err(|_| anyhow::anyhow!("hls manager exited"))?;
+    match rx.await? {
+        Some(data) => {
+            let mut resp = Response::new(Body::from(data));
+            resp.headers_mut()
+                .insert("Content-Type", "video/mp2t".parse().unwrap());
+            resp.headers_mut()
+                .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
+            Ok(resp)
+        }
+        None => Err(anyhow::anyhow!("segment not found")),
+    }
+}
+
+fn parse_request(req: &Request<Body>) -> Result<RtmpRequest> {
+    let client = utils::http_client_info(req);
+    let mut rtmp_req = RtmpRequest::parse_from(format!(
+        "http://{}{}",
+        req.headers()
+            .get("Host")
+            .unwrap_or(&HeaderValue::from_static("0.0.0.0"))
+            .to_str()
+            .unwrap_or("0.0.0.0"),
+        req.uri()
+    ))?;
+    rtmp_req.client = client;
+    Ok(rtmp_req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msir_service::{
+        auth::{sign, Auth, AuthConfig},
+        hls::HlsConfig,
+        hook::{HookConfig, Hooks},
+        vhost::Vhost,
+    };
+    use std::collections::HashMap;
+
+    fn vhosts() -> Vhosts {
+        let auth = Auth::new(AuthConfig {
+            play: true,
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        });
+        let vhost = Vhost {
+            hls: Some(HlsConfig {
+                fragment: 2,
+                window: 10,
+            }),
+            // Not called for hls, or the signed request fails
+            hooks: Hooks::new(HookConfig {
+                on_play: Some("http://127.0.0.1:1/on_play".to_string()),
+                timeout: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .with_auth(auth),
+            ..Default::default()
+        };
+        Vhosts::new(vhost, HashMap::new())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri)
+            .header("Host", "127.0.0.1")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hls_auth() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        // The unsigned playlist and segment are rejected before the hls manager
+        let resp = hls_playlist_service(get("/live/test.m3u8"), tx.clone(), vhosts())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = hls_segment_service(get("/live/test/1.ts"), tx.clone(), vhosts())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(rx.try_recv().is_err());
+
+        // The segment is signed over the stream as the playlist
+        let expire = msir_core::utils::current_time() as u64 + 60;
+        let query = format!(
+            "token={}&expire={}",
+            sign("secret", "live", "test", expire),
+            expire
+        );
+        let manager = tokio::spawn(async move {
+            match rx.recv().await {
+                Some(HlsEvent::QuerySegment(stream_key, 1, ret)) => {
+                    assert_eq!(stream_key, "/live/test");
+                    let _ = ret.send(Some(Bytes::from_static(b"ts")));
+                }
+                _ => panic!("expect the segment query"),
+            }
+        });
+        let uri = format!("/live/test/1.ts?{}", query);
+        let resp = hls_segment_service(get(&uri), tx, vhosts()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        manager.await.unwrap();
+    }
+}
@@ -1,17 +1,32 @@
 use crate::api_server::api_server_start;
 use crate::http_server::http_server_start;
-use crate::rtmp_server::rtmp_server_start;
+use crate::rtmp_server::{rtmp_server_start, rtmps_server_start};
 use clap::{value_parser, Arg, Command};
-use config::LogConfig;
+use config::{
+    AuthConfig, Config, DvrConfig, HookConfig, LogConfig, RtmpEdge, RtmpQueue, VhostConfig,
+};
+use msir_service::dvr::{self, ConnToDvrChanTx, DvrEvent, DvrManager};
+use msir_service::edge::{self, EdgePolicy, Origins};
+use msir_service::forward::{ConnToForwardChanTx, ForwardConfig, ForwardEvent, ForwardManager};
 use msir_service::statistic::{ConnToStatChanTx, StatEvent, Statistic};
-use msir_service::stream::{Manager, StreamEvent};
+use msir_service::stream::{
+    queue::{QueueConfig, SlowPolicy},
+    ConnToMgrChanTx, Manager, StreamEvent,
+};
+use msir_service::{
+    auth::{self, Auth},
+    hls::HlsConfig,
+    hook::{self, Hooks},
+    vhost::{Vhost, Vhosts},
+};
 use std::error::Error;
 use std::path::Path;
+use std::time::Duration;
 use std::{io, process};
 use tokio::runtime;
 use tokio::signal;
-use tokio::sync::mpsc::{self, UnboundedSender};
-use tracing::{error, info, Instrument};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, warn, Instrument};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber;
 // use tokio_metrics::RuntimeMonitor;
@@ -51,9 +66,9 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     info!("MSIR start...");
 
-    let _guard = log_init(&cfg.log.unwrap());
+    let _guard = log_init(cfg.log.as_ref().unwrap());
 
-    let rt = match cfg.worker.unwrap().mode.as_str() {
+    let rt = match cfg.worker.as_ref().unwrap().mode.as_str() {
         "single" => runtime::Builder::new_current_thread()
             .enable_all()
             .build()?,
@@ -76,12 +91,69 @@ fn main() -> Result<(), Box<dyn Error>> {
         // }
 
         let stat_tx = statistic_bg_start();
-        let stream_tx = stream_mgr_start(stat_tx.clone());
+        let vhosts = vhosts_init(&cfg);
+        let dvr_cfg = cfg.dvr.unwrap();
+        let (dvr_tx, dvr_rx) = mpsc::unbounded_channel::<DvrEvent>();
+        let dvr_tx = match dvr_cfg.enabled {
+            true => Some(dvr_tx),
+            false => None,
+        };
+        let (forward_tx, forward_rx) = mpsc::unbounded_channel::<ForwardEvent>();
+        let forward_tx = match vhosts.any(|v| v.forward.is_some()) {
+            true => Some(forward_tx),
+            false => None,
+        };
+        let edge = edge_init(cfg.rtmp.as_ref().and_then(|r| r.edge.as_ref()));
+        let queue = queue_init(cfg.rtmp.as_ref().and_then(|r| r.queue.as_ref()));
+        let stream_tx = stream_mgr_start(
+            stat_tx.clone(),
+            dvr_tx.clone(),
+            forward_tx.clone(),
+            edge,
+            queue,
+            vhosts.clone(),
+        );
+        if forward_tx.is_some() {
+            forward_mgr_start(
+                forward_rx,
+                stream_tx.clone(),
+                stat_tx.clone(),
+                vhosts.clone(),
+            );
+        }
+        if let Some(tx) = &dvr_tx {
+            dvr_mgr_start(
+                dvr_rx,
+                tx.clone(),
+                stream_tx.clone(),
+                stat_tx.clone(),
+                &dvr_cfg,
+            );
+        }
+
+        if let Some(tls) = cfg.rtmp.as_ref().and_then(|r| r.tls.clone()) {
+            if tls.enabled {
+                let stat_tx_c = stat_tx.clone();
+                let stream_tx_c = stream_tx.clone();
+                let vhosts_c = vhosts.clone();
+                tokio::spawn(async move {
+                    if let Err(err) =
+                        rtmps_server_start(stream_tx_c, stat_tx_c, vhosts_c, &tls).await
+                    {
+                        error!("Start rtmps server error: {}\n", err);
+                        process::exit(-1);
+                    }
+                });
+            }
+        }
 
         let stat_tx_c = stat_tx.clone();
         let stream_tx_c = stream_tx.clone();
+        let vhosts_c = vhosts.clone();
         tokio::spawn(async move {
-            if let Err(err) = rtmp_server_start(stream_tx_c, stat_tx_c, &cfg.rtmp.unwrap()).await {
+            if let Err(err) =
+                rtmp_server_start(stream_tx_c, stat_tx_c, vhosts_c, &cfg.rtmp.unwrap()).await
+            {
                 error!("Start rtmp server error: {}\n", err);
                 process::exit(-1);
             }
@@ -90,14 +162,17 @@ fn main() -> Result<(), Box<dyn Error>> {
         let stat_tx_c = stat_tx.clone();
         let stream_tx_c = stream_tx.clone();
         tokio::spawn(async move {
-            if let Err(err) = http_server_start(stream_tx_c, stat_tx_c, &cfg.http.unwrap()).await {
+            if let Err(err) =
+                http_server_start(stream_tx_c, stat_tx_c, vhosts, &cfg.http.unwrap()).await
+            {
                 error!("Start http server error: {}\n", err);
                 process::exit(-1);
             }
         });
 
         tokio::spawn(async move {
-            if let Err(err) = api_server_start(stream_tx, stat_tx, &cfg.api.unwrap()).await {
+            if let Err(err) = api_server_start(stream_tx, stat_tx, dvr_tx, &cfg.api.unwrap()).await
+            {
                 error!("Start api server error: {}\n", err);
                 process::exit(-1);
             }
@@ -164,9 +239,25 @@ fn statistic_bg_start() -> UnboundedSender<StatEvent> {
     tx
 }
 
-fn stream_mgr_start(stat_tx: ConnToStatChanTx) -> UnboundedSender<StreamEvent> {
+fn stream_mgr_start(
+    stat_tx: ConnToStatChanTx,
+    dvr_tx: Option<ConnToDvrChanTx>,
+    forward_tx: Option<ConnToForwardChanTx>,
+    edge: Option<Origins>,
+    queue: QueueConfig,
+    vhosts: Vhosts,
+) -> UnboundedSender<StreamEvent> {
     let (tx, rx) = mpsc::unbounded_channel::<StreamEvent>();
-    let stream_mgr = Manager::new(rx, tx.clone(), stat_tx);
+    let stream_mgr = Manager::new(
+        rx,
+        tx.clone(),
+        stat_tx,
+        dvr_tx,
+        forward_tx,
+        edge,
+        queue,
+        vhosts,
+    );
     tokio::spawn(
         stream_mgr
             .run()
@@ -174,3 +265,140 @@ fn stream_mgr_start(stat_tx: ConnToStatChanTx) -> UnboundedSender<StreamEvent> {
     );
     tx
 }
+
+fn dvr_mgr_start(
+    rx: UnboundedReceiver<DvrEvent>,
+    tx: ConnToDvrChanTx,
+    stream_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+    config: &DvrConfig,
+) {
+    let config = dvr::DvrConfig {
+        path: config.path.clone().unwrap(),
+        auto: config.auto.unwrap(),
+        segment_duration: config.segment_duration.unwrap(),
+        segment_size: config.segment_size.unwrap(),
+    };
+    let dvr_mgr = DvrManager::new(config, rx, tx, stream_tx, stat_tx);
+    tokio::spawn(dvr_mgr.run().instrument(tracing::info_span!("DVR-MGR")));
+}
+
+fn forward_mgr_start(
+    rx: UnboundedReceiver<ForwardEvent>,
+    stream_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
+) {
+    let forward_mgr = ForwardManager::new(vhosts, rx, stream_tx, stat_tx);
+    tokio::spawn(
+        forward_mgr
+            .run()
+            .instrument(tracing::info_span!("FORWARD-MGR")),
+    );
+}
+
+fn edge_init(config: Option<&RtmpEdge>) -> Option<Origins> {
+    let config = config.filter(|c| c.enabled && !c.origins.is_empty())?;
+    let policy = config.policy.as_deref().unwrap_or("round_robin");
+    let policy = EdgePolicy::parse(policy).unwrap_or_else(|| {
+        warn!("Unknown edge policy {}, use round_robin", policy);
+        EdgePolicy::RoundRobin
+    });
+    Some(Origins::new(edge::EdgeConfig {
+        origins: config.origins.clone(),
+        policy,
+    }))
+}
+
+fn queue_init(config: Option<&RtmpQueue>) -> QueueConfig {
+    let mut queue = QueueConfig::default();
+    let config = match config {
+        Some(c) => c,
+        None => return queue,
+    };
+    let parse = |policy: &str| {
+        SlowPolicy::parse(policy).unwrap_or_else(|| {
+            warn!("Unknown queue policy {}, use drop", policy);
+            SlowPolicy::DropFrames
+        })
+    };
+    if let Some(size) = config.size {
+        queue.size = size;
+    }
+    if let Some(policy) = &config.policy {
+        queue.policy = parse(policy);
+    }
+    for (app, policy) in config.apps.iter().flatten() {
+        queue.apps.insert(app.clone(), parse(policy));
+    }
+    queue
+}
+
+fn auth_init(config: Option<&AuthConfig>) -> Auth {
+    let config = match config {
+        Some(c) if c.enabled => c,
+        _ => return Auth::default(),
+    };
+    Auth::new(auth::AuthConfig {
+        publish: config.publish.unwrap_or(true),
+        play: config.play.unwrap_or(false),
+        secret: config.secret.clone(),
+        apps: config.apps.clone().unwrap_or_default(),
+    })
+}
+
+fn hooks_init(config: &HookConfig) -> Hooks {
+    if !config.enabled {
+        return Hooks::default();
+    }
+    Hooks::new(hook::HookConfig {
+        on_publish: config.on_publish.clone(),
+        on_unpublish: config.on_unpublish.clone(),
+        on_play: config.on_play.clone(),
+        on_stop: config.on_stop.clone(),
+        timeout: Duration::from_millis(config.timeout.unwrap()),
+        retries: config.retries.unwrap(),
+    })
+}
+
+// The default vhost is from the global config, which is overridden by [vhost.<name>]
+fn vhosts_init(cfg: &Config) -> Vhosts {
+    let vhost_init = |v: &VhostConfig| {
+        let http = cfg.http.as_ref().filter(|h| h.enabled);
+        let flv = v.flv.as_ref().or(http.and_then(|h| h.flv.as_ref()));
+        let hls = v.hls.as_ref().or(http.and_then(|h| h.hls.as_ref()));
+        let forward = v
+            .forward
+            .as_ref()
+            .or(cfg.rtmp.as_ref().and_then(|r| r.forward.as_ref()));
+        let hook = v.hook.as_ref().or(cfg.hook.as_ref()).unwrap();
+        let auth = v.auth.as_ref().or(cfg.auth.as_ref());
+        Vhost {
+            gop_cache: v
+                .gop_cache
+                .or(cfg.rtmp.as_ref().and_then(|r| r.gop_cache))
+                .unwrap_or(true),
+            flv: http.is_some() && flv.map(|f| f.enabled).unwrap_or(false),
+            hls: hls
+                .filter(|h| http.is_some() && h.enabled)
+                .map(|h| HlsConfig {
+                    fragment: h.fragment.unwrap_or(10),
+                    window: h.window.unwrap_or(60),
+                }),
+            forward: forward.filter(|f| f.enabled).map(|f| ForwardConfig {
+                destinations: f.destinations.clone(),
+            }),
+            hooks: hooks_init(hook).with_auth(auth_init(auth)),
+        }
+    };
+    let vhosts = cfg
+        .vhost
+        .iter()
+        .flatten()
+        .map(|(name, v)| {
+            info!("Vhost {} is configured", name);
+            (name.clone(), vhost_init(v))
+        })
+        .collect();
+    Vhosts::new(vhost_init(&VhostConfig::default()), vhosts)
+}
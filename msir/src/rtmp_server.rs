@@ -1,50 +1,152 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::FutureExt;
-use msir_core::transport::Transport;
+use msir_core::{
+    proxy_protocol::{self, ProxyHeader},
+    transport::{AsyncStream, Transport},
+};
 use msir_service::{
     rtmp_service::RtmpService, statistic::ConnToStatChanTx, stream::ConnToMgrChanTx, utils,
+    vhost::Vhosts,
 };
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, Instrument};
+use tokio_rustls::{
+    rustls::{crypto::ring, ServerConfig},
+    TlsAcceptor,
+};
+use tracing::{error, field, info, Instrument, Span};
+
+use crate::config::{RtmpConfig, RtmpTls};
 
-use crate::config::RtmpConfig;
+// Not to keep the connection which never completes the handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn rtmp_server_start(
     stream_tx: ConnToMgrChanTx,
     stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
     config: &RtmpConfig,
 ) -> Result<()> {
     let listen_addr = &config.listen;
+    let proxy = config.proxy_protocol.unwrap_or(false);
+
+    let listener = TcpListener::bind(listen_addr).await?;
+
+    info!("Listening on: {} proxy_protocol:{}", listen_addr, proxy);
+
+    while let Ok((mut inbound, _)) = listener.accept().await {
+        let uid = utils::gen_uid();
+        let uid_c = uid.clone();
+        let stream_tx = stream_tx.clone();
+        let stat_tx = stat_tx.clone();
+        let vhosts = vhosts.clone();
+        let rtmp_service = async move {
+            let addrs = client_addrs(&mut inbound, proxy).await?;
+            rtmp_service(inbound, addrs, uid_c, stream_tx, stat_tx, vhosts).await
+        }
+        .map(|r| {
+            if let Err(e) = r {
+                error!("Failed to transfer; error={}", e);
+            }
+        });
+
+        let span = tracing::info_span!("RTMP-CONN", uid, ip = field::Empty);
+        tokio::spawn(rtmp_service.instrument(span));
+    }
+
+    Ok(())
+}
+
+pub async fn rtmps_server_start(
+    stream_tx: ConnToMgrChanTx,
+    stat_tx: ConnToStatChanTx,
+    vhosts: Vhosts,
+    config: &RtmpTls,
+) -> Result<()> {
+    let listen_addr = config.listen.as_deref().unwrap_or("0.0.0.0:443");
+    let proxy = config.proxy_protocol.unwrap_or(false);
+    let acceptor = tls_acceptor(config)?;
 
     let listener = TcpListener::bind(listen_addr).await?;
 
-    info!("Listening on: {}", listen_addr);
+    info!(
+        "Listening on: {} (rtmps) proxy_protocol:{}",
+        listen_addr, proxy
+    );
 
-    while let Ok((inbound, _)) = listener.accept().await {
+    while let Ok((mut inbound, _)) = listener.accept().await {
         let uid = utils::gen_uid();
-        let rtmp_service = rtmp_service(inbound, uid.clone(), stream_tx.clone(), stat_tx.clone())
-            .map(|r| {
-                if let Err(e) = r {
-                    error!("Failed to transfer; error={}", e);
-                }
-            });
-
-        tokio::spawn(rtmp_service.instrument(tracing::info_span!("RTMP-CONN", uid)));
+        let uid_c = uid.clone();
+        let acceptor = acceptor.clone();
+        let stream_tx = stream_tx.clone();
+        let stat_tx = stat_tx.clone();
+        let vhosts = vhosts.clone();
+        let rtmps_service = async move {
+            // The PROXY protocol header is in plain text before the TLS handshake
+            let addrs = client_addrs(&mut inbound, proxy).await?;
+            let inbound =
+                tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(inbound)).await??;
+            rtmp_service(inbound, addrs, uid_c, stream_tx, stat_tx, vhosts).await
+        }
+        .map(|r| {
+            if let Err(e) = r {
+                error!("Failed to transfer; error={}", e);
+            }
+        });
+
+        let span = tracing::info_span!("RTMPS-CONN", uid, ip = field::Empty);
+        tokio::spawn(rtmps_service.instrument(span));
     }
 
     Ok(())
 }
 
+// The real client behind the load balancer, recorded in the span of the connection
+async fn client_addrs(inbound: &mut TcpStream, proxy: bool) -> Result<ProxyHeader> {
+    let (peer, local) = (inbound.peer_addr()?, inbound.local_addr()?);
+    let addrs = match proxy {
+        true => proxy_protocol::resolve_addrs(inbound, peer, local).await?,
+        false => ProxyHeader {
+            src: peer,
+            dst: local,
+        },
+    };
+    Span::current().record("ip", addrs.src.ip().to_string());
+    Ok(addrs)
+}
+
+fn tls_acceptor(config: &RtmpTls) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.key)?))?
+        .ok_or_else(|| anyhow!("No private key in {}", config.key))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
 // #[instrument]
 async fn rtmp_service(
-    inbound: TcpStream,
+    inbound: impl AsyncStream + 'static,
+    addrs: ProxyHeader,
     uid: String,
     stream: ConnToMgrChanTx,
     stat: ConnToStatChanTx,
+    vhosts: Vhosts,
 ) -> Result<()> {
-    RtmpService::new(Transport::new(inbound), Some(uid), stream, stat)
-        .await?
-        .run()
-        .await?;
+    RtmpService::new(
+        Transport::new(inbound),
+        Some(uid),
+        Some(addrs.src),
+        Some(addrs.dst),
+        stream,
+        stat,
+        vhosts,
+    )
+    .await?
+    .run()
+    .await?;
     Ok(())
 }